@@ -1,8 +1,10 @@
 use deku::prelude::*;
+use crate::linux_user::LinuxUser;
+use crate::semihosting::Semihosting;
 
-type Byte = u8;
-type HalfWord = u16;
-type Word = u32;
+pub type Byte = u8;
+pub type HalfWord = u16;
+pub type Word = u32;
 
 #[repr(u8)]
 #[derive(PartialEq)]
@@ -16,12 +18,447 @@ pub enum ProcessorMode {
     System(u8) = 0x1F,
 }
 
-pub type BusState = Result<Word, ()>;
+impl ProcessorMode {
+    pub fn from_bits(mode: u32) -> Option<ProcessorMode> {
+        match mode {
+            0x10 => Some(ProcessorMode::User(0)),
+            0x11 => Some(ProcessorMode::FIQ(0)),
+            0x12 => Some(ProcessorMode::IRQ(0)),
+            0x13 => Some(ProcessorMode::Supervisor(0)),
+            0x17 => Some(ProcessorMode::Abort(0)),
+            0x1B => Some(ProcessorMode::Undefined(0)),
+            0x1F => Some(ProcessorMode::System(0)),
+            _ => None,
+        }
+    }
+}
+
+/// `Ok` carries the number of wait states the access took.
+pub type BusState = Result<Word, BusError>;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BusError {
+    /// Nothing responds at the address.
+    Unmapped(Word),
+    /// The responder does not support the access width.
+    Width(Word),
+    /// The access is not allowed, e.g. a User mode access to a privileged region.
+    Permission(Word),
+    /// The device at the address signalled an error.
+    Device(Word),
+}
+
+impl std::fmt::Display for BusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BusError::Unmapped(addr) => write!(f, "unmapped address 0x{:08x}", addr),
+            BusError::Width(addr) => write!(f, "unsupported access width at 0x{:08x}", addr),
+            BusError::Permission(addr) => write!(f, "permission denied at 0x{:08x}", addr),
+            BusError::Device(addr) => write!(f, "device error at 0x{:08x}", addr),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BusWidth {
+    Byte,
+    HalfWord,
+    Word,
+}
+
+impl BusWidth {
+    pub fn bytes(&self) -> u32 {
+        match self {
+            BusWidth::Byte => 1,
+            BusWidth::HalfWord => 2,
+            BusWidth::Word => 4,
+        }
+    }
+
+    pub fn mask(&self) -> Word {
+        match self {
+            BusWidth::Byte => 0xFF,
+            BusWidth::HalfWord => 0xFFFF,
+            BusWidth::Word => 0xFFFFFFFF,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BusKind {
+    /// Instruction fetch.
+    Fetch,
+    /// Load or store.
+    Data,
+    /// Locked read-then-write of SWP/SWPB.
+    Swap,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Privilege {
+    User,
+    Privileged,
+}
+
+/// One bus transaction. `addr` is aligned to `width`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct BusRequest {
+    pub addr: Word,
+    pub rw: BusRW,
+    pub width: BusWidth,
+    pub kind: BusKind,
+    pub privilege: Privilege,
+    pub cycle: BusCycle,
+}
+
+impl BusRequest {
+    /// Word-sized privileged data access, as issued through `Bus::access`.
+    pub fn word(addr: Word, rw: BusRW, cycle: BusCycle) -> BusRequest {
+        BusRequest {
+            addr,
+            rw,
+            width: BusWidth::Word,
+            kind: BusKind::Data,
+            privilege: Privilege::Privileged,
+            cycle,
+        }
+    }
+}
 
 pub trait Bus {
-    fn access(&mut self, addr: Word, data: &mut Word, rw: BusRW) -> BusState;
+    /// Word access at a word-aligned `addr`.
+    fn access(&mut self, addr: Word, data: &mut Word, rw: BusRW, cycle: BusCycle) -> BusState;
+
+    /// Sized access. Narrow data sits in the low bits of `data`.
+    ///
+    /// The default adapter serves everything through `access`, so word-only buses keep
+    /// working: narrow reads pick their byte lanes out of the containing word and narrow
+    /// writes read-modify-write it. Buses with side effects on neighbouring bytes
+    /// should override this.
+    fn transfer(&mut self, request: &BusRequest, data: &mut Word) -> BusState {
+        let aligned = request.addr & !0x3;
+        if request.width == BusWidth::Word {
+            return self.access(aligned, data, request.rw, request.cycle);
+        }
+        let shift = (request.addr & 0x3) * 8;
+        let mask = request.width.mask() << shift;
+        let mut word: Word = 0;
+        let mut wait_states = self.access(aligned, &mut word, BusRW::Read, request.cycle)?;
+        match request.rw {
+            BusRW::Read => {
+                *data = (word & mask) >> shift;
+            }
+            BusRW::Write => {
+                word = (word & !mask) | ((*data << shift) & mask);
+                wait_states += self.access(aligned, &mut word, BusRW::Write, BusCycle::Sequential)?;
+            }
+        }
+        Ok(wait_states)
+    }
+
+    /// Advances whatever sits behind the bus by `cycles` of emulated time.
+    fn tick(&mut self, _cycles: u64) {}
+
+    /// Cycles until the next scheduled event behind the bus, if any.
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    /// Current level of the nIRQ/nFIQ inputs of the CPU.
+    fn interrupt_lines(&mut self) -> InterruptLines {
+        InterruptLines::default()
+    }
+
+    /// Cycles other bus masters held the bus for since the last call.
+    fn stolen_cycles(&mut self) -> u64 {
+        0
+    }
+
+    /// Takes the pending request from behind the bus to whoever runs the system.
+    fn system_request(&mut self) -> Option<SystemRequest> {
+        None
+    }
+
+    /// Called at the start of every step with the address of the instruction
+    /// whose accesses follow.
+    fn begin_instruction(&mut self, _pc: Word) {}
+
+    /// Reads `width` bytes at `addr` without side effects or timing, for
+    /// debuggers. `None` where that is not possible, e.g. device registers.
+    fn peek(&mut self, _addr: Word, _width: BusWidth) -> Option<Word> {
+        None
+    }
+
+    /// Cache maintenance requested through CP15 register 7. Buses without caches ignore it.
+    fn cache_operation(&mut self, _operation: CacheOperation) -> BusState {
+        Ok(0)
+    }
+}
+
+/// CP15 register 7 cache maintenance, with the modified virtual address for
+/// line operations and the set/way written to Rd for index operations.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum CacheOperation {
+    InvalidateAll,
+    InvalidateInstructions,
+    InvalidateInstructionLine(Word),
+    InvalidateData,
+    InvalidateDataLine(Word),
+    CleanDataLine(Word),
+    CleanDataIndex(Word),
+    CleanInvalidateDataLine(Word),
+    CleanInvalidateDataIndex(Word),
+    DrainWriteBuffer,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Reads and writes.
+    Access,
+}
+
+/// Stops execution after a data access that overlaps `start..=end`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Watchpoint {
+    pub start: Word,
+    pub end: Word,
+    pub kind: WatchKind,
+    /// Only trigger when the value read or written equals this one.
+    pub value: Option<Word>,
+}
+
+impl Watchpoint {
+    pub fn new(start: Word, end: Word, kind: WatchKind) -> Watchpoint {
+        Watchpoint { start, end, kind, value: None }
+    }
+
+    pub fn with_value(mut self, value: Word) -> Watchpoint {
+        self.value = Some(value);
+        self
+    }
+
+    fn matches(&self, request: &BusRequest, value: Word) -> bool {
+        let last = request.addr.wrapping_add(request.width.bytes() - 1);
+        let is_kind = match self.kind {
+            WatchKind::Read => request.rw == BusRW::Read,
+            WatchKind::Write => request.rw == BusRW::Write,
+            WatchKind::Access => true,
+        };
+        let is_value = self.value.map_or(true, |expected| expected & request.width.mask() == value & request.width.mask());
+        is_kind && request.addr <= self.end && self.start <= last && is_value
+    }
+}
+
+/// The access that triggered a watchpoint.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct WatchpointHit {
+    /// Address of the instruction that made the access.
+    pub pc: Word,
+    pub addr: Word,
+    pub width: BusWidth,
+    pub rw: BusRW,
+    /// Memory contents before the access, if the bus could tell without side effects.
+    pub old: Option<Word>,
+    /// Value read or written.
+    pub new: Word,
+}
+
+impl std::fmt::Display for WatchpointHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let rw = if self.rw == BusRW::Read { "read" } else { "write" };
+        write!(f, "watchpoint: {} of {} bytes at 0x{:08x} by 0x{:08x}: ", rw, self.width.bytes(), self.addr, self.pc)?;
+        match self.old {
+            Some(old) => write!(f, "0x{:08x} -> 0x{:08x}", old, self.new),
+            None => write!(f, "? -> 0x{:08x}", self.new),
+        }
+    }
+}
+
+/// A bus error the CPU took as an abort exception.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct AbortReport {
+    pub exception: Exception,
+    /// Address of the instruction that aborted.
+    pub pc: Word,
+    pub error: BusError,
+}
+
+impl std::fmt::Display for AbortReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = if self.exception == Exception::PrefetchAbort { "prefetch" } else { "data" };
+        write!(f, "{} abort at 0x{:08x}: {}", kind, self.pc, self.error)
+    }
+}
+
+/// An SWI as seen by a host-side handler.
+pub struct SwiCall<'a> {
+    /// The 24-bit comment field of the instruction.
+    pub number: Word,
+    /// r0 to r15 of the calling mode, written back afterwards. r15 holds the
+    /// address of the instruction after the SWI; changing it branches.
+    pub regs: [Word; 16],
+    /// The calling CPSR. Only changes to the condition flags are written back.
+    pub cpsr: Word,
+    pub bus: &'a mut dyn Bus,
+    /// Set to pass a request on to the host running the core.
+    pub request: Option<SystemRequest>,
+}
+
+/// Emulates an SWI service on the host, in place of the guest handler.
+pub trait SwiHandler {
+    fn call(&mut self, call: &mut SwiCall);
+}
+
+impl<F: FnMut(&mut SwiCall)> SwiHandler for F {
+    fn call(&mut self, call: &mut SwiCall) {
+        self(call)
+    }
+}
+
+/// A request from the emulated system to the host running it.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SystemRequest {
+    /// End the run with this exit status.
+    Exit(i32),
+    /// A guest assertion failed; the value identifies which one.
+    AssertionFailure(Word),
+    /// Assert the reset input of the core. The CPU acts on this itself.
+    Reset,
+}
+
+/// Asserted (active) state of the CPU interrupt inputs.
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct InterruptLines {
+    pub irq: bool,
+    pub fiq: bool,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Exception {
+    Reset,
+    Undefined,
+    SoftwareInterrupt,
+    PrefetchAbort,
+    DataAbort,
+    Irq,
+    Fiq,
+}
+
+/// What to do when an instruction hits an UNPREDICTABLE case of the ARM ARM.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum UnpredictablePolicy {
+    /// Behave like ARM7TDMI silicon and carry on silently.
+    Emulate,
+    /// Stop before the instruction executes and report it from `step`.
+    Error,
+    /// Print a warning to stderr, then behave like `Emulate`.
+    Warn,
+}
+
+/// What happens when an instruction stores to the two instructions already in the pipeline.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum PrefetchPolicy {
+    /// Behave like ARM7TDMI silicon: the old instructions still execute, and
+    /// the new ones only after a branch or `invalidate_prefetch`.
+    Stale,
+    /// Fetch them again after the store, as if there were no pipeline.
+    Coherent,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum UnpredictableRule {
+    /// Base writeback with Rn = PC.
+    WritebackToPc,
+    /// LDM with writeback and the base register in the register list.
+    LoadMultipleBaseInList,
+    /// MUL/MLA with Rd == Rm.
+    MultiplyRdIsRm,
+    /// UMULL/SMULL/UMLAL/SMLAL with RdHi, RdLo and Rm not all distinct.
+    MultiplyLongOverlap,
+    /// MRS with Rd = PC or MSR with Rm = PC.
+    PsrTransferWithPc,
+    /// MRS/MSR on the SPSR in User or System mode, which have no SPSR.
+    SpsrInUserOrSystem,
+}
+
+impl std::fmt::Display for UnpredictableRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let description = match self {
+            UnpredictableRule::WritebackToPc => "base writeback with Rn = PC",
+            UnpredictableRule::LoadMultipleBaseInList => "LDM with writeback and the base register in the list",
+            UnpredictableRule::MultiplyRdIsRm => "MUL/MLA with Rd == Rm",
+            UnpredictableRule::MultiplyLongOverlap => "long multiply with RdHi, RdLo and Rm not distinct",
+            UnpredictableRule::PsrTransferWithPc => "MRS/MSR with R15",
+            UnpredictableRule::SpsrInUserOrSystem => "SPSR access in User or System mode",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// ARM7TDMI cycle cost split by cycle type: non-sequential (N), sequential (S),
+/// internal (I) and coprocessor (C).
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct Cycles {
+    pub n: u64,
+    pub s: u64,
+    pub i: u64,
+    pub c: u64,
+    /// Wait states inserted by the bus on top of the N and S cycles.
+    pub wait: u64,
+    /// Cycles the CPU stalled while another bus master held the bus.
+    pub stall: u64,
 }
 
+impl Cycles {
+    pub fn new(n: u64, s: u64, i: u64, c: u64) -> Cycles {
+        Cycles { n, s, i, c, wait: 0, stall: 0 }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.n + self.s + self.i + self.c + self.wait + self.stall
+    }
+}
+
+impl std::ops::Add for Cycles {
+    type Output = Cycles;
+
+    fn add(self, other: Cycles) -> Cycles {
+        Cycles {
+            n: self.n + other.n,
+            s: self.s + other.s,
+            i: self.i + other.i,
+            c: self.c + other.c,
+            wait: self.wait + other.wait,
+            stall: self.stall + other.stall,
+        }
+    }
+}
+
+impl std::ops::AddAssign for Cycles {
+    fn add_assign(&mut self, other: Cycles) {
+        *self = *self + other;
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum EmulatorError {
+    Unpredictable { pc: Word, raw_inst: Word, rule: UnpredictableRule },
+}
+
+impl std::fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EmulatorError::Unpredictable { pc, raw_inst, rule } => {
+                write!(f, "UNPREDICTABLE at 0x{:08x} (0x{:08x}: {}): {}", pc, raw_inst, disassemble(*raw_inst).trim_end(), rule)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
 #[derive(Debug, PartialEq, DekuRead, DekuWrite, Copy, Clone)]
 #[deku(endian = "big")]
 pub struct CpsrFlags {
@@ -69,10 +506,10 @@ pub enum Mnemonic{
     LDC,        
     LDM,        // impl
     LDR,        // impl
-    LDRB,       // TODO
-    LDRBT,      // TODO
-    LDRH,       // TODO
-    LDRT,       // TODO
+    LDRB,       // impl
+    LDRBT,      // impl
+    LDRH,       // impl
+    LDRT,       // impl
     MCR,
     MLA,        // impl
     MOV,        // impl
@@ -90,14 +527,14 @@ pub enum Mnemonic{
     STC,
     STM,        // impl
     STR,        // impl
-    STRB,       // TODO
-    STRBT,      // TODO
-    STRH,       // TODO
-    STRT,       // TODO
+    STRB,       // impl
+    STRBT,      // impl
+    STRH,       // impl
+    STRT,       // impl
     SUB,        // impl
     SWI,        // TODO
-    SWP,        // TODO
-    SWPB,       // TODO
+    SWP,        // impl
+    SWPB,       // impl
     TEQ,        // impl
     TST,        // impl
     UMLAL,      // TODO
@@ -229,6 +666,29 @@ pub struct BranchExchange {
     pub rn: u32,
 }
 
+#[derive(Debug, PartialEq, DekuRead, DekuWrite, Copy, Clone)]
+#[deku(endian = "big")]
+pub struct Swap {
+    #[deku(bits=4)]
+    pub cond: u32,
+    #[deku(bits=5)]
+    pub _00010: u32,
+    #[deku(bits=1)]
+    pub b: u32,
+    #[deku(bits=2)]
+    pub _00: u32,
+    #[deku(bits=4)]
+    pub rn: u32,
+    #[deku(bits=4)]
+    pub rd: u32,
+    #[deku(bits=4)]
+    pub _0000: u32,
+    #[deku(bits=4)]
+    pub _1001: u32,
+    #[deku(bits=4)]
+    pub rm: u32,
+}
+
 #[derive(Debug, PartialEq, DekuRead, DekuWrite, Copy, Clone)]
 #[deku(endian = "big")]
 pub struct LoadStoreExtention {
@@ -407,9 +867,9 @@ pub struct CoProcessorRegisterTransfer {
 pub struct SoftwareInterrupt {
     #[deku(bits=4)]
     pub cond: u32,
-    #[deku(bits=24)]
-    pub _1111: u32,
     #[deku(bits=4)]
+    pub _1111: u32,
+    #[deku(bits=24)]
     pub imm24: u32,
 }
 
@@ -427,6 +887,7 @@ pub enum InstKind {
     ControlImmediate(ControlImmediate),
     ControlRegister(ControlRegister),
     BranchExchange(BranchExchange),
+    Swap(Swap),
     LoadStoreExtention(LoadStoreExtention),
     SingleDataTransfer(SingleDataTransfer),
     BlockDataTransfer(BlockDataTransfer),
@@ -439,11 +900,20 @@ pub enum InstKind {
 }
 
 
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum BusRW {
     Read,
     Write,
 }
 
+/// Sequential accesses continue from the previous address of the same burst
+/// (instruction stream or LDM/STM); everything else is non-sequential.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BusCycle {
+    NonSequential,
+    Sequential,
+}
+
 
 pub struct BankedRegisters {
     pub fiq: [Word; 7],
@@ -465,6 +935,8 @@ pub struct DecodedInstruction {
     pub inst: InstKind,
     pub cond: u32,
     pub raw_inst: u32,
+    /// The fetch of `raw_inst` failed; executing it takes a prefetch abort.
+    pub fetch_error: Option<BusError>,
 }
 
 pub struct ARMv4T<T: Bus> {
@@ -476,26 +948,101 @@ pub struct ARMv4T<T: Bus> {
     pub bus: T,
     pub inst: Option<Word>,
     pub decoded_inst : Option<DecodedInstruction>,
+    pub unpredictable_policy: UnpredictablePolicy,
+    pub prefetch_policy: PrefetchPolicy,
+    /// The current instruction stored over a prefetched one.
+    pub is_prefetch_overwritten: bool,
+    pub cycles: Cycles,
+    pub is_fetch_sequential: bool,
+    pub wait_states: u64,
+    /// Set by CP15 wait-for-interrupt; cleared when nIRQ or nFIQ is asserted.
+    pub halted: bool,
+    /// Treat a branch to itself (`b .`) as wait-for-interrupt.
+    pub idle_loop_detection: bool,
+    /// Last request raised behind the bus, left for the runner to take. `run` stops while one is pending.
+    pub system_request: Option<SystemRequest>,
+    pub watchpoints: Vec<Watchpoint>,
+    /// First watchpoint hit, left for the caller to take. `run` stops while one is pending.
+    pub watchpoint_hit: Option<WatchpointHit>,
+    /// Address of the instruction the current step executes.
+    pub step_address: Word,
+    /// Error of the fetch that produced `inst`.
+    pub fetch_error: Option<BusError>,
+    /// First failed data access of the current instruction; later accesses are suppressed.
+    pub data_abort: Option<BusError>,
+    /// Last abort taken, left for the caller to take.
+    pub abort: Option<AbortReport>,
+    /// Services semihosting calls on the host instead of taking the exception.
+    pub semihosting: Option<Semihosting>,
+    /// Carries out Linux system calls on the host instead of taking the exception.
+    pub linux: Option<LinuxUser>,
+    /// Host-side handlers for ranges of SWI numbers; the first match wins,
+    /// ahead of semihosting and Linux system calls.
+    pub swi_handlers: Vec<(std::ops::RangeInclusive<Word>, Box<dyn SwiHandler>)>,
 }
 
 
 impl<T> ARMv4T<T>
 where T: Bus
 {
-    pub fn step(&mut self) {
+    /// Runs one pipeline step and returns the cycles of the instruction it executed,
+    /// or no cycles while the pipeline is refilling.
+    pub fn step(&mut self) -> Result<Cycles, EmulatorError> {
+        self.step_address = self.next_instruction_address();
+        self.bus.begin_instruction(self.step_address);
+        let lines = self.bus.interrupt_lines();
+        if self.halted {
+            if !lines.irq && !lines.fiq {
+                return Ok(self.finish_step(Cycles::new(0, 0, 1, 0)));
+            }
+            // wake-up does not depend on the CPSR masks
+            self.halted = false;
+        }
+        if lines.fiq && self.cpsr.f == 0 {
+            return Ok(self.take_exception(Exception::Fiq));
+        }
+        if lines.irq && self.cpsr.i == 0 {
+            return Ok(self.take_exception(Exception::Irq));
+        }
+        if let Some(error) = self.decoded_inst.as_ref().and_then(|decoded| decoded.fetch_error) {
+            self.abort = Some(AbortReport { exception: Exception::PrefetchAbort, pc: self.step_address, error });
+            return Ok(self.take_exception(Exception::PrefetchAbort));
+        }
+
+        if let Some(decoded) = &self.decoded_inst {
+            if self.is_condition_passed(decoded.cond) {
+                if let Some(rule) = self.check_unpredictable(&decoded.inst) {
+                    self.handle_unpredictable(decoded.raw_inst, rule)?;
+                }
+            }
+        }
+
         let mut decoded_inst: Option<DecodedInstruction> = None;
         match self.inst {
             Some(inst) => {
-                decoded_inst = Some(self.decode(inst));
+                let mut decoded = self.decode(inst);
+                decoded.fetch_error = self.fetch_error.take();
+                decoded_inst = Some(decoded);
             }
             None => (),
         }
         self.inst = Some(self.fetch());
 
+        let mut cycles = Cycles::default();
         match &self.decoded_inst {
             Some(decoded) => {
-                let is_pc_changed = self.execute(decoded.inst, decoded.cond);
-                if is_pc_changed {
+                cycles = self.instruction_cycles(&decoded.inst, self.is_condition_passed(decoded.cond));
+                let (inst, cond, raw_inst) = (decoded.inst, decoded.cond, decoded.raw_inst);
+                let host_call = if self.is_condition_passed(cond) { self.host_call(raw_inst) } else { None };
+                let is_pc_changed = match host_call {
+                    Some(is_pc_changed) => is_pc_changed,
+                    None => self.execute(inst, cond),
+                };
+                if let Some(error) = self.data_abort.take() {
+                    self.abort = Some(AbortReport { exception: Exception::DataAbort, pc: self.step_address, error });
+                    self.enter_exception(Exception::DataAbort, self.step_address.wrapping_add(8));
+                }
+                else if is_pc_changed {
                     self.flush_pipeline();
                 }
                 else {
@@ -508,11 +1055,366 @@ where T: Bus
                 self.decoded_inst = decoded_inst;
             },
         }
+        if std::mem::take(&mut self.is_prefetch_overwritten) && self.prefetch_policy == PrefetchPolicy::Coherent {
+            self.invalidate_prefetch();
+        }
+        Ok(self.finish_step(cycles))
+    }
+
+    /// Runs for at least `cycles` cycles and returns how many actually elapsed.
+    /// While halted, time skips straight to the next event behind the bus.
+    /// Stops early once a system request or watchpoint hit is pending.
+    pub fn run(&mut self, cycles: u64) -> Result<u64, EmulatorError> {
+        let start = self.cycles.total();
+        let end = start + cycles;
+        while self.cycles.total() < end && self.system_request.is_none() && self.watchpoint_hit.is_none() {
+            if self.halted && !self.is_interrupt_asserted() {
+                let remaining = end - self.cycles.total();
+                let idle = match self.bus.next_event() {
+                    Some(event) => event.clamp(1, remaining),
+                    None => remaining,
+                };
+                self.bus.tick(idle);
+                // nothing waits on other bus masters while the core is halted
+                self.bus.stolen_cycles();
+                self.cycles.i += idle;
+                self.poll_system_request();
+            }
+            else {
+                self.step()?;
+            }
+        }
+        Ok(self.cycles.total() - start)
+    }
+
+    pub fn is_interrupt_asserted(&mut self) -> bool {
+        let lines = self.bus.interrupt_lines();
+        lines.irq || lines.fiq
+    }
+
+    fn finish_step(&mut self, mut cycles: Cycles) -> Cycles {
+        cycles.wait = self.wait_states;
+        self.wait_states = 0;
+        self.bus.tick(cycles.total());
+        // the CPU waits out bus masters that ran meanwhile; anything they take
+        // while it does is charged to the next step
+        cycles.stall = self.bus.stolen_cycles();
+        if cycles.stall > 0 {
+            self.bus.tick(cycles.stall);
+        }
+        self.cycles += cycles;
+        self.poll_system_request();
+        cycles
+    }
+
+    fn poll_system_request(&mut self) {
+        match self.bus.system_request() {
+            Some(SystemRequest::Reset) => self.reset(),
+            Some(request) => self.system_request = Some(request),
+            None => (),
+        }
+    }
+
+    fn take_exception(&mut self, exception: Exception) -> Cycles {
+        let return_address = self.next_instruction_address().wrapping_add(4);
+        self.enter_exception(exception, return_address);
+        self.finish_step(Cycles::new(1, 2, 0, 0))
+    }
+
+    /// Address of the instruction that the next step would execute.
+    pub fn next_instruction_address(&self) -> Word {
+        if self.decoded_inst.is_some() {
+            self.get_gpr(15).wrapping_sub(8)
+        }
+        else if self.inst.is_some() {
+            self.get_gpr(15).wrapping_sub(4)
+        }
+        else {
+            self.get_gpr(15)
+        }
+    }
+
+    pub fn enter_exception(&mut self, exception: Exception, return_address: Word) {
+        let cpsr = self.get_cpsr_word();
+        let (mode, vector) = match exception {
+            Exception::Reset => (ProcessorMode::Supervisor(0), 0x00),
+            Exception::Undefined => (ProcessorMode::Undefined(0), 0x04),
+            Exception::SoftwareInterrupt => (ProcessorMode::Supervisor(0), 0x08),
+            Exception::PrefetchAbort => (ProcessorMode::Abort(0), 0x0C),
+            Exception::DataAbort => (ProcessorMode::Abort(0), 0x10),
+            Exception::Irq => (ProcessorMode::IRQ(0), 0x18),
+            Exception::Fiq => (ProcessorMode::FIQ(0), 0x1C),
+        };
+        self.set_mode(mode);
+        self.set_spsr(cpsr);
+        self.set_gpr(14, return_address);
+        self.cpsr.t = 0;
+        self.cpsr.i = 1;
+        if exception == Exception::Reset || exception == Exception::Fiq {
+            self.cpsr.f = 1;
+        }
+        self.set_gpr(15, vector);
+        self.flush_pipeline();
+    }
+
+    /// Performs a data access and accounts its wait states to the current step.
+    pub fn bus_access(&mut self, request: &BusRequest, data: &mut Word) -> BusState {
+        if let Some(error) = self.data_abort {
+            return Err(error);
+        }
+        let is_watched = self.watchpoint_hit.is_none() && !self.watchpoints.is_empty();
+        let old = match request.rw {
+            BusRW::Write if is_watched => self.bus.peek(request.addr, request.width),
+            _ => None,
+        };
+        let state = self.bus.transfer(request, data);
+        if is_watched && state.is_ok() && self.watchpoints.iter().any(|watchpoint| watchpoint.matches(request, *data)) {
+            self.watchpoint_hit = Some(WatchpointHit {
+                pc: self.step_address,
+                addr: request.addr,
+                width: request.width,
+                rw: request.rw,
+                old: if request.rw == BusRW::Read { Some(*data) } else { old },
+                new: *data,
+            });
+        }
+        match state {
+            Ok(wait_states) => self.wait_states += wait_states as u64,
+            Err(error) => self.data_abort = Some(error),
+        }
+        if request.rw == BusRW::Write && state.is_ok() {
+            // the pipeline holds the two instructions after the current one
+            let prefetched = self.step_address.wrapping_add(4);
+            let overlaps = request.addr.wrapping_sub(prefetched) < 8
+                || prefetched.wrapping_sub(request.addr) < request.width.bytes();
+            self.is_prefetch_overwritten |= overlaps;
+        }
+        // a data access breaks the sequential instruction stream
+        self.is_fetch_sequential = false;
+        state
+    }
+
+    /// ARM7TDMI timing of `inst`, evaluated before it executes so that register
+    /// dependent costs (multiplier early termination) see the operand values.
+    pub fn instruction_cycles(&self, inst: &InstKind, is_executed: bool) -> Cycles {
+        if !is_executed {
+            return Cycles::new(0, 1, 0, 0);
+        }
+        match inst {
+            InstKind::DataProcess(inst) => {
+                let shift_by_register = inst.i == 0 && inst.operand2 & 0x10 != 0;
+                let writes_pc = inst.rd == 15 && !(0x8..=0xB).contains(&inst.opcode);
+                let mut cycles = Cycles::new(0, 1, shift_by_register as u64, 0);
+                if writes_pc {
+                    cycles += Cycles::new(1, 1, 0, 0);
+                }
+                cycles
+            }
+            InstKind::Multiply(inst) => {
+                let m = multiply_cycles(self.get_gpr(inst.rs as u8), true);
+                Cycles::new(0, 1, m + inst.a as u64, 0)
+            }
+            InstKind::MultiplyLong(inst) => {
+                let m = multiply_cycles(self.get_gpr(inst.rs as u8), inst.signed != 0);
+                Cycles::new(0, 1, m + 1 + inst.a as u64, 0)
+            }
+            InstKind::ControlImmediate(_) | InstKind::ControlRegister(_) => Cycles::new(0, 1, 0, 0),
+            InstKind::BranchExchange(_) | InstKind::Branch(_) => Cycles::new(1, 2, 0, 0),
+            InstKind::SingleDataTransfer(inst) => {
+                if inst.l == 0 {
+                    Cycles::new(2, 0, 0, 0)
+                }
+                else if inst.rd == 15 {
+                    Cycles::new(2, 2, 1, 0)
+                }
+                else {
+                    Cycles::new(1, 1, 1, 0)
+                }
+            }
+            InstKind::Swap(_) => Cycles::new(2, 1, 1, 0),
+            InstKind::LoadStoreExtention(inst) => {
+                if inst.l == 0 {
+                    Cycles::new(2, 0, 0, 0)
+                }
+                else if inst.rd == 15 {
+                    Cycles::new(2, 2, 1, 0)
+                }
+                else {
+                    Cycles::new(1, 1, 1, 0)
+                }
+            }
+            InstKind::BlockDataTransfer(inst) => {
+                let count = (inst.register_list.count_ones() as u64).max(1);
+                if inst.l == 0 {
+                    Cycles::new(2, count - 1, 0, 0)
+                }
+                else if inst.register_list & (1 << 15) != 0 {
+                    Cycles::new(2, count + 1, 1, 0)
+                }
+                else {
+                    Cycles::new(1, count, 1, 0)
+                }
+            }
+            InstKind::CoProcessorDataOperation(_) => Cycles::new(0, 1, 0, 0),
+            InstKind::CoProcessorDataTransfer(_) => Cycles::new(2, 0, 0, 0),
+            InstKind::CoProcessorRegisterTransfer(inst) => {
+                if inst.l == 0 {
+                    Cycles::new(1, 0, 0, 1)
+                }
+                else {
+                    Cycles::new(0, 1, 1, 1)
+                }
+            }
+            InstKind::SoftwareInterrupt(_) => Cycles::new(1, 2, 0, 0),
+            InstKind::Undefined => Cycles::new(1, 2, 1, 0),
+        }
+    }
+
+    /// Non-sequential data access of `width` at `addr` (aligned down) with the privilege of the current mode.
+    pub fn data_request(&self, addr: Word, rw: BusRW, width: BusWidth) -> BusRequest {
+        BusRequest {
+            addr: addr & !(width.bytes() - 1),
+            rw,
+            width,
+            kind: BusKind::Data,
+            privilege: self.privilege(),
+            cycle: BusCycle::NonSequential,
+        }
+    }
+
+    pub fn privilege(&self) -> Privilege {
+        if self.mode == ProcessorMode::User(0) {
+            Privilege::User
+        }
+        else {
+            Privilege::Privileged
+        }
+    }
+
+    /// Returns the UNPREDICTABLE rule `inst` breaks in the current mode, if any.
+    pub fn check_unpredictable(&self, inst: &InstKind) -> Option<UnpredictableRule> {
+        let has_no_spsr = self.mode == ProcessorMode::User(0) || self.mode == ProcessorMode::System(0);
+        match inst {
+            InstKind::SingleDataTransfer(inst) if inst.rn == 15 && (inst.p == 0 || inst.w == 1) => {
+                Some(UnpredictableRule::WritebackToPc)
+            }
+            InstKind::LoadStoreExtention(inst) if inst.rn == 15 && (inst.p == 0 || inst.w == 1) => {
+                Some(UnpredictableRule::WritebackToPc)
+            }
+            InstKind::BlockDataTransfer(inst) if inst.rn == 15 && inst.w == 1 => {
+                Some(UnpredictableRule::WritebackToPc)
+            }
+            InstKind::BlockDataTransfer(inst) if inst.l == 1 && inst.w == 1 && inst.register_list & (1 << inst.rn) != 0 => {
+                Some(UnpredictableRule::LoadMultipleBaseInList)
+            }
+            InstKind::Multiply(inst) if inst.rd == inst.rm => {
+                Some(UnpredictableRule::MultiplyRdIsRm)
+            }
+            InstKind::MultiplyLong(inst) if inst.rd == inst.rn || inst.rd == inst.rm || inst.rn == inst.rm => {
+                Some(UnpredictableRule::MultiplyLongOverlap)
+            }
+            InstKind::ControlRegister(inst) if (inst.op1 & 0b01 == 0 && inst.rd == 15) || (inst.op1 & 0b01 != 0 && inst.rm == 15) => {
+                Some(UnpredictableRule::PsrTransferWithPc)
+            }
+            InstKind::ControlRegister(inst) if inst.op1 & 0b10 != 0 && has_no_spsr => {
+                Some(UnpredictableRule::SpsrInUserOrSystem)
+            }
+            InstKind::ControlImmediate(inst) if inst.op1 & 0b10 != 0 && has_no_spsr => {
+                Some(UnpredictableRule::SpsrInUserOrSystem)
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_unpredictable(&self, raw_inst: Word, rule: UnpredictableRule) -> Result<(), EmulatorError> {
+        let error = EmulatorError::Unpredictable {
+            pc: self.get_gpr(15).wrapping_sub(8),
+            raw_inst,
+            rule,
+        };
+        match self.unpredictable_policy {
+            UnpredictablePolicy::Emulate => Ok(()),
+            UnpredictablePolicy::Warn => {
+                eprintln!("warning: {}", error);
+                Ok(())
+            }
+            UnpredictablePolicy::Error => Err(error),
+        }
+    }
+
+    /// Handles SWIs numbered `numbers` (inclusive) on the host with `handler`.
+    /// The Thumb SWI cannot be handled, as the core does not decode Thumb.
+    pub fn add_swi_handler(&mut self, numbers: std::ops::RangeInclusive<Word>, handler: Box<dyn SwiHandler>) {
+        self.swi_handlers.push((numbers, handler));
+    }
+
+    /// Runs the handler registered for the SWI `raw_inst`, if any, and
+    /// returns whether it changed the PC.
+    fn swi_handler_call(&mut self, raw_inst: Word) -> Option<bool> {
+        if raw_inst & 0x0F000000 != 0x0F000000 {
+            return None;
+        }
+        let number = raw_inst & 0x00FFFFFF;
+        let index = self.swi_handlers.iter().position(|(numbers, _)| numbers.contains(&number))?;
+        let next_address = self.step_address.wrapping_add(4);
+        let mut regs: [Word; 16] = std::array::from_fn(|reg| self.get_gpr(reg as u8));
+        regs[15] = next_address;
+        let cpsr = self.get_cpsr_word();
+        let mut call = SwiCall { number, regs, cpsr, bus: &mut self.bus, request: None };
+        self.swi_handlers[index].1.call(&mut call);
+        let SwiCall { regs, cpsr: new_cpsr, request, .. } = call;
+
+        for (reg, value) in regs.iter().enumerate().take(15) {
+            self.set_gpr(reg as u8, *value);
+        }
+        self.set_cpsr_word((cpsr & 0x0FFFFFFF) | (new_cpsr & 0xF0000000));
+        if request.is_some() {
+            self.system_request = request;
+        }
+        if regs[15] != next_address {
+            self.set_gpr(15, regs[15]);
+            return Some(true);
+        }
+        Some(false)
+    }
+
+    /// Carries out `raw_inst` on the host instead if it is an SWI with a
+    /// registered handler, a semihosting call or a Linux system call.
+    /// Returns whether that changed the PC, or `None` if it was none of these.
+    fn host_call(&mut self, raw_inst: Word) -> Option<bool> {
+        if let Some(is_pc_changed) = self.swi_handler_call(raw_inst) {
+            return Some(is_pc_changed);
+        }
+        let (result, request) = if let Some(semihosting) = self.semihosting.as_mut().filter(|semihosting| semihosting.is_call(raw_inst)) {
+            // r0 and r1 are never banked
+            semihosting.call(self.r[0], self.r[1], &mut self.bus)
+        }
+        else if let Some(number) = self.linux.as_ref().and_then(|linux| linux.syscall_number(raw_inst, self.get_gpr(7))) {
+            let args = [0, 1, 2, 3, 4, 5].map(|reg| self.get_gpr(reg));
+            self.linux.as_mut().unwrap().syscall(number, args, &mut self.bus)
+        }
+        else {
+            return None;
+        };
+        self.set_gpr(0, result);
+        if request.is_some() {
+            self.system_request = request;
+        }
+        Some(false)
+    }
+
+    /// Discards the prefetched instructions so that they are fetched again
+    /// from memory, e.g. after patching code.
+    pub fn invalidate_prefetch(&mut self) {
+        self.set_gpr(15, self.next_instruction_address());
+        self.flush_pipeline();
     }
 
     pub fn flush_pipeline(&mut self) {
         self.inst = None;
         self.decoded_inst = None;
+        self.fetch_error = None;
+        self.is_fetch_sequential = false;
     }
 
     pub fn execute(&mut self, decoded_inst: InstKind, cond: u32) -> bool {
@@ -521,7 +1423,6 @@ where T: Bus
             match decoded_inst {
                 // TODO
                 // LoadStoreExtention
-                // SoftwareInterrupt
                 InstKind::MultiplyLong(inst) => {
                     let rdhi: u32;
                     let rdlo: u32;
@@ -606,8 +1507,9 @@ where T: Bus
                     }
                 }
                 InstKind::BranchExchange(inst) => {
-                    self.set_gpr(inst.rn as u8, self.get_gpr(14));
-                    self.cpsr.t = self.get_gpr(inst.rn as u8) & 0x1;
+                    let target = self.get_gpr(inst.rn as u8);
+                    self.cpsr.t = target & 0x1;
+                    self.set_gpr(15, target & !0x1);
                     is_pc_changed = true;
                 }
                 InstKind::BlockDataTransfer(inst) => {
@@ -617,50 +1519,79 @@ where T: Bus
                     }
                     else if inst.u == 1 && inst.p == 1 {
                         // increment before
-                        self.get_gpr(inst.rn as u8) + 4
+                        self.get_gpr(inst.rn as u8).wrapping_add(4)
                     }
                     else if inst.u == 0 && inst.p == 0 {
                         // decrement after
-                        self.get_gpr(inst.rn as u8) - inst.register_list.count_ones() as u32 * 4 + 4
+                        self.get_gpr(inst.rn as u8).wrapping_sub(inst.register_list.count_ones() * 4).wrapping_add(4)
                     }
                     else {
                         // decrement before
-                        self.get_gpr(inst.rn as u8) - inst.register_list.count_ones() as u32 * 4
+                        self.get_gpr(inst.rn as u8).wrapping_sub(inst.register_list.count_ones() * 4)
                     };
                     
                     let mut address = start_address;
+                    let written_back_base = if inst.u == 1 {
+                        self.get_gpr(inst.rn as u8).wrapping_add(inst.register_list.count_ones() * 4)
+                    }
+                    else {
+                        self.get_gpr(inst.rn as u8).wrapping_sub(inst.register_list.count_ones() * 4)
+                    };
+                    // ARM7TDMI writes the base back at the end of the second cycle:
+                    // before any LDM load (so a loaded base wins) and after the first STM store.
+                    if inst.w == 1 && inst.l == 1 {
+                        self.set_gpr(inst.rn as u8, written_back_base);
+                    }
+                    let mut is_first_transfer = true;
 
                     for i in 0..16 {
                         if inst.register_list & (1 << i) != 0 {
+                            let rw = if inst.l == 1 { BusRW::Read } else { BusRW::Write };
+                            let mut request = self.data_request(address, rw, BusWidth::Word);
+                            if !is_first_transfer {
+                                request.cycle = BusCycle::Sequential;
+                            }
                             if inst.l == 1 {
                                 let mut data: Word = 0;
-                                _ = self.bus.access(address, &mut data, BusRW::Read);
-                                self.set_gpr(i as u8, data);
+                                if self.bus_access(&request, &mut data).is_ok() {
+                                    self.set_gpr(i as u8, data);
+                                }
                             }
                             else {
                                 let mut data = self.get_gpr(i as u8);
-                                _ = self.bus.access(address, &mut data, BusRW::Write);
+                                _ = self.bus_access(&request, &mut data);
+                                if inst.w == 1 && is_first_transfer {
+                                    self.set_gpr(inst.rn as u8, written_back_base);
+                                }
                             }
-                            address = if inst.u == 1 {address + 4} else {address - 4};
+                            is_first_transfer = false;
+                            // the lowest register always uses the lowest address
+                            address = address.wrapping_add(4);
                         }
                     
                     }
+                    if inst.l == 1 && inst.register_list & (1 << 15) != 0 && self.data_abort.is_none() {
+                        // LDM with ^ and PC in the list returns from an exception
+                        if inst.s == 1 {
+                            self.set_cpsr_word(self.get_spsr());
+                        }
+                        is_pc_changed = true;
+                    }
                 }
                 InstKind::SingleDataTransfer(inst) => {
+                    if inst.l != 0 && inst.rd == 15 {
+                        is_pc_changed = true;
+                    }
                     let rn = self.get_gpr(inst.rn as u8);
                     let offset: u32;
                     if inst.i != 0 {
                         let rm  = self.get_gpr(get_bit_range(inst.offset, 3, 0) as u8);
                         let shift_imm = get_bit_range(inst.offset, 11, 7);
+                        // LSR #0 and ASR #0 encode a shift by 32
                         offset = match get_bit_range(inst.offset, 6, 5) {
                             0b00 => rm << shift_imm,
-                            0b01 => rm >> shift_imm,
-                            0b10 => if rm & 0x80000000 != 0 {
-                                (rm >> shift_imm) | (0xFFFFFFFF << (32 - shift_imm))
-                            }
-                            else {
-                                rm >> shift_imm
-                            }
+                            0b01 => if shift_imm == 0 { 0 } else { rm >> shift_imm },
+                            0b10 => ((rm as i32) >> if shift_imm == 0 { 31 } else { shift_imm }) as u32,
                             0b11 => if shift_imm != 0 {rm.rotate_right(shift_imm)} else {(rm >> 1)| ((self.cpsr.c as u32) << 31)},
                             _ => 0,
                         };
@@ -668,48 +1599,91 @@ where T: Bus
                     else {
                         offset = inst.offset;
                     }
-                    let address = if inst.u != 0 { rn + offset } else { rn - offset };
-
-                    // normal operation
-                    if inst.p == 1 && inst.w == 0 {
-                        if inst.l != 0 {
-                            let mut data: Word = 0;
-                            _ =  self.bus.access(address, &mut data, BusRW::Read);
-                            self.set_gpr(inst.rd as u8, data);
+                    let address = if inst.u != 0 { rn.wrapping_add(offset) } else { rn.wrapping_sub(offset) };
+                    // pre-indexed accesses use the offset address, post-indexed the base
+                    let transfer_address = if inst.p == 1 { address } else { rn };
+                    let width = if inst.b != 0 { BusWidth::Byte } else { BusWidth::Word };
+
+                    if inst.l != 0 {
+                        let mut request = self.data_request(transfer_address, BusRW::Read, width);
+                        // LDRT/LDRBT: post-indexed with W set accesses memory as User
+                        if inst.p == 0 && inst.w == 1 {
+                            request.privilege = Privilege::User;
                         }
-                        else {
-                            let mut data = self.get_gpr(inst.rd as u8);
-                            _ = self.bus.access(address, &mut data, BusRW::Write);
-                        }
-                        ()
-                    }
-                    // pre-indexed
-                    if inst.p == 1 && inst.w == 1 {
-                        if inst.l != 0 {
-                            let mut data: Word = 0;
-                            _ =  self.bus.access(address, &mut data, BusRW::Read);
+                        let mut data: Word = 0;
+                        if self.bus_access(&request, &mut data).is_ok() {
+                            if width == BusWidth::Word {
+                                // unaligned word loads rotate the addressed byte into bits 7:0
+                                data = data.rotate_right((transfer_address & 0x3) * 8);
+                            }
                             self.set_gpr(inst.rd as u8, data);
                         }
-                        else {
-                            let mut data = self.get_gpr(inst.rd as u8);
-                            _ = self.bus.access(address, &mut data, BusRW::Write);
+                    }
+                    else {
+                        let mut request = self.data_request(transfer_address, BusRW::Write, width);
+                        // STRT/STRBT
+                        if inst.p == 0 && inst.w == 1 {
+                            request.privilege = Privilege::User;
                         }
+                        let mut data = self.get_gpr(inst.rd as u8) & width.mask();
+                        _ = self.bus_access(&request, &mut data);
+                    }
+                    if inst.p == 0 || inst.w == 1 {
                         self.set_gpr(inst.rn as u8, address);
-                        ()
                     }
-                    // post-indexed
-                    if inst.p == 0 && inst.w == 0 {
-                        if inst.l != 0 {
-                            let mut data: Word = 0;
-                            _ =  self.bus.access(rn, &mut data, BusRW::Read);
+                }
+                InstKind::LoadStoreExtention(inst) => {
+                    if inst.l != 0 && inst.rd == 15 {
+                        is_pc_changed = true;
+                    }
+                    let rn = self.get_gpr(inst.rn as u8);
+                    // bit 22 selects the split 8-bit immediate offset over Rm
+                    let offset = if inst.b != 0 { (inst.rs << 4) | inst.offset2 } else { self.get_gpr(inst.offset2 as u8) };
+                    let address = if inst.u != 0 { rn.wrapping_add(offset) } else { rn.wrapping_sub(offset) };
+                    let transfer_address = if inst.p == 1 { address } else { rn };
+
+                    if inst.l != 0 {
+                        let width = if inst.op1 == 0b10 { BusWidth::Byte } else { BusWidth::HalfWord };
+                        let request = self.data_request(transfer_address, BusRW::Read, width);
+                        let mut data: Word = 0;
+                        let state = self.bus_access(&request, &mut data);
+                        let data = match inst.op1 {
+                            // LDRSB
+                            0b10 => data as u8 as i8 as i32 as u32,
+                            // LDRSH
+                            0b11 => data as u16 as i16 as i32 as u32,
+                            // LDRH
+                            _ => data,
+                        };
+                        if state.is_ok() {
                             self.set_gpr(inst.rd as u8, data);
                         }
-                        else {
-                            let mut data = self.get_gpr(inst.rd as u8);
-                            _ = self.bus.access(rn, &mut data, BusRW::Write);
-                        }
+                    }
+                    else {
+                        // STRH
+                        let request = self.data_request(transfer_address, BusRW::Write, BusWidth::HalfWord);
+                        let mut data = self.get_gpr(inst.rd as u8) & 0xFFFF;
+                        _ = self.bus_access(&request, &mut data);
+                    }
+                    if inst.p == 0 || inst.w == 1 {
                         self.set_gpr(inst.rn as u8, address);
-                        ()
+                    }
+                }
+                InstKind::Swap(inst) => {
+                    let address = self.get_gpr(inst.rn as u8);
+                    let width = if inst.b != 0 { BusWidth::Byte } else { BusWidth::Word };
+                    let mut request = self.data_request(address, BusRW::Read, width);
+                    request.kind = BusKind::Swap;
+                    let mut data: Word = 0;
+                    _ = self.bus_access(&request, &mut data);
+                    request.rw = BusRW::Write;
+                    let mut new_data = self.get_gpr(inst.rm as u8) & width.mask();
+                    _ = self.bus_access(&request, &mut new_data);
+                    if width == BusWidth::Word {
+                        data = data.rotate_right((address & 0x3) * 8);
+                    }
+                    if self.data_abort.is_none() {
+                        self.set_gpr(inst.rd as u8, data);
                     }
                 }
                 InstKind::DataProcess(inst) => {
@@ -857,7 +1831,7 @@ where T: Bus
                             n = ((_result & 0x80000000) != 0) as u32;
                             z = (_result == 0) as u32;
                             c = shifter_operand.carry_out as u32;
-                            shifter_operand.shifter_operand
+                            _result
                         }
                         _ => 0,
                     };
@@ -868,8 +1842,9 @@ where T: Bus
                         self.cpsr.c = c;
                         self.cpsr.v = v;
                     }
-                    else if inst.rd == 15 {
-                        self.store_spsr();
+                    else if inst.rd == 15 && inst.s != 0 {
+                        // MOVS/SUBS pc, ... returns from an exception
+                        self.set_cpsr_word(self.get_spsr());
                     }
                     else {
                         ()
@@ -888,6 +1863,24 @@ where T: Bus
                     }
                     self.set_gpr(   15, self.get_gpr(15).overflowing_add(offset).0);
                     is_pc_changed = true;
+                    if self.idle_loop_detection && !link && inst.offset == 0xFFFFFE {
+                        self.halted = true;
+                    }
+                },
+                InstKind::SoftwareInterrupt(_) => {
+                    self.enter_exception(Exception::SoftwareInterrupt, self.step_address.wrapping_add(4));
+                    is_pc_changed = true;
+                },
+                InstKind::CoProcessorRegisterTransfer(inst) if is_wait_for_interrupt(&inst) => {
+                    self.halted = true;
+                },
+                // other CP15 c7 writes, such as prefetching a line, have no effect here
+                InstKind::CoProcessorRegisterTransfer(inst) if inst.cp_num == 15 && inst.l == 0 && inst.crn == 7 => {
+                    if let Some(operation) = cache_operation(&inst, self.get_gpr(inst.rd as u8)) {
+                        if let Ok(wait_states) = self.bus.cache_operation(operation) {
+                            self.wait_states += wait_states as u64;
+                        }
+                    }
                 },
                 _ => {
                     println!("{}", self);
@@ -905,9 +1898,9 @@ where T: Bus
         const DATA_PROCESS: InstFormat                  = InstFormat{ mask: 0x0c000000, data: 0x00000000 };
         const MULTIPLY: InstFormat                      = InstFormat{ mask: 0x0FC000F0, data: 0x00000090 };
         const MULTIPLY_LONG: InstFormat                 = InstFormat{ mask: 0x0F8000F0, data: 0x00800090 };
-        const CONTROL_IMM: InstFormat                   = InstFormat{ mask: 0x0F900000, data: 0x01000000 };
-        const CONTROL_REG1: InstFormat                  = InstFormat{ mask: 0x0F900010, data: 0x03000000 };
-        const CONTROL_REG2: InstFormat                  = InstFormat{ mask: 0x0F900090, data: 0x00000010 };
+        const CONTROL_IMM: InstFormat                   = InstFormat{ mask: 0x0FB00000, data: 0x03200000 };
+        const CONTROL_REG: InstFormat                   = InstFormat{ mask: 0x0F900FF0, data: 0x01000000 };
+        const SWAP: InstFormat                          = InstFormat{ mask: 0x0FB00FF0, data: 0x01000090 };
         const LOAD_STORE_EXTENTION: InstFormat          = InstFormat{ mask: 0x0E000090, data: 0x00000090 };
         const BRANCH_EXCHANGE: InstFormat               = InstFormat{ mask: 0x0FFFFFF0, data: 0x012FFF10 };
         const SINGLE_DATA_TRANSFER: InstFormat          = InstFormat{ mask: 0x0C000000, data: 0x04000000 };
@@ -922,8 +1915,12 @@ where T: Bus
         let mut inst_kind: InstKind = InstKind::Undefined;
 
         if is_match_format(inst, DATA_PROCESS) {
+            if is_match_format(inst, BRANCH_EXCHANGE){
+                let (_, branch_exchange) = BranchExchange::from_bytes((inst.to_be_bytes().as_ref(), 0)).unwrap();
+                inst_kind = InstKind::BranchExchange(branch_exchange);
+            }
             // arithmetic extention
-            if is_match_format(inst, MULTIPLY){
+            else if is_match_format(inst, MULTIPLY){
                 let (_, multiply) = Multiply::from_bytes((inst.to_be_bytes().as_ref(), 0)).unwrap();
                 inst_kind = InstKind::Multiply(multiply);
             }
@@ -931,13 +1928,21 @@ where T: Bus
                 let (_, multiply_long) = MultiplyLong::from_bytes((inst.to_be_bytes().as_ref(), 0)).unwrap();
                 inst_kind = InstKind::MultiplyLong(multiply_long);
             }
+            else if is_match_format(inst, SWAP){
+                let (_, swap) = Swap::from_bytes((inst.to_be_bytes().as_ref(), 0)).unwrap();
+                inst_kind = InstKind::Swap(swap);
+            }
+            // load/store extension (bits 6:5 == 0 are the multiplies and swaps above)
+            else if is_match_format(inst, LOAD_STORE_EXTENTION) && get_bit_range(inst, 6, 5) != 0 {
+                let (_, load_store_extention) = LoadStoreExtention::from_bytes((inst.to_be_bytes().as_ref(), 0)).unwrap();
+                inst_kind = InstKind::LoadStoreExtention(load_store_extention);
+            }
             // control extention
             else if is_match_format(inst, CONTROL_IMM) {
                 let (_, control_extentsion) = ControlImmediate::from_bytes((inst.to_be_bytes().as_ref(), 0)).unwrap();
                 inst_kind = InstKind::ControlImmediate(control_extentsion);
             }
-            // load/store extension
-            else if is_match_format(inst, CONTROL_REG1) || is_match_format(inst, CONTROL_REG2) {
+            else if is_match_format(inst, CONTROL_REG) {
                 let (_, control_register) = ControlRegister::from_bytes((inst.to_be_bytes().as_ref(), 0)).unwrap();
                 inst_kind = InstKind::ControlRegister(control_register);
             }
@@ -946,10 +1951,6 @@ where T: Bus
                 inst_kind = InstKind::DataProcess(data_process);
             }
         }
-        else if is_match_format(inst, BRANCH_EXCHANGE){
-            let (_, branch_exchange) = BranchExchange::from_bytes((inst.to_be_bytes().as_ref(), 0)).unwrap();
-            inst_kind = InstKind::BranchExchange(branch_exchange);
-        }
         else if is_match_format(inst, SINGLE_DATA_TRANSFER){
             let (_, single_data_transfer) = SingleDataTransfer::from_bytes((inst.to_be_bytes().as_ref(), 0)).unwrap();
             inst_kind = InstKind::SingleDataTransfer(single_data_transfer);
@@ -984,7 +1985,8 @@ where T: Bus
         DecodedInstruction {
             inst: inst_kind,
             cond: cond,
-            raw_inst: inst 
+            raw_inst: inst,
+            fetch_error: None,
         }
     }
 
@@ -1166,7 +2168,19 @@ where T: Bus
     pub fn fetch(&mut self) -> Word {
         let pc = self.get_gpr(15);
         let mut data: Word = 0;
-        _ = self.bus.access(pc, &mut data, BusRW::Read);
+        let request = BusRequest {
+            addr: pc & !0x3,
+            rw: BusRW::Read,
+            width: BusWidth::Word,
+            kind: BusKind::Fetch,
+            privilege: self.privilege(),
+            cycle: if self.is_fetch_sequential { BusCycle::Sequential } else { BusCycle::NonSequential },
+        };
+        match self.bus.transfer(&request, &mut data) {
+            Ok(wait_states) => self.wait_states += wait_states as u64,
+            Err(error) => self.fetch_error = Some(error),
+        }
+        self.is_fetch_sequential = true;
         data
     }
 
@@ -1198,11 +2212,29 @@ where T: Bus
             bus: bus,
             inst: None,
             decoded_inst: None,
+            unpredictable_policy: UnpredictablePolicy::Emulate,
+            prefetch_policy: PrefetchPolicy::Stale,
+            is_prefetch_overwritten: false,
+            cycles: Cycles::default(),
+            is_fetch_sequential: false,
+            wait_states: 0,
+            halted: false,
+            idle_loop_detection: false,
+            system_request: None,
+            watchpoints: Vec::new(),
+            watchpoint_hit: None,
+            step_address: 0,
+            fetch_error: None,
+            data_abort: None,
+            abort: None,
+            semihosting: None,
+            linux: None,
+            swi_handlers: Vec::new(),
         }
     }
 
     pub fn advance_pc(&mut self, offset: Word) {
-        self.r[15] += offset;
+        self.r[15] = self.r[15].wrapping_add(offset);
     }
 
     pub fn reset (&mut self) {
@@ -1220,6 +2252,12 @@ where T: Bus
             und: [0; 2],
         };
         self.spsr = [0; 5];
+        self.set_mode(ProcessorMode::Supervisor(0));
+        self.cpsr.i = 1;
+        self.cpsr.f = 1;
+        self.cpsr.t = 0;
+        self.halted = false;
+        self.flush_pipeline();
     }
 
     pub fn store_spsr(&mut self) {
@@ -1282,6 +2320,17 @@ where T: Bus
 
     pub fn set_cpsr(&mut self, value: CpsrFlags) {
         self.cpsr = value;
+        if let Some(mode) = ProcessorMode::from_bits(value.mode) {
+            self.mode = mode;
+        }
+    }
+
+    pub fn get_cpsr_word(&self) -> Word {
+        u32::from_be_bytes(self.get_cpsr().to_bytes().unwrap().try_into().unwrap())
+    }
+
+    pub fn set_cpsr_word(&mut self, value: Word) {
+        self.set_cpsr(CpsrFlags::from_bytes((value.to_be_bytes().as_ref(), 0)).unwrap().1);
     }
 
     pub fn get_spsr(&self) -> Word {
@@ -1313,6 +2362,33 @@ pub fn is_match_format(inst: Word, format: InstFormat) -> bool {
 }
 
 
+/// MCR p15, 0, Rd, c7, c0, 4: wait for interrupt.
+pub fn is_wait_for_interrupt(inst: &CoProcessorRegisterTransfer) -> bool {
+    inst.cp_num == 15 && inst.l == 0 && inst.cp_opc == 0 && inst.crn == 7 && inst.crm == 0 && inst.cp == 4
+}
+
+
+/// MCR p15, 0, Rd, c7, CRm, opcode_2 cache maintenance, as on ARM920T.
+pub fn cache_operation(inst: &CoProcessorRegisterTransfer, rd: Word) -> Option<CacheOperation> {
+    if inst.cp_num != 15 || inst.l != 0 || inst.cp_opc != 0 || inst.crn != 7 {
+        return None;
+    }
+    match (inst.crm, inst.cp) {
+        (7, 0) => Some(CacheOperation::InvalidateAll),
+        (5, 0) => Some(CacheOperation::InvalidateInstructions),
+        (5, 1) => Some(CacheOperation::InvalidateInstructionLine(rd)),
+        (6, 0) => Some(CacheOperation::InvalidateData),
+        (6, 1) => Some(CacheOperation::InvalidateDataLine(rd)),
+        (10, 1) => Some(CacheOperation::CleanDataLine(rd)),
+        (10, 2) => Some(CacheOperation::CleanDataIndex(rd)),
+        (14, 1) => Some(CacheOperation::CleanInvalidateDataLine(rd)),
+        (14, 2) => Some(CacheOperation::CleanInvalidateDataIndex(rd)),
+        (10, 4) => Some(CacheOperation::DrainWriteBuffer),
+        _ => None,
+    }
+}
+
+
 pub fn get_bit_range(data: Word, msb: u8, lsb: u8) -> Word {
     if lsb > msb {
         return 0;
@@ -1526,6 +2602,27 @@ pub fn disassemble(inst: Word) -> String {
 
 
 
+/// Number of multiplier cycles (m) for a multiply by `rs`: the ARM7TDMI multiplier
+/// terminates early once the remaining bits of Rs are all zeros (or all ones if signed).
+pub fn multiply_cycles(rs: Word, signed: bool) -> u64 {
+    let is_done = |mask: u32| {
+        let upper = rs & mask;
+        upper == 0 || (signed && upper == mask)
+    };
+    if is_done(0xFFFFFF00) {
+        1
+    }
+    else if is_done(0xFFFF0000) {
+        2
+    }
+    else if is_done(0xFF000000) {
+        3
+    }
+    else {
+        4
+    }
+}
+
 pub fn check_add_overflow(a: u32, b: u32, result: u32) -> bool {
     let a_sign = (a & 0x80000000) != 0;
     let b_sign = (b & 0x80000000) != 0;
@@ -1564,10 +2661,474 @@ impl<T: Bus> std::fmt::Display for ARMv4T<T> {
             }
         }
         
+        formatted_string.push_str(&format!("cycles: {} ({}N {}S {}I {}C {}W {} stalled)\n",
+            self.cycles.total(), self.cycles.n, self.cycles.s, self.cycles.i, self.cycles.c, self.cycles.wait, self.cycles.stall));
+
         if let Some(decoded_inst) = &self.decoded_inst {
             formatted_string.push_str(&format!("\n{}\n", disassemble(decoded_inst.raw_inst)));
         }
 
         write!(f, "{}", formatted_string)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_memory::SparseMemory;
+
+    fn memory(program: &[Word]) -> SparseMemory {
+        let mut memory = SparseMemory::new();
+        for (i, inst) in program.iter().enumerate() {
+            memory.load(i as Word * 4, &inst.to_le_bytes());
+        }
+        memory
+    }
+
+    /// A core reset into `program`, loaded at address 0.
+    fn cpu(program: &[Word]) -> ARMv4T<SparseMemory> {
+        let mut cpu = ARMv4T::new(memory(program));
+        cpu.reset();
+        cpu
+    }
+
+    /// Steps until the instruction at `addr` is the next to execute.
+    fn run_to<T: Bus>(cpu: &mut ARMv4T<T>, addr: Word) {
+        for _ in 0..1000 {
+            if cpu.decoded_inst.is_some() && cpu.get_gpr(15).wrapping_sub(8) == addr {
+                return;
+            }
+            cpu.step().unwrap();
+        }
+        panic!("never reached 0x{:08x}", addr);
+    }
+
+    fn unpredictable_rule(cpu: &ARMv4T<SparseMemory>, raw_inst: Word) -> Option<UnpredictableRule> {
+        cpu.check_unpredictable(&cpu.decode(raw_inst).inst)
+    }
+
+    #[test]
+    fn detects_unpredictable_encodings() {
+        let mut cpu = cpu(&[]);
+        // ldr r0, [pc], #4
+        assert_eq!(unpredictable_rule(&cpu, 0xE49F0004), Some(UnpredictableRule::WritebackToPc));
+        // ldmia r0!, {r0, r1}
+        assert_eq!(unpredictable_rule(&cpu, 0xE8B00003), Some(UnpredictableRule::LoadMultipleBaseInList));
+        // stmia r0!, {r0, r1}
+        assert_eq!(unpredictable_rule(&cpu, 0xE8A00003), None);
+        // mul r0, r0, r1
+        assert_eq!(unpredictable_rule(&cpu, 0xE0000190), Some(UnpredictableRule::MultiplyRdIsRm));
+        // umull r0, r0, r1, r2
+        assert_eq!(unpredictable_rule(&cpu, 0xE0800291), Some(UnpredictableRule::MultiplyLongOverlap));
+        // mrs pc, cpsr
+        assert_eq!(unpredictable_rule(&cpu, 0xE10FF000), Some(UnpredictableRule::PsrTransferWithPc));
+        // mrs r0, spsr
+        assert_eq!(unpredictable_rule(&cpu, 0xE14F0000), None);
+        cpu.set_mode(ProcessorMode::User(0));
+        assert_eq!(unpredictable_rule(&cpu, 0xE14F0000), Some(UnpredictableRule::SpsrInUserOrSystem));
+        // msr spsr_f, #0xF0000000
+        assert_eq!(unpredictable_rule(&cpu, 0xE368F20F), Some(UnpredictableRule::SpsrInUserOrSystem));
+        // mov r0, r1
+        assert_eq!(unpredictable_rule(&cpu, 0xE1A00001), None);
+    }
+
+    fn unpredictable_cpu(policy: UnpredictablePolicy) -> ARMv4T<SparseMemory> {
+        let mut cpu = cpu(&[
+            0xE3A00002, // mov r0, #2
+            0xE3A01003, // mov r1, #3
+            0xE0000190, // mul r0, r0, r1
+            0xEAFFFFFE, // b .
+        ]);
+        cpu.unpredictable_policy = policy;
+        cpu
+    }
+
+    #[test]
+    fn emulate_and_warn_policies_execute_the_instruction() {
+        for policy in [UnpredictablePolicy::Emulate, UnpredictablePolicy::Warn] {
+            let mut cpu = unpredictable_cpu(policy);
+            run_to(&mut cpu, 0xC);
+            assert_eq!(cpu.get_gpr(0), 6);
+        }
+    }
+
+    #[test]
+    fn error_policy_stops_before_the_instruction() {
+        let mut cpu = unpredictable_cpu(UnpredictablePolicy::Error);
+        run_to(&mut cpu, 8);
+        let error = cpu.step().unwrap_err();
+        assert_eq!(error, EmulatorError::Unpredictable { pc: 8, raw_inst: 0xE0000190, rule: UnpredictableRule::MultiplyRdIsRm });
+        assert_eq!(cpu.get_gpr(0), 2);
+        assert_eq!(cpu.get_gpr(15), 0x10);
+    }
+
+    fn instruction_cycles(cpu: &ARMv4T<SparseMemory>, raw_inst: Word) -> Cycles {
+        cpu.instruction_cycles(&cpu.decode(raw_inst).inst, true)
+    }
+
+    #[test]
+    fn instruction_timing_table() {
+        let mut cpu = cpu(&[]);
+        // mov r0, #1
+        assert_eq!(instruction_cycles(&cpu, 0xE3A00001), Cycles::new(0, 1, 0, 0));
+        // add r0, r0, r1, lsl r2
+        assert_eq!(instruction_cycles(&cpu, 0xE0800211), Cycles::new(0, 1, 1, 0));
+        // mov pc, lr
+        assert_eq!(instruction_cycles(&cpu, 0xE1A0F00E), Cycles::new(1, 2, 0, 0));
+        // b .+8
+        assert_eq!(instruction_cycles(&cpu, 0xEA000000), Cycles::new(1, 2, 0, 0));
+        // ldr r0, [r1]
+        assert_eq!(instruction_cycles(&cpu, 0xE5910000), Cycles::new(1, 1, 1, 0));
+        // ldr pc, [r1]
+        assert_eq!(instruction_cycles(&cpu, 0xE591F000), Cycles::new(2, 2, 1, 0));
+        // str r0, [r1]
+        assert_eq!(instruction_cycles(&cpu, 0xE5810000), Cycles::new(2, 0, 0, 0));
+        // ldmia r1, {r0-r2}
+        assert_eq!(instruction_cycles(&cpu, 0xE8910007), Cycles::new(1, 3, 1, 0));
+        // stmia r1, {r0-r2}
+        assert_eq!(instruction_cycles(&cpu, 0xE8810007), Cycles::new(2, 2, 0, 0));
+        // swp r0, r2, [r1]
+        assert_eq!(instruction_cycles(&cpu, 0xE1010092), Cycles::new(2, 1, 1, 0));
+        // swi 0
+        assert_eq!(instruction_cycles(&cpu, 0xEF000000), Cycles::new(1, 2, 0, 0));
+        // moveq r0, #1 with the condition failing
+        assert_eq!(cpu.instruction_cycles(&cpu.decode(0x03A00001).inst, false), Cycles::new(0, 1, 0, 0));
+
+        // the multiplier terminates early on the significant bytes of Rs
+        // mul r0, r1, r2
+        assert_eq!(instruction_cycles(&cpu, 0xE0000291), Cycles::new(0, 1, 1, 0));
+        // mla r0, r1, r2, r3
+        assert_eq!(instruction_cycles(&cpu, 0xE0203291), Cycles::new(0, 1, 2, 0));
+        // umull r0, r1, r2, r3
+        assert_eq!(instruction_cycles(&cpu, 0xE0810392), Cycles::new(0, 1, 2, 0));
+        cpu.set_gpr(2, 0x00012345);
+        assert_eq!(instruction_cycles(&cpu, 0xE0000291), Cycles::new(0, 1, 3, 0));
+        cpu.set_gpr(2, 0xFFFFFF80);
+        assert_eq!(instruction_cycles(&cpu, 0xE0000291), Cycles::new(0, 1, 1, 0));
+        cpu.set_gpr(3, 0xFFFFFF80);
+        assert_eq!(instruction_cycles(&cpu, 0xE0810392), Cycles::new(0, 1, 5, 0));
+    }
+
+    #[test]
+    fn step_accumulates_cycles() {
+        let mut cpu = cpu(&[
+            0xE3A00001, // mov r0, #1
+            0xE3A01C01, // mov r1, #0x100
+            0xE5810000, // str r0, [r1]
+            0xEAFFFFFE, // b .
+        ]);
+        run_to(&mut cpu, 8);
+        let before = cpu.cycles;
+        let cycles = cpu.step().unwrap();
+        assert_eq!((cycles.n, cycles.s, cycles.i, cycles.c), (2, 0, 0, 0));
+        assert_eq!(cpu.cycles.total(), before.total() + 2);
+    }
+
+    /// Memory with an IRQ line the test drives.
+    struct IrqBus {
+        memory: SparseMemory,
+        irq: bool,
+    }
+
+    impl Bus for IrqBus {
+        fn access(&mut self, addr: Word, data: &mut Word, rw: BusRW, cycle: BusCycle) -> BusState {
+            self.memory.access(addr, data, rw, cycle)
+        }
+
+        fn interrupt_lines(&mut self) -> InterruptLines {
+            InterruptLines { irq: self.irq, fiq: false }
+        }
+    }
+
+    /// Enables IRQs, then runs `program` from 0x4 onwards with an IRQ
+    /// handler that sets r2 to 5.
+    fn irq_cpu(program: &[Word]) -> ARMv4T<IrqBus> {
+        let mut memory = memory(&[
+            0xE321F013, // msr cpsr_c, #0x13
+        ]);
+        for (i, inst) in program.iter().enumerate() {
+            memory.load(4 + i as Word * 4, &inst.to_le_bytes());
+        }
+        memory.load(0x18, &0xE3A02005u32.to_le_bytes()); // mov r2, #5
+        memory.load(0x1C, &0xE25EF004u32.to_le_bytes()); // subs pc, lr, #4
+        let mut cpu = ARMv4T::new(IrqBus { memory, irq: false });
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn irq_entry_and_return() {
+        let mut cpu = irq_cpu(&[
+            0xEAFFFFFE, // b .
+        ]);
+        run_to(&mut cpu, 4);
+        cpu.bus.irq = true;
+        cpu.step().unwrap();
+        assert!(cpu.mode == ProcessorMode::IRQ(0));
+        assert_eq!(cpu.get_gpr(14), 8);
+        assert_eq!((cpu.cpsr.i, cpu.cpsr.f), (1, 0));
+        assert_eq!(cpu.get_spsr() & 0xFF, 0x13);
+        assert_eq!(cpu.next_instruction_address(), 0x18);
+
+        cpu.bus.irq = false;
+        run_to(&mut cpu, 4);
+        assert!(cpu.mode == ProcessorMode::Supervisor(0));
+        assert_eq!((cpu.cpsr.i, cpu.cpsr.f), (0, 0));
+        assert_eq!(cpu.get_gpr(2), 5);
+    }
+
+    #[test]
+    fn fiq_entry_masks_both_interrupts() {
+        let mut cpu = cpu(&[]);
+        cpu.cpsr.i = 0;
+        cpu.cpsr.f = 0;
+        cpu.enter_exception(Exception::Fiq, 0x104);
+        assert!(cpu.mode == ProcessorMode::FIQ(0));
+        assert_eq!(cpu.get_gpr(14), 0x104);
+        assert_eq!((cpu.cpsr.i, cpu.cpsr.f), (1, 1));
+        assert_eq!(cpu.next_instruction_address(), 0x1C);
+    }
+
+    #[test]
+    fn wfi_halts_until_an_interrupt() {
+        let mut cpu = irq_cpu(&[
+            0xEE070F90, // mcr p15, 0, r0, c7, c0, 4
+            0xE3A01001, // mov r1, #1
+            0xEAFFFFFE, // b .
+        ]);
+        run_to(&mut cpu, 8);
+        assert!(cpu.halted);
+        let cycles = cpu.step().unwrap();
+        assert_eq!(cycles, Cycles::new(0, 0, 1, 0));
+        let start = cpu.cycles.total();
+        assert_eq!(cpu.run(100).unwrap(), 100);
+        assert_eq!(cpu.cycles.total(), start + 100);
+        assert!(cpu.halted);
+        assert_eq!(cpu.next_instruction_address(), 8);
+
+        cpu.bus.irq = true;
+        cpu.step().unwrap();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.get_gpr(14), 0xC);
+        cpu.bus.irq = false;
+        run_to(&mut cpu, 0xC);
+        assert_eq!((cpu.get_gpr(1), cpu.get_gpr(2)), (1, 5));
+    }
+
+    #[test]
+    fn halfword_signed_and_swap_transfers() {
+        let mut cpu = cpu(&[
+            0xE3A01C01, // mov r1, #0x100
+            0xE3A000FF, // mov r0, #0xff
+            0xE1C100B2, // strh r0, [r1, #2]
+            0xE1D120F2, // ldrsh r2, [r1, #2]
+            0xE1D130D2, // ldrsb r3, [r1, #2]
+            0xE3A04055, // mov r4, #0x55
+            0xE1015094, // swp r5, r4, [r1]
+            0xE5D16002, // ldrb r6, [r1, #2]
+            0xEAFFFFFE, // b .
+        ]);
+        run_to(&mut cpu, 0x20);
+        assert_eq!(cpu.get_gpr(2), 0xFF);
+        assert_eq!(cpu.get_gpr(3), 0xFFFFFFFF);
+        assert_eq!(cpu.get_gpr(5), 0x00FF0000);
+        assert_eq!(cpu.get_gpr(6), 0);
+        assert_eq!((cpu.bus.read_byte(0x100), cpu.bus.read_byte(0x102)), (0x55, 0));
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_access() {
+        let mut cpu = cpu(&[
+            0xE3A01C01, // mov r1, #0x100
+            0xE3A00041, // mov r0, #0x41
+            0xE5C10001, // strb r0, [r1, #1]
+            0xE5912000, // ldr r2, [r1]
+            0xEAFFFFFE, // b .
+        ]);
+        cpu.bus.load(0x100, &[1, 2, 3, 4]);
+        cpu.watchpoints.push(Watchpoint::new(0x101, 0x101, WatchKind::Write));
+        cpu.watchpoints.push(Watchpoint::new(0x100, 0x100, WatchKind::Read).with_value(0x04034101));
+
+        cpu.run(1000).unwrap();
+        let hit = cpu.watchpoint_hit.take().unwrap();
+        assert_eq!((hit.pc, hit.addr, hit.old, hit.new), (8, 0x101, Some(2), 0x41));
+        assert_eq!(cpu.next_instruction_address(), 0xC);
+        cpu.run(1000).unwrap();
+        let hit = cpu.watchpoint_hit.take().unwrap();
+        assert_eq!((hit.pc, hit.rw, hit.new), (0xC, BusRW::Read, 0x04034101));
+    }
+
+    /// Overwrites the instruction at 0x8, already prefetched, with `mov r1, #7`.
+    fn self_modifying_cpu(policy: PrefetchPolicy) -> ARMv4T<SparseMemory> {
+        let mut cpu = cpu(&[
+            0xE59F0010, // ldr r0, [pc, #16]
+            0xE50F0004, // str r0, [pc, #-4]
+            0xE3A01001, // mov r1, #1
+            0xEAFFFFFE, // b .
+            0,
+            0,
+            0xE3A01007, // mov r1, #7
+        ]);
+        cpu.prefetch_policy = policy;
+        cpu
+    }
+
+    #[test]
+    fn stale_prefetch_runs_the_old_instruction() {
+        let mut cpu = self_modifying_cpu(PrefetchPolicy::Stale);
+        cpu.run(30).unwrap();
+        assert_eq!(cpu.get_gpr(1), 1);
+    }
+
+    #[test]
+    fn coherent_prefetch_runs_the_new_instruction() {
+        let mut cpu = self_modifying_cpu(PrefetchPolicy::Coherent);
+        cpu.run(30).unwrap();
+        assert_eq!(cpu.get_gpr(1), 7);
+    }
+
+    #[test]
+    fn invalidate_prefetch_refetches_in_place() {
+        let mut cpu = self_modifying_cpu(PrefetchPolicy::Stale);
+        while cpu.step_address != 4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.next_instruction_address(), 8);
+        cpu.invalidate_prefetch();
+        assert_eq!(cpu.next_instruction_address(), 8);
+        cpu.run(30).unwrap();
+        assert_eq!(cpu.get_gpr(1), 7);
+    }
+
+    #[test]
+    fn mvn_writes_inverted_operand() {
+        let mut cpu = cpu(&[
+            0xE3E00000, // mvn r0, #0
+            0xE3F010FF, // mvns r1, #0xff
+            0xEAFFFFFE, // b .
+        ]);
+        run_to(&mut cpu, 8);
+        assert_eq!(cpu.get_gpr(0), 0xFFFFFFFF);
+        assert_eq!(cpu.get_gpr(1), 0xFFFFFF00);
+        assert_eq!((cpu.cpsr.n, cpu.cpsr.z), (1, 0));
+    }
+
+    #[test]
+    fn decrementing_block_transfers_ascend_from_the_lowest_address() {
+        let mut cpu = cpu(&[
+            0xE3A0DC01, // mov sp, #0x100
+            0xE3A01011, // mov r1, #0x11
+            0xE3A02022, // mov r2, #0x22
+            0xE92D0006, // stmdb sp!, {r1, r2}
+            0xE3A0BC01, // mov r11, #0x100
+            0xE81B0018, // ldmda r11, {r3, r4}
+            0xEAFFFFFE, // b .
+        ]);
+        cpu.bus.load(0x100, &0x33u32.to_le_bytes());
+        run_to(&mut cpu, 0x18);
+        assert_eq!(cpu.get_gpr(13), 0xF8);
+        assert_eq!((cpu.bus.read_byte(0xF8), cpu.bus.read_byte(0xFC)), (0x11, 0x22));
+        assert_eq!((cpu.get_gpr(3), cpu.get_gpr(4)), (0x22, 0x33));
+    }
+
+    #[test]
+    fn swi_handlers_run_on_the_host() {
+        let mut cpu = cpu(&[
+            0xEA000006, // b 0x20
+            0,
+            0xEAFFFFFE, // swi: b .
+            0,
+            0,
+            0,
+            0,
+            0,
+            0xE3A00064, // mov r0, #100
+            0xE3A01007, // mov r1, #7
+            0xEF000006, // swi 6
+            0xE1A02000, // mov r2, r0
+            0xEF000007, // swi 7
+            0xE3A04001, // mov r4, #1
+        ]);
+        cpu.bus.load(0x100, &0xEF000008u32.to_le_bytes());
+        // divide r0 by r1, set C and switch to System mode
+        cpu.add_swi_handler(6..=6, Box::new(|call: &mut SwiCall| {
+            let (numerator, denominator) = (call.regs[0], call.regs[1]);
+            call.regs[0] = numerator / denominator;
+            call.regs[1] = numerator % denominator;
+            call.cpsr |= 0x2000_0000 | 0x1F;
+        }));
+        // write through the bus, then return to 0x100 instead of the next instruction
+        cpu.add_swi_handler(7..=7, Box::new(|call: &mut SwiCall| {
+            assert_eq!(call.regs[15], 0x34);
+            let mut data = 0xABCD;
+            call.bus.transfer(&BusRequest::word(0x200, BusRW::Write, BusCycle::NonSequential), &mut data).unwrap();
+            call.regs[15] = 0x100;
+        }));
+        cpu.run(60).unwrap();
+        assert_eq!((cpu.get_gpr(0), cpu.get_gpr(1), cpu.get_gpr(2), cpu.get_gpr(4)), (14, 2, 14, 0));
+        assert_eq!(cpu.cpsr.c, 1);
+        assert_eq!(cpu.bus.peek(0x200, BusWidth::Word), Some(0xABCD));
+        // swi 8 has no handler and takes the exception
+        assert!(cpu.mode == ProcessorMode::Supervisor(0));
+        assert_eq!(cpu.get_gpr(14), 0x104);
+    }
+
+    #[test]
+    fn single_data_transfer_address_wraps() {
+        let mut cpu = cpu(&[
+            0xE3A01000, // mov r1, #0
+            0xE3A02C01, // mov r2, #0x100
+            0xE3A03102, // mov r3, #0x80000000
+            0xE5110004, // ldr r0, [r1, #-4]
+            0xE7925023, // ldr r5, [r2, r3, lsr #32]
+            0xE7524043, // ldrb r4, [r2, -r3, asr #32]
+            0xEAFFFFFE, // b .
+        ]);
+        cpu.bus.load(0xFFFFFFFC, &0xDEADBEEFu32.to_le_bytes());
+        cpu.bus.load(0x100, &0x44332211u32.to_le_bytes());
+        run_to(&mut cpu, 0x18);
+        assert_eq!(cpu.get_gpr(0), 0xDEADBEEF);
+        assert_eq!(cpu.get_gpr(5), 0x44332211);
+        assert_eq!(cpu.get_gpr(4), 0x22);
+    }
+
+    #[test]
+    fn decodes_cp15_cache_operations() {
+        let cpu = cpu(&[]);
+        let operation = |raw_inst: Word, rd: Word| match cpu.decode(raw_inst).inst {
+            InstKind::CoProcessorRegisterTransfer(inst) => cache_operation(&inst, rd),
+            _ => None,
+        };
+        // mcr p15, 0, r0, c7, c10, 2
+        assert_eq!(operation(0xEE070F5A, 0x0400_0020), Some(CacheOperation::CleanDataIndex(0x0400_0020)));
+        // mcr p15, 0, r0, c7, c14, 2
+        assert_eq!(operation(0xEE070F5E, 0x20), Some(CacheOperation::CleanInvalidateDataIndex(0x20)));
+        // mcr p15, 0, r0, c7, c13, 1
+        assert_eq!(operation(0xEE070F3D, 0), None);
+    }
+
+    #[test]
+    fn unknown_cp15_c7_writes_are_ignored() {
+        let mut cpu = cpu(&[
+            0xEE070F5A, // mcr p15, 0, r0, c7, c10, 2
+            0xEE070F3D, // mcr p15, 0, r0, c7, c13, 1
+            0xEAFFFFFE, // b .
+        ]);
+        run_to(&mut cpu, 8);
+    }
+
+    #[test]
+    fn block_data_transfer_address_wraps() {
+        let mut cpu = cpu(&[
+            0xE3A01000, // mov r1, #0
+            0xE9110004, // ldmdb r1, {r2}
+            0xE3E04007, // mvn r4, #7
+            0xE9940060, // ldmib r4, {r5, r6}
+            0xEAFFFFFE, // b .
+        ]);
+        cpu.bus.load(0xFFFFFFFC, &0xDEADBEEFu32.to_le_bytes());
+        run_to(&mut cpu, 0x10);
+        assert_eq!(cpu.get_gpr(2), 0xDEADBEEF);
+        assert_eq!((cpu.get_gpr(5), cpu.get_gpr(6)), (0xDEADBEEF, 0xE3A01000));
+    }
+}
@@ -0,0 +1,355 @@
+use crate::armv4t::*;
+use crate::device::*;
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const BLOCK_SECTOR: Word = 0x00;
+const BLOCK_COUNT: Word = 0x04;
+const BLOCK_COMMAND: Word = 0x08;
+const BLOCK_STATUS: Word = 0x0C;
+const BLOCK_DATA: Word = 0x10;
+const BLOCK_INT_MASK: Word = 0x14;
+const BLOCK_INT_CLEAR: Word = 0x18;
+const BLOCK_CAPACITY: Word = 0x1C;
+
+const CMD_READ: Word = 1;
+const CMD_WRITE: Word = 2;
+const CMD_FLUSH: Word = 3;
+
+const STATUS_BUSY: Word = 0x01;
+/// The data port has a word to read, or room for one to write.
+const STATUS_DATA_REQUEST: Word = 0x02;
+const STATUS_ERROR: Word = 0x04;
+const STATUS_DONE: Word = 0x08;
+const STATUS_READ_ONLY: Word = 0x10;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BlockMode {
+    ReadWrite,
+    /// Write commands fail with an error.
+    ReadOnly,
+    /// Writes are kept in memory and the image is never modified.
+    CopyOnWrite,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BlockTransfer {
+    Idle,
+    Read,
+    Write,
+}
+
+/// A disk backed by a host image file, one 512-byte sector at a time:
+///
+/// | offset | access | function                                                  |
+/// |--------|--------|-----------------------------------------------------------|
+/// | 0x00   | RW     | first sector of the transfer; advances as it proceeds     |
+/// | 0x04   | RW     | number of sectors                                         |
+/// | 0x08   | W      | command: 1 read, 2 write, 3 flush to the image            |
+/// | 0x0C   | R      | status: busy, data request, error, done, read-only        |
+/// | 0x10   | RW     | data port, one little-endian word of the sector at a time |
+/// | 0x14   | RW     | interrupt mask for the done and error bits                |
+/// | 0x18   | W      | writing 1s clears the matching done and error bits        |
+/// | 0x1C   | R      | capacity in sectors                                       |
+///
+/// Moving each sector between the buffer and the medium takes `cycles_per_sector`.
+/// DMA request line 0 follows the data request bit, so a DMA controller can
+/// move the data instead of the CPU.
+pub struct BlockDevice {
+    pub file: std::fs::File,
+    pub mode: BlockMode,
+    pub sectors: u64,
+    /// Sectors written in copy-on-write mode.
+    pub overlay: HashMap<u64, Vec<u8>>,
+    pub sector: Word,
+    pub count: Word,
+    pub status: Word,
+    pub int_mask: Word,
+    pub transfer: BlockTransfer,
+    /// Sectors of the transfer not yet moved through the buffer.
+    pub remaining: Word,
+    pub buffer: Vec<u8>,
+    /// Next byte of `buffer` the data port reads or writes.
+    pub position: usize,
+    /// Cycles until the current sector has moved between buffer and medium.
+    pub busy_cycles: u64,
+    pub cycles_per_sector: u64,
+}
+
+impl BlockDevice {
+    pub fn open(path: &str, mode: BlockMode) -> std::io::Result<BlockDevice> {
+        let file = std::fs::OpenOptions::new().read(true).write(mode == BlockMode::ReadWrite).open(path)?;
+        let length = file.metadata()?.len();
+        Ok(BlockDevice {
+            file,
+            mode,
+            sectors: length.div_ceil(SECTOR_SIZE as u64),
+            overlay: HashMap::new(),
+            sector: 0,
+            count: 0,
+            status: 0,
+            int_mask: 0,
+            transfer: BlockTransfer::Idle,
+            remaining: 0,
+            buffer: vec![0; SECTOR_SIZE],
+            position: SECTOR_SIZE,
+            busy_cycles: 0,
+            cycles_per_sector: 0,
+        })
+    }
+
+    fn is_data_request(&self) -> bool {
+        self.transfer != BlockTransfer::Idle && self.busy_cycles == 0 && self.position < SECTOR_SIZE
+    }
+
+    fn read_sector(&mut self, sector: u64) -> std::io::Result<()> {
+        if let Some(data) = self.overlay.get(&sector) {
+            self.buffer.copy_from_slice(data);
+            return Ok(());
+        }
+        // the last sector of an image that is not a whole number of sectors reads as zeros past the end
+        self.buffer.fill(0);
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        let mut filled = 0;
+        while filled < SECTOR_SIZE {
+            match self.file.read(&mut self.buffer[filled..])? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sector(&mut self, sector: u64) -> std::io::Result<()> {
+        if self.mode == BlockMode::CopyOnWrite {
+            self.overlay.insert(sector, self.buffer.clone());
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        self.file.write_all(&self.buffer)
+    }
+
+    fn start(&mut self, command: Word) {
+        self.status &= !(STATUS_DONE | STATUS_ERROR);
+        let is_in_range = self.count > 0 && self.sector as u64 + self.count as u64 <= self.sectors;
+        match command {
+            CMD_READ | CMD_WRITE if !is_in_range => self.finish(false),
+            CMD_WRITE if self.mode == BlockMode::ReadOnly => self.finish(false),
+            CMD_READ => {
+                self.transfer = BlockTransfer::Read;
+                self.remaining = self.count;
+                self.position = SECTOR_SIZE;
+                self.busy_cycles = self.cycles_per_sector;
+            }
+            CMD_WRITE => {
+                self.transfer = BlockTransfer::Write;
+                self.remaining = self.count;
+                self.position = 0;
+            }
+            CMD_FLUSH => {
+                let result = if self.mode == BlockMode::ReadWrite { self.file.sync_data() } else { Ok(()) };
+                self.finish(result.is_ok());
+            }
+            _ => self.finish(false),
+        }
+        self.advance();
+    }
+
+    fn finish(&mut self, is_ok: bool) {
+        self.transfer = BlockTransfer::Idle;
+        self.busy_cycles = 0;
+        self.status |= if is_ok { STATUS_DONE } else { STATUS_ERROR };
+    }
+
+    /// Moves the current sector once its time on the medium has passed.
+    fn advance(&mut self) {
+        if self.busy_cycles > 0 {
+            return;
+        }
+        match self.transfer {
+            BlockTransfer::Read if self.position == SECTOR_SIZE => {
+                if self.remaining == 0 {
+                    self.finish(true);
+                    return;
+                }
+                if self.read_sector(self.sector as u64).is_err() {
+                    self.finish(false);
+                    return;
+                }
+                self.position = 0;
+                self.sector += 1;
+                self.remaining -= 1;
+            }
+            BlockTransfer::Write if self.position == SECTOR_SIZE => {
+                if self.write_sector(self.sector as u64).is_err() {
+                    self.finish(false);
+                    return;
+                }
+                self.sector += 1;
+                self.remaining -= 1;
+                self.position = 0;
+                if self.remaining == 0 {
+                    self.finish(true);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn read_data(&mut self) -> Word {
+        if self.transfer != BlockTransfer::Read || !self.is_data_request() {
+            return 0;
+        }
+        let value = u32::from_le_bytes(self.buffer[self.position..self.position + 4].try_into().unwrap());
+        self.position += 4;
+        if self.position == SECTOR_SIZE {
+            self.busy_cycles = if self.remaining > 0 { self.cycles_per_sector } else { 0 };
+            self.advance();
+        }
+        value
+    }
+
+    fn write_data(&mut self, value: Word) {
+        if self.transfer != BlockTransfer::Write || !self.is_data_request() {
+            return;
+        }
+        self.buffer[self.position..self.position + 4].copy_from_slice(&value.to_le_bytes());
+        self.position += 4;
+        if self.position == SECTOR_SIZE {
+            self.busy_cycles = self.cycles_per_sector;
+            self.advance();
+        }
+    }
+}
+
+impl Device for BlockDevice {
+    fn read(&mut self, offset: Word, width: BusWidth) -> Result<Word, BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        let value = match offset {
+            BLOCK_SECTOR => self.sector,
+            BLOCK_COUNT => self.count,
+            BLOCK_STATUS => {
+                let busy = if self.transfer != BlockTransfer::Idle { STATUS_BUSY } else { 0 };
+                let request = if self.is_data_request() { STATUS_DATA_REQUEST } else { 0 };
+                let read_only = if self.mode == BlockMode::ReadOnly { STATUS_READ_ONLY } else { 0 };
+                self.status | busy | request | read_only
+            }
+            BLOCK_DATA => self.read_data(),
+            BLOCK_INT_MASK => self.int_mask,
+            BLOCK_CAPACITY => self.sectors as Word,
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: Word, value: Word, width: BusWidth) -> Result<(), BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        match offset {
+            BLOCK_SECTOR if self.transfer == BlockTransfer::Idle => self.sector = value,
+            BLOCK_COUNT if self.transfer == BlockTransfer::Idle => self.count = value,
+            BLOCK_COMMAND if self.transfer == BlockTransfer::Idle => self.start(value),
+            BLOCK_DATA => self.write_data(value),
+            BLOCK_INT_MASK => self.int_mask = value & (STATUS_DONE | STATUS_ERROR),
+            BLOCK_INT_CLEAR => self.status &= !value,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if self.busy_cycles > 0 {
+            self.busy_cycles = self.busy_cycles.saturating_sub(cycles);
+            self.advance();
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        (self.busy_cycles > 0).then_some(self.busy_cycles)
+    }
+
+    fn interrupt(&self) -> bool {
+        self.status & self.int_mask != 0
+    }
+
+    fn dma_requests(&self) -> Word {
+        self.is_data_request() as Word
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORD: BusWidth = BusWidth::Word;
+
+    fn start(device: &mut BlockDevice, sector: Word, count: Word, command: Word) {
+        device.write(BLOCK_SECTOR, sector, WORD).unwrap();
+        device.write(BLOCK_COUNT, count, WORD).unwrap();
+        device.write(BLOCK_COMMAND, command, WORD).unwrap();
+    }
+
+    fn status(device: &mut BlockDevice) -> Word {
+        device.read(BLOCK_STATUS, WORD).unwrap()
+    }
+
+    #[test]
+    fn reads_and_writes_sectors_in_each_mode() {
+        let path = std::env::temp_dir().join("armv4t_block_test.img");
+        let path = path.to_str().unwrap();
+        // two sectors and a partial third
+        let mut image = vec![0u8; 1024 + 100];
+        image[512] = 0xAB;
+        image[1024] = 0xCD;
+        std::fs::write(path, &image).unwrap();
+
+        for mode in [BlockMode::CopyOnWrite, BlockMode::ReadOnly, BlockMode::ReadWrite] {
+            let mut device = BlockDevice::open(path, mode).unwrap();
+            device.cycles_per_sector = 10;
+            assert_eq!(device.read(BLOCK_CAPACITY, WORD).unwrap(), 3);
+            device.write(BLOCK_INT_MASK, STATUS_ERROR | STATUS_DONE, WORD).unwrap();
+
+            start(&mut device, 1, 2, CMD_READ);
+            assert_eq!(status(&mut device) & (STATUS_BUSY | STATUS_DATA_REQUEST), STATUS_BUSY);
+            device.tick(10);
+            assert_eq!(status(&mut device) & (STATUS_BUSY | STATUS_DATA_REQUEST), STATUS_BUSY | STATUS_DATA_REQUEST);
+            assert_eq!(device.read(BLOCK_DATA, WORD).unwrap(), 0xAB);
+            for _ in 1..128 {
+                device.read(BLOCK_DATA, WORD).unwrap();
+            }
+            assert_eq!(device.next_event(), Some(10));
+            device.tick(10);
+            assert_eq!(device.read(BLOCK_DATA, WORD).unwrap(), 0xCD);
+            for _ in 1..128 {
+                device.read(BLOCK_DATA, WORD).unwrap();
+            }
+            assert_eq!(status(&mut device) & (STATUS_BUSY | STATUS_DATA_REQUEST | STATUS_DONE), STATUS_DONE);
+            assert!(device.interrupt());
+            device.write(BLOCK_INT_CLEAR, STATUS_DONE, WORD).unwrap();
+
+            start(&mut device, 0, 1, CMD_WRITE);
+            if mode == BlockMode::ReadOnly {
+                assert_eq!(status(&mut device) & STATUS_ERROR, STATUS_ERROR);
+                continue;
+            }
+            for i in 0..128 {
+                device.write(BLOCK_DATA, i, WORD).unwrap();
+            }
+            device.tick(10);
+            assert_eq!(status(&mut device) & 0xF, STATUS_DONE);
+            start(&mut device, 0, 1, CMD_READ);
+            device.tick(10);
+            assert_eq!(device.read(BLOCK_DATA, WORD).unwrap(), 0);
+            assert_eq!(device.read(BLOCK_DATA, WORD).unwrap(), 1);
+            // only read-write mode reaches the image file
+            let disk = std::fs::read(path).unwrap();
+            assert_eq!(disk[4], if mode == BlockMode::ReadWrite { 1 } else { 0 });
+        }
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,555 @@
+use crate::armv4t::*;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum WritePolicy {
+    /// Writes stay in the cache until the line is cleaned or evicted.
+    WriteBack,
+    /// Writes update the cache and memory together.
+    WriteThrough,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Replacement {
+    RoundRobin,
+    /// Pseudo-random, from a fixed seed so that runs repeat.
+    Random,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct CacheConfig {
+    /// Total size in bytes.
+    pub size: usize,
+    pub line_size: usize,
+    pub ways: usize,
+    pub write_policy: WritePolicy,
+    pub replacement: Replacement,
+}
+
+impl CacheConfig {
+    pub fn new(size: usize, line_size: usize, ways: usize) -> CacheConfig {
+        CacheConfig {
+            size,
+            line_size,
+            ways,
+            write_policy: WritePolicy::WriteBack,
+            replacement: Replacement::RoundRobin,
+        }
+    }
+
+    /// ARM920T-like: 16 KiB, 32-byte lines, 64 ways.
+    pub fn arm920t() -> CacheConfig {
+        CacheConfig::new(0x4000, 32, 64)
+    }
+
+    pub fn write_policy(mut self, write_policy: WritePolicy) -> CacheConfig {
+        self.write_policy = write_policy;
+        self
+    }
+
+    pub fn replacement(mut self, replacement: Replacement) -> CacheConfig {
+        self.replacement = replacement;
+        self
+    }
+
+    pub fn sets(&self) -> usize {
+        self.size / (self.line_size * self.ways)
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct CacheStats {
+    pub read_hits: u64,
+    pub read_misses: u64,
+    pub write_hits: u64,
+    pub write_misses: u64,
+    /// Dirty lines written to memory, on eviction or clean.
+    pub write_backs: u64,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let hits = self.read_hits + self.write_hits;
+        let accesses = hits + self.read_misses + self.write_misses;
+        if accesses == 0 { 0.0 } else { hits as f64 / accesses as f64 }
+    }
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} read hits, {} read misses, {} write hits, {} write misses, {} write-backs ({:.1}% hits)",
+            self.read_hits,
+            self.read_misses,
+            self.write_hits,
+            self.write_misses,
+            self.write_backs,
+            self.hit_rate() * 100.0
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CacheLine {
+    pub valid: bool,
+    pub dirty: bool,
+    /// Address of the first byte of the line.
+    pub addr: Word,
+    pub data: Vec<u8>,
+}
+
+/// A set-associative cache that allocates lines on read misses only; write
+/// misses go straight to memory.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cache {
+    pub config: CacheConfig,
+    /// `sets * ways` lines, one set after the other.
+    pub lines: Vec<CacheLine>,
+    /// Ways below this index are locked down: they keep their contents and are never replaced.
+    pub locked_ways: usize,
+    /// Next way each set replaces under round-robin.
+    pub victims: Vec<usize>,
+    pub random_state: u32,
+    pub stats: CacheStats,
+}
+
+impl Cache {
+    /// Panics unless the line size is a power of two, at least a word, and
+    /// the size divides into a power-of-two number of sets.
+    pub fn new(config: CacheConfig) -> Cache {
+        assert!(config.line_size >= 4 && config.line_size.is_power_of_two(), "cache line size must be a power of two");
+        assert!(config.ways > 0 && config.sets() > 0 && config.sets().is_power_of_two(), "cache sets must be a power of two");
+        let line = CacheLine { valid: false, dirty: false, addr: 0, data: vec![0; config.line_size] };
+        Cache {
+            config,
+            lines: vec![line; config.sets() * config.ways],
+            locked_ways: 0,
+            victims: vec![0; config.sets()],
+            random_state: 0x2545F491,
+            stats: CacheStats::default(),
+        }
+    }
+
+    fn line_address(&self, addr: Word) -> Word {
+        addr & !(self.config.line_size as Word - 1)
+    }
+
+    fn set_index(&self, addr: Word) -> usize {
+        (addr as usize / self.config.line_size) % self.config.sets()
+    }
+
+    /// Index into `lines` of the valid line holding `addr`.
+    pub fn find(&self, addr: Word) -> Option<usize> {
+        let line_address = self.line_address(addr);
+        let first = self.set_index(addr) * self.config.ways;
+        (first..first + self.config.ways).find(|&index| self.lines[index].valid && self.lines[index].addr == line_address)
+    }
+
+    /// Index into `lines` of the set/way `index` used by the CP15 index
+    /// operations: the set sits above the line offset and the way in the top bits.
+    fn set_way(&self, index: Word) -> usize {
+        let set = (index as usize / self.config.line_size) % self.config.sets();
+        let way_bits = self.config.ways.next_power_of_two().trailing_zeros();
+        let way = if way_bits == 0 { 0 } else { (index >> (32 - way_bits)) as usize };
+        set * self.config.ways + way.min(self.config.ways - 1)
+    }
+
+    /// Locks the first `ways` ways of every set; what they hold now stays cached.
+    pub fn lockdown(&mut self, ways: usize) {
+        self.locked_ways = ways.min(self.config.ways - 1);
+    }
+
+    fn choose_victim(&mut self, set: usize) -> usize {
+        let first = set * self.config.ways;
+        let unlocked = self.locked_ways..self.config.ways;
+        if let Some(way) = unlocked.clone().find(|&way| !self.lines[first + way].valid) {
+            return first + way;
+        }
+        let way = match self.config.replacement {
+            Replacement::RoundRobin => {
+                let way = self.victims[set].max(self.locked_ways);
+                self.victims[set] = if way + 1 < self.config.ways { way + 1 } else { self.locked_ways };
+                way
+            }
+            Replacement::Random => {
+                // xorshift32
+                self.random_state ^= self.random_state << 13;
+                self.random_state ^= self.random_state >> 17;
+                self.random_state ^= self.random_state << 5;
+                unlocked.start + self.random_state as usize % unlocked.len()
+            }
+        };
+        first + way
+    }
+
+    /// Moves a whole line between the cache and memory, one word per bus cycle.
+    /// The wait states include the cycles after the first, which the core
+    /// spends waiting for the rest of the line.
+    fn transfer_line<B: Bus>(bus: &mut B, line: &mut CacheLine, rw: BusRW, request: &BusRequest) -> BusState {
+        let mut wait_states = 0;
+        for (i, word) in line.data.chunks_mut(4).enumerate() {
+            let word_request = BusRequest {
+                addr: line.addr + (i * 4) as Word,
+                rw,
+                width: BusWidth::Word,
+                cycle: if i == 0 { BusCycle::NonSequential } else { BusCycle::Sequential },
+                ..*request
+            };
+            let mut data = Word::from_le_bytes((&*word).try_into().unwrap());
+            wait_states += bus.transfer(&word_request, &mut data)? + (i > 0) as Word;
+            word.copy_from_slice(&data.to_le_bytes());
+        }
+        Ok(wait_states)
+    }
+
+    fn write_back<B: Bus>(&mut self, bus: &mut B, index: usize, request: &BusRequest) -> BusState {
+        if !(self.lines[index].valid && self.lines[index].dirty) {
+            return Ok(0);
+        }
+        let wait_states = Cache::transfer_line(bus, &mut self.lines[index], BusRW::Write, request)?;
+        self.lines[index].dirty = false;
+        self.stats.write_backs += 1;
+        Ok(wait_states)
+    }
+
+    pub fn access<B: Bus>(&mut self, bus: &mut B, request: &BusRequest, data: &mut Word) -> BusState {
+        let bytes = request.width.bytes() as usize;
+        let offset = request.addr as usize & (self.config.line_size - 1);
+        match (self.find(request.addr), request.rw) {
+            (Some(index), BusRW::Read) => {
+                self.stats.read_hits += 1;
+                *data = read_bytes(&self.lines[index].data[offset..offset + bytes]);
+                Ok(0)
+            }
+            (Some(index), BusRW::Write) => {
+                self.stats.write_hits += 1;
+                write_bytes(&mut self.lines[index].data[offset..offset + bytes], *data);
+                match self.config.write_policy {
+                    WritePolicy::WriteBack => {
+                        self.lines[index].dirty = true;
+                        Ok(0)
+                    }
+                    WritePolicy::WriteThrough => bus.transfer(request, data),
+                }
+            }
+            (None, BusRW::Read) => {
+                self.stats.read_misses += 1;
+                let index = self.choose_victim(self.set_index(request.addr));
+                let mut wait_states = self.write_back(bus, index, request)?;
+                let line = &mut self.lines[index];
+                line.valid = false;
+                line.dirty = false;
+                line.addr = request.addr & !(self.config.line_size as Word - 1);
+                wait_states += Cache::transfer_line(bus, line, BusRW::Read, request)?;
+                line.valid = true;
+                *data = read_bytes(&line.data[offset..offset + bytes]);
+                Ok(wait_states)
+            }
+            (None, BusRW::Write) => {
+                self.stats.write_misses += 1;
+                bus.transfer(request, data)
+            }
+        }
+    }
+
+    /// Writes the line holding `addr` to memory if it is dirty.
+    pub fn clean<B: Bus>(&mut self, bus: &mut B, addr: Word) -> BusState {
+        match self.find(addr) {
+            Some(index) => self.write_back(bus, index, &BusRequest::word(addr, BusRW::Write, BusCycle::NonSequential)),
+            None => Ok(0),
+        }
+    }
+
+    /// Drops the line holding `addr` without writing it back, even if it is locked down.
+    pub fn invalidate(&mut self, addr: Word) {
+        if let Some(index) = self.find(addr) {
+            self.lines[index].valid = false;
+            self.lines[index].dirty = false;
+        }
+    }
+
+    /// Writes the line at set/way `index` to memory if it is dirty.
+    pub fn clean_index<B: Bus>(&mut self, bus: &mut B, index: Word) -> BusState {
+        let line = self.set_way(index);
+        let request = BusRequest::word(self.lines[line].addr, BusRW::Write, BusCycle::NonSequential);
+        self.write_back(bus, line, &request)
+    }
+
+    /// Drops the line at set/way `index` without writing it back.
+    pub fn invalidate_index(&mut self, index: Word) {
+        let line = self.set_way(index);
+        self.lines[line].valid = false;
+        self.lines[line].dirty = false;
+    }
+
+    pub fn clean_all<B: Bus>(&mut self, bus: &mut B) -> BusState {
+        let mut wait_states = 0;
+        for index in 0..self.lines.len() {
+            let request = BusRequest::word(self.lines[index].addr, BusRW::Write, BusCycle::NonSequential);
+            wait_states += self.write_back(bus, index, &request)?;
+        }
+        Ok(wait_states)
+    }
+
+    /// Drops every line without writing anything back.
+    pub fn invalidate_all(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.valid = false;
+            line.dirty = false;
+        }
+    }
+
+    /// Addresses of clean lines whose memory has changed behind the cache,
+    /// e.g. by DMA, so that the core would read stale data from them.
+    pub fn stale_lines<B: Bus>(&self, bus: &mut B) -> Vec<Word> {
+        let mut stale = Vec::new();
+        for line in self.lines.iter().filter(|line| line.valid && !line.dirty) {
+            let is_stale = line.data.chunks(4).enumerate().any(|(i, word)| {
+                let addr = line.addr + (i * 4) as Word;
+                bus.peek(addr, BusWidth::Word).is_some_and(|value| value != read_bytes(word))
+            });
+            if is_stale {
+                stale.push(line.addr);
+            }
+        }
+        stale.sort_unstable();
+        stale
+    }
+}
+
+fn read_bytes(bytes: &[u8]) -> Word {
+    bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as Word)
+}
+
+fn write_bytes(bytes: &mut [u8], value: Word) {
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (i * 8)) as u8;
+    }
+}
+
+/// Separate instruction and data caches in front of `bus`. Fetches go
+/// through `icache`, data accesses through `dcache`, and accesses in
+/// `uncached` ranges (inclusive), such as device registers, go straight to the bus.
+///
+/// Nothing keeps the caches coherent with each other or with other bus
+/// masters: code written through the D-cache is not seen by the I-cache, and
+/// DMA into memory held in the D-cache leaves it stale until invalidated,
+/// as on hardware. `CacheOperation`s from CP15 maintain them.
+pub struct CachedBus<T: Bus> {
+    pub bus: T,
+    pub icache: Cache,
+    pub dcache: Cache,
+    pub uncached: Vec<(Word, Word)>,
+}
+
+impl<T: Bus> CachedBus<T> {
+    pub fn new(bus: T, icache: CacheConfig, dcache: CacheConfig) -> CachedBus<T> {
+        CachedBus {
+            bus,
+            icache: Cache::new(icache),
+            dcache: Cache::new(dcache),
+            uncached: Vec::new(),
+        }
+    }
+
+    pub fn uncached(mut self, start: Word, end: Word) -> CachedBus<T> {
+        self.uncached.push((start, end));
+        self
+    }
+
+    fn is_cached(&self, addr: Word) -> bool {
+        !self.uncached.iter().any(|(start, end)| *start <= addr && addr <= *end)
+    }
+
+    /// Addresses of D-cache lines that disagree with memory they have not written.
+    pub fn stale_lines(&mut self) -> Vec<Word> {
+        self.dcache.stale_lines(&mut self.bus)
+    }
+}
+
+impl<T: Bus> Bus for CachedBus<T> {
+    fn access(&mut self, addr: Word, data: &mut Word, rw: BusRW, cycle: BusCycle) -> BusState {
+        self.transfer(&BusRequest::word(addr, rw, cycle), data)
+    }
+
+    fn transfer(&mut self, request: &BusRequest, data: &mut Word) -> BusState {
+        if !self.is_cached(request.addr) {
+            return self.bus.transfer(request, data);
+        }
+        match request.kind {
+            BusKind::Fetch => self.icache.access(&mut self.bus, request, data),
+            BusKind::Data | BusKind::Swap => self.dcache.access(&mut self.bus, request, data),
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.bus.tick(cycles);
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.bus.next_event()
+    }
+
+    fn interrupt_lines(&mut self) -> InterruptLines {
+        self.bus.interrupt_lines()
+    }
+
+    fn stolen_cycles(&mut self) -> u64 {
+        self.bus.stolen_cycles()
+    }
+
+    fn system_request(&mut self) -> Option<SystemRequest> {
+        self.bus.system_request()
+    }
+
+    /// What a data load would see: the D-cache line if there is one, else memory.
+    fn peek(&mut self, addr: Word, width: BusWidth) -> Option<Word> {
+        match self.dcache.find(addr) {
+            Some(index) => {
+                let offset = addr as usize & (self.dcache.config.line_size - 1);
+                Some(read_bytes(&self.dcache.lines[index].data[offset..offset + width.bytes() as usize]))
+            }
+            None => self.bus.peek(addr, width),
+        }
+    }
+
+    fn begin_instruction(&mut self, pc: Word) {
+        self.bus.begin_instruction(pc);
+    }
+
+    fn cache_operation(&mut self, operation: CacheOperation) -> BusState {
+        match operation {
+            CacheOperation::InvalidateAll => {
+                self.icache.invalidate_all();
+                self.dcache.invalidate_all();
+                Ok(0)
+            }
+            CacheOperation::InvalidateInstructions => {
+                self.icache.invalidate_all();
+                Ok(0)
+            }
+            CacheOperation::InvalidateInstructionLine(addr) => {
+                self.icache.invalidate(addr);
+                Ok(0)
+            }
+            CacheOperation::InvalidateData => {
+                self.dcache.invalidate_all();
+                Ok(0)
+            }
+            CacheOperation::InvalidateDataLine(addr) => {
+                self.dcache.invalidate(addr);
+                Ok(0)
+            }
+            CacheOperation::CleanDataLine(addr) => self.dcache.clean(&mut self.bus, addr),
+            CacheOperation::CleanInvalidateDataLine(addr) => {
+                let wait_states = self.dcache.clean(&mut self.bus, addr)?;
+                self.dcache.invalidate(addr);
+                Ok(wait_states)
+            }
+            CacheOperation::CleanDataIndex(index) => self.dcache.clean_index(&mut self.bus, index),
+            CacheOperation::CleanInvalidateDataIndex(index) => {
+                let wait_states = self.dcache.clean_index(&mut self.bus, index)?;
+                self.dcache.invalidate_index(index);
+                Ok(wait_states)
+            }
+            CacheOperation::DrainWriteBuffer => Ok(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_map::*;
+
+    fn cached_ram() -> CachedBus<MemoryMap> {
+        let mut map = MemoryMap::new();
+        map.add_ram("ram", 0, 0x10000);
+        // 8 sets of 2 ways
+        let config = CacheConfig::new(256, 16, 2);
+        CachedBus::new(map, config, config.write_policy(WritePolicy::WriteBack))
+    }
+
+    #[test]
+    fn write_back_data_cache() {
+        let mut bus = cached_ram();
+        bus.bus.load(0x800, &[1, 0, 0, 0]).unwrap();
+        let read = BusRequest::word(0x800, BusRW::Read, BusCycle::NonSequential);
+        let mut data = 0;
+        // a miss fills the line
+        assert_eq!(bus.transfer(&read, &mut data), Ok(3));
+        assert_eq!(bus.transfer(&read, &mut data), Ok(0));
+        bus.bus.load(0x800, &[9, 0, 0, 0]).unwrap();
+        assert_eq!(bus.stale_lines(), vec![0x800]);
+        bus.transfer(&read, &mut data).unwrap();
+        assert_eq!(data, 1);
+
+        let mut data = 5;
+        bus.transfer(&BusRequest { rw: BusRW::Write, ..read }, &mut data).unwrap();
+        assert_eq!(bus.bus.peek(0x800, BusWidth::Word), Some(9));
+        assert_eq!(bus.peek(0x800, BusWidth::Word), Some(5));
+        bus.dcache.clean(&mut bus.bus, 0x800).unwrap();
+        assert_eq!(bus.bus.peek(0x800, BusWidth::Word), Some(5));
+        assert_eq!(bus.dcache.stats.write_backs, 1);
+    }
+
+    #[test]
+    fn lockdown_keeps_lines_from_eviction() {
+        let mut bus = cached_ram();
+        let mut data = 0;
+        for addr in [0x800, 0x1800, 0x2800, 0x3800] {
+            bus.transfer(&BusRequest::word(addr, BusRW::Read, BusCycle::NonSequential), &mut data).unwrap();
+            if addr == 0x800 {
+                bus.dcache.lockdown(1);
+            }
+        }
+        assert!(bus.dcache.find(0x800).is_some());
+        assert!(bus.dcache.find(0x2800).is_none());
+        assert!(bus.dcache.find(0x3800).is_some());
+    }
+
+    #[test]
+    fn index_operations_address_lines_by_set_and_way() {
+        let mut bus = cached_ram();
+        let mut data = 0;
+        for addr in [0x810, 0x1810] {
+            bus.transfer(&BusRequest::word(addr, BusRW::Read, BusCycle::NonSequential), &mut data).unwrap();
+            let mut value = addr;
+            bus.transfer(&BusRequest::word(addr, BusRW::Write, BusCycle::NonSequential), &mut value).unwrap();
+        }
+        // set 1, way 1
+        bus.cache_operation(CacheOperation::CleanDataIndex(0x8000_0010)).unwrap();
+        assert_eq!(bus.bus.peek(0x1810, BusWidth::Word), Some(0x1810));
+        assert_eq!(bus.bus.peek(0x810, BusWidth::Word), Some(0));
+        // set 1, way 0
+        bus.cache_operation(CacheOperation::CleanInvalidateDataIndex(0x10)).unwrap();
+        assert_eq!(bus.bus.peek(0x810, BusWidth::Word), Some(0x810));
+        assert!(bus.dcache.find(0x810).is_none());
+        assert!(bus.dcache.find(0x1810).is_some());
+    }
+
+    #[test]
+    fn guest_invalidates_a_stale_data_line() {
+        let mut bus = cached_ram();
+        let program: [Word; 5] = [
+            0xE3A00B02, // mov r0, #0x800
+            0xE5901000, // ldr r1, [r0]
+            0xEE070F36, // mcr p15, 0, r0, c7, c6, 1
+            0xE5902000, // ldr r2, [r0]
+            0xEAFFFFFE, // b .
+        ];
+        for (i, inst) in program.iter().enumerate() {
+            bus.bus.load(i as Word * 4, &inst.to_le_bytes()).unwrap();
+        }
+        bus.bus.load(0x800, &[1, 0, 0, 0]).unwrap();
+        let mut cpu = ARMv4T::new(bus);
+        cpu.reset();
+        while cpu.step_address != 4 {
+            cpu.step().unwrap();
+        }
+        cpu.bus.bus.load(0x800, &[7, 0, 0, 0]).unwrap();
+        cpu.run(40).unwrap();
+        assert_eq!((cpu.get_gpr(1), cpu.get_gpr(2)), (1, 7));
+        assert!(cpu.bus.icache.stats.read_hits > 0);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::armv4t::*;
+
+/// A memory-mapped peripheral. Offsets are relative to the base address the
+/// device is mapped at, and narrow values sit in the low bits.
+pub trait Device {
+    fn read(&mut self, offset: Word, width: BusWidth) -> Result<Word, BusError>;
+
+    fn write(&mut self, offset: Word, value: Word, width: BusWidth) -> Result<(), BusError>;
+
+    /// Advances the device by `cycles` CPU cycles.
+    fn tick(&mut self, _cycles: u64) {}
+
+    /// Cycles until the device changes state on its own, if it ever does.
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    /// Level of the device's interrupt output.
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    /// Level of a dedicated FIQ output, as found on interrupt controllers.
+    /// It drives nFIQ directly regardless of the device's route.
+    fn fast_interrupt(&self) -> bool {
+        false
+    }
+
+    /// Interrupt controllers receive the levels of their sources here, one bit per source.
+    fn set_interrupt_sources(&mut self, _sources: Word) {}
+
+    /// Levels of the device's DMA request lines, one bit per line.
+    fn dma_requests(&self) -> Word {
+        0
+    }
+
+    /// DMA controllers receive the levels of their request lines here, one bit per line.
+    fn set_dma_requests(&mut self, _requests: Word) {}
+
+    /// Bus masters run here after every tick, with the bus they are mapped on.
+    /// Returns the cycles they held the bus for, which the CPU stalls for.
+    fn master(&mut self, _bus: &mut dyn Bus) -> u64 {
+        0
+    }
+
+    /// Takes a pending request to the host, such as ending the run.
+    fn system_request(&mut self) -> Option<SystemRequest> {
+        None
+    }
+}
+
+/// Where the interrupt output of a mapped device is connected.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum InterruptRoute {
+    None,
+    Irq,
+    Fiq,
+    /// Source `source` of the interrupt controller mapped as region `controller`.
+    Controller { controller: usize, source: u32 },
+}
+
+/// Connects the DMA request lines of a mapped device to lines `line..` of the
+/// DMA controller mapped as region `controller`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct DmaRoute {
+    pub controller: usize,
+    pub line: u32,
+}
+
+pub struct MappedDevice {
+    pub device: Box<dyn Device>,
+    pub route: InterruptRoute,
+    pub dma: Option<DmaRoute>,
+}
@@ -0,0 +1,470 @@
+use crate::armv4t::*;
+use crate::device::*;
+use std::collections::VecDeque;
+
+const DMAC_INT_STATUS: Word = 0x000;
+const DMAC_INT_TC_STATUS: Word = 0x004;
+const DMAC_INT_TC_CLEAR: Word = 0x008;
+const DMAC_INT_ERROR_STATUS: Word = 0x00C;
+const DMAC_INT_ERR_CLR: Word = 0x010;
+const DMAC_RAW_INT_TC_STATUS: Word = 0x014;
+const DMAC_RAW_INT_ERROR_STATUS: Word = 0x018;
+const DMAC_ENBLD_CHNS: Word = 0x01C;
+const DMAC_SOFT_BREQ: Word = 0x020;
+const DMAC_SOFT_SREQ: Word = 0x024;
+const DMAC_SOFT_LBREQ: Word = 0x028;
+const DMAC_SOFT_LSREQ: Word = 0x02C;
+const DMAC_CONFIGURATION: Word = 0x030;
+const DMAC_SYNC: Word = 0x034;
+const DMAC_CHANNEL0: Word = 0x100;
+const DMAC_CHANNEL_STRIDE: Word = 0x20;
+const DMAC_PERIPH_ID0: Word = 0xFE0;
+
+const CHANNEL_SRC_ADDR: Word = 0x00;
+const CHANNEL_DEST_ADDR: Word = 0x04;
+const CHANNEL_LLI: Word = 0x08;
+const CHANNEL_CONTROL: Word = 0x0C;
+const CHANNEL_CONFIGURATION: Word = 0x10;
+
+const CHANNELS: usize = 8;
+/// Each channel buffers up to four words between its source and destination.
+const FIFO_BYTES: usize = 16;
+
+const DMAC_ENABLE: Word = 0x1;
+
+const CONTROL_TRANSFER_SIZE: Word = 0xFFF;
+const CONTROL_SI: Word = 1 << 26;
+const CONTROL_DI: Word = 1 << 27;
+const CONTROL_PROT_PRIVILEGED: Word = 1 << 28;
+const CONTROL_TC_INT: Word = 1 << 31;
+
+const CONFIG_ENABLE: Word = 1 << 0;
+const CONFIG_IE: Word = 1 << 14;
+const CONFIG_ITC: Word = 1 << 15;
+const CONFIG_ACTIVE: Word = 1 << 17;
+const CONFIG_HALT: Word = 1 << 18;
+
+const PERIPH_ID: [Word; 8] = [0x80, 0x10, 0x04, 0x0A, 0x0D, 0xF0, 0x05, 0xB1];
+
+/// One DMA channel and the data it has read but not yet written.
+#[derive(Debug, Default, Clone)]
+pub struct DmaChannel {
+    pub src_addr: Word,
+    pub dest_addr: Word,
+    pub lli: Word,
+    pub control: Word,
+    pub configuration: Word,
+    pub fifo: VecDeque<u8>,
+}
+
+impl DmaChannel {
+    pub fn is_enabled(&self) -> bool {
+        self.configuration & CONFIG_ENABLE != 0
+    }
+
+    /// Transfers left, counted in source-width units.
+    pub fn transfer_size(&self) -> Word {
+        self.control & CONTROL_TRANSFER_SIZE
+    }
+
+    /// Flow control with the peripheral as flow controller is treated as if the DMAC controlled it.
+    fn flow_control(&self) -> Word {
+        match (self.configuration >> 11) & 0x7 {
+            4 | 7 => 3,
+            5 => 1,
+            6 => 2,
+            flow => flow,
+        }
+    }
+
+    /// Request line gating the source side, if the source is a peripheral.
+    fn src_peripheral(&self) -> Option<Word> {
+        matches!(self.flow_control(), 2 | 3).then_some((self.configuration >> 1) & 0xF)
+    }
+
+    /// Request line gating the destination side, if the destination is a peripheral.
+    fn dest_peripheral(&self) -> Option<Word> {
+        matches!(self.flow_control(), 1 | 3).then_some((self.configuration >> 6) & 0xF)
+    }
+
+    fn privilege(&self) -> Privilege {
+        if self.control & CONTROL_PROT_PRIVILEGED != 0 { Privilege::Privileged } else { Privilege::User }
+    }
+
+    /// Whether a side may transfer: memory always can, a peripheral only while it requests.
+    fn is_requested(line: Option<Word>, requests: Word) -> bool {
+        line.map_or(true, |line| requests & (1 << line) != 0)
+    }
+
+    /// Whether servicing the channel now would make progress.
+    fn is_ready(&self, requests: Word) -> bool {
+        if !self.is_enabled() || self.configuration & CONFIG_HALT != 0 {
+            return false;
+        }
+        let (Ok(src_width), Ok(dest_width)) = (transfer_width(self.control >> 18), transfer_width(self.control >> 21)) else {
+            // servicing reports the bad width as an error
+            return true;
+        };
+        let can_read = DmaChannel::is_requested(self.src_peripheral(), requests)
+            && self.transfer_size() > 0
+            && self.fifo.len() + src_width.bytes() as usize <= FIFO_BYTES;
+        let can_write = DmaChannel::is_requested(self.dest_peripheral(), requests) && self.fifo.len() >= dest_width.bytes() as usize;
+        let is_done = self.transfer_size() == 0 && self.fifo.is_empty();
+        can_read || can_write || is_done
+    }
+
+    fn read(&self, bus: &mut dyn Bus, addr: Word, width: BusWidth, cycle: BusCycle) -> Result<(Word, u64), BusError> {
+        let mut data: Word = 0;
+        let wait_states = bus.transfer(&self.request(addr, BusRW::Read, width, cycle), &mut data)?;
+        Ok((data, 1 + wait_states as u64))
+    }
+
+    fn write(&self, bus: &mut dyn Bus, addr: Word, data: Word, width: BusWidth, cycle: BusCycle) -> Result<u64, BusError> {
+        let mut data = data;
+        let wait_states = bus.transfer(&self.request(addr, BusRW::Write, width, cycle), &mut data)?;
+        Ok(1 + wait_states as u64)
+    }
+
+    fn request(&self, addr: Word, rw: BusRW, width: BusWidth, cycle: BusCycle) -> BusRequest {
+        BusRequest {
+            addr: addr & !(width.bytes() - 1),
+            rw,
+            width,
+            kind: BusKind::Data,
+            privilege: self.privilege(),
+            cycle,
+        }
+    }
+
+    /// Moves up to one source burst into the FIFO and one destination burst out
+    /// of it, each side only while its request line (if any) is asserted.
+    /// Returns the bus cycles used.
+    fn run(&mut self, bus: &mut dyn Bus, requests: Word) -> Result<u64, BusError> {
+        let src_width = transfer_width(self.control >> 18)?;
+        let dest_width = transfer_width(self.control >> 21)?;
+        let mut cycles = 0;
+
+        if DmaChannel::is_requested(self.src_peripheral(), requests) {
+            let mut cycle = BusCycle::NonSequential;
+            for _ in 0..burst_size(self.control >> 12) {
+                if self.transfer_size() == 0 || self.fifo.len() + src_width.bytes() as usize > FIFO_BYTES {
+                    break;
+                }
+                let (data, used) = self.read(bus, self.src_addr, src_width, cycle)?;
+                self.fifo.extend(data.to_le_bytes().iter().take(src_width.bytes() as usize));
+                if self.control & CONTROL_SI != 0 {
+                    self.src_addr = self.src_addr.wrapping_add(src_width.bytes());
+                }
+                self.control -= 1;
+                cycles += used;
+                cycle = BusCycle::Sequential;
+            }
+        }
+
+        if DmaChannel::is_requested(self.dest_peripheral(), requests) {
+            let mut cycle = BusCycle::NonSequential;
+            for _ in 0..burst_size(self.control >> 15) {
+                if self.fifo.len() < dest_width.bytes() as usize {
+                    break;
+                }
+                let data = self.fifo.drain(..dest_width.bytes() as usize).rev().fold(0, |data, byte| data << 8 | byte as Word);
+                cycles += self.write(bus, self.dest_addr, data, dest_width, cycle)?;
+                if self.control & CONTROL_DI != 0 {
+                    self.dest_addr = self.dest_addr.wrapping_add(dest_width.bytes());
+                }
+                cycle = BusCycle::Sequential;
+            }
+        }
+        Ok(cycles)
+    }
+
+    /// Loads the next linked list item: source, destination, next item and control.
+    fn load_lli(&mut self, bus: &mut dyn Bus) -> Result<u64, BusError> {
+        let mut words: [Word; 4] = [0; 4];
+        let mut cycles = 0;
+        for (i, word) in words.iter_mut().enumerate() {
+            let cycle = if i == 0 { BusCycle::NonSequential } else { BusCycle::Sequential };
+            let (data, used) = self.read(bus, self.lli.wrapping_add(i as Word * 4), BusWidth::Word, cycle)?;
+            *word = data;
+            cycles += used;
+        }
+        self.src_addr = words[0];
+        self.dest_addr = words[1];
+        self.lli = words[2] & !0x3;
+        self.control = words[3];
+        Ok(cycles)
+    }
+}
+
+fn transfer_width(field: Word) -> Result<BusWidth, BusError> {
+    match field & 0x7 {
+        0 => Ok(BusWidth::Byte),
+        1 => Ok(BusWidth::HalfWord),
+        2 => Ok(BusWidth::Word),
+        _ => Err(BusError::Width(field & 0x7)),
+    }
+}
+
+fn burst_size(field: Word) -> u32 {
+    match field & 0x7 {
+        0 => 1,
+        size => 1 << (size + 1),
+    }
+}
+
+/// PrimeCell PL080 DMA controller with eight channels and sixteen request lines.
+///
+/// It masters the bus it is mapped on: every tick the highest-priority ready
+/// channel (channel 0 first) moves one burst, and the cycles that takes are
+/// stolen from the CPU. Peripherals drive the request lines through
+/// `MemoryMap::route_dma`. Terminal count and error interrupts are combined
+/// on the device's interrupt output.
+#[derive(Debug, Default)]
+pub struct Dma {
+    pub channels: [DmaChannel; CHANNELS],
+    pub configuration: Word,
+    pub sync: Word,
+    pub raw_tc: Word,
+    pub raw_error: Word,
+    /// Levels of the hardware request lines.
+    pub requests: Word,
+    /// Software requests, cleared once the line has been serviced.
+    pub soft_requests: Word,
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma::default()
+    }
+
+    pub fn tc_status(&self) -> Word {
+        self.raw_tc & self.channel_mask(CONFIG_ITC)
+    }
+
+    pub fn error_status(&self) -> Word {
+        self.raw_error & self.channel_mask(CONFIG_IE)
+    }
+
+    /// One bit per channel with `bit` set in its configuration.
+    fn channel_mask(&self, bit: Word) -> Word {
+        self.channels
+            .iter()
+            .enumerate()
+            .filter(|(_, channel)| channel.configuration & bit != 0)
+            .fold(0, |mask, (index, _)| mask | 1 << index)
+    }
+
+    fn service(&mut self, index: usize, bus: &mut dyn Bus) -> u64 {
+        let requests = self.requests | self.soft_requests;
+        let channel = &mut self.channels[index];
+        let result = channel.run(bus, requests).and_then(|mut cycles| {
+            if channel.transfer_size() == 0 && channel.fifo.is_empty() {
+                if channel.control & CONTROL_TC_INT != 0 {
+                    self.raw_tc |= 1 << index;
+                }
+                if channel.lli != 0 {
+                    cycles += channel.load_lli(bus)?;
+                } else {
+                    channel.configuration &= !CONFIG_ENABLE;
+                }
+            }
+            Ok(cycles)
+        });
+
+        for line in [channel.src_peripheral(), channel.dest_peripheral()].into_iter().flatten() {
+            self.soft_requests &= !(1 << line);
+        }
+        match result {
+            Ok(cycles) => cycles,
+            Err(_) => {
+                channel.configuration &= !CONFIG_ENABLE;
+                channel.fifo.clear();
+                self.raw_error |= 1 << index;
+                1
+            }
+        }
+    }
+
+    fn read_channel(&self, index: usize, offset: Word) -> Word {
+        let channel = &self.channels[index];
+        match offset {
+            CHANNEL_SRC_ADDR => channel.src_addr,
+            CHANNEL_DEST_ADDR => channel.dest_addr,
+            CHANNEL_LLI => channel.lli,
+            CHANNEL_CONTROL => channel.control,
+            CHANNEL_CONFIGURATION => {
+                let active = if channel.fifo.is_empty() { 0 } else { CONFIG_ACTIVE };
+                channel.configuration | active
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_channel(&mut self, index: usize, offset: Word, value: Word) {
+        let channel = &mut self.channels[index];
+        match offset {
+            CHANNEL_SRC_ADDR => channel.src_addr = value,
+            CHANNEL_DEST_ADDR => channel.dest_addr = value,
+            CHANNEL_LLI => channel.lli = value & !0x3,
+            CHANNEL_CONTROL => channel.control = value,
+            CHANNEL_CONFIGURATION => {
+                channel.configuration = value & 0x7FFFF & !CONFIG_ACTIVE;
+                // disabling a channel abandons whatever is still in its FIFO
+                if value & CONFIG_ENABLE == 0 {
+                    channel.fifo.clear();
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Device for Dma {
+    fn read(&mut self, offset: Word, width: BusWidth) -> Result<Word, BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        let value = match offset {
+            DMAC_INT_STATUS => self.tc_status() | self.error_status(),
+            DMAC_INT_TC_STATUS => self.tc_status(),
+            DMAC_INT_ERROR_STATUS => self.error_status(),
+            DMAC_RAW_INT_TC_STATUS => self.raw_tc,
+            DMAC_RAW_INT_ERROR_STATUS => self.raw_error,
+            DMAC_ENBLD_CHNS => self.channel_mask(CONFIG_ENABLE),
+            DMAC_SOFT_BREQ | DMAC_SOFT_SREQ | DMAC_SOFT_LBREQ | DMAC_SOFT_LSREQ => self.soft_requests,
+            DMAC_CONFIGURATION => self.configuration,
+            DMAC_SYNC => self.sync,
+            0x100..=0x1FC => {
+                let index = ((offset - DMAC_CHANNEL0) / DMAC_CHANNEL_STRIDE) as usize;
+                self.read_channel(index, (offset - DMAC_CHANNEL0) % DMAC_CHANNEL_STRIDE)
+            }
+            0xFE0..=0xFFC => PERIPH_ID[((offset - DMAC_PERIPH_ID0) / 4) as usize],
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: Word, value: Word, width: BusWidth) -> Result<(), BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        match offset {
+            DMAC_INT_TC_CLEAR => self.raw_tc &= !value,
+            DMAC_INT_ERR_CLR => self.raw_error &= !value,
+            DMAC_SOFT_BREQ | DMAC_SOFT_SREQ | DMAC_SOFT_LBREQ | DMAC_SOFT_LSREQ => self.soft_requests |= value & 0xFFFF,
+            DMAC_CONFIGURATION => self.configuration = value & 0x7,
+            DMAC_SYNC => self.sync = value & 0xFFFF,
+            0x100..=0x1FC => {
+                let index = ((offset - DMAC_CHANNEL0) / DMAC_CHANNEL_STRIDE) as usize;
+                self.write_channel(index, (offset - DMAC_CHANNEL0) % DMAC_CHANNEL_STRIDE, value);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let requests = self.requests | self.soft_requests;
+        let is_busy = self.configuration & DMAC_ENABLE != 0 && self.channels.iter().any(|channel| channel.is_ready(requests));
+        is_busy.then_some(1)
+    }
+
+    fn interrupt(&self) -> bool {
+        self.tc_status() | self.error_status() != 0
+    }
+
+    fn set_dma_requests(&mut self, requests: Word) {
+        self.requests = requests;
+    }
+
+    fn master(&mut self, bus: &mut dyn Bus) -> u64 {
+        if self.configuration & DMAC_ENABLE == 0 {
+            return 0;
+        }
+        let requests = self.requests | self.soft_requests;
+        match (0..CHANNELS).find(|index| self.channels[*index].is_ready(requests)) {
+            Some(index) => self.service(index, bus),
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_map::*;
+    use crate::uart::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const DMAC: Word = 0x20000;
+    const UART: Word = 0x30000;
+
+    fn channel(number: Word, register: Word) -> Word {
+        DMAC + DMAC_CHANNEL0 + number * DMAC_CHANNEL_STRIDE + register
+    }
+
+    fn read(map: &mut MemoryMap, addr: Word) -> Word {
+        let mut data = 0;
+        map.access(addr, &mut data, BusRW::Read, BusCycle::NonSequential).unwrap();
+        data
+    }
+
+    fn write(map: &mut MemoryMap, addr: Word, value: Word) {
+        let mut data = value;
+        map.access(addr, &mut data, BusRW::Write, BusCycle::NonSequential).unwrap();
+    }
+
+    #[test]
+    fn copies_memory_and_feeds_peripherals() {
+        let mut map = MemoryMap::new();
+        map.add_ram("ram", 0, 0x10000);
+        let dmac = map.add_device("dma", DMAC, 0x1000, Box::new(Dma::new()));
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let uart = map.add_device("uart", UART, 0x1000, Box::new(Uart::new(UartTx::Buffer(output.clone()), UartRx::None)));
+        map.route_dma(uart, DmaRoute { controller: dmac, line: 4 });
+        map.route_interrupt(dmac, InterruptRoute::Irq);
+        write(&mut map, DMAC + DMAC_CONFIGURATION, DMAC_ENABLE);
+
+        // memory to memory: 10 words in bursts of 4, with a terminal count interrupt
+        for i in 0..10 {
+            write(&mut map, 0x1000 + i * 4, 0x1111 * i);
+        }
+        write(&mut map, channel(0, CHANNEL_SRC_ADDR), 0x1000);
+        write(&mut map, channel(0, CHANNEL_DEST_ADDR), 0x2000);
+        write(&mut map, channel(0, CHANNEL_CONTROL), 10 | (1 << 12) | (1 << 15) | (2 << 18) | (2 << 21) | CONTROL_SI | CONTROL_DI | CONTROL_TC_INT);
+        write(&mut map, channel(0, CHANNEL_CONFIGURATION), CONFIG_ENABLE | CONFIG_ITC | CONFIG_IE);
+        for _ in 0..20 {
+            map.tick(1);
+        }
+        for i in 0..10 {
+            assert_eq!(read(&mut map, 0x2000 + i * 4), 0x1111 * i);
+        }
+        assert!(map.interrupt_lines().irq);
+        assert!(map.stolen_cycles() >= 20);
+        assert_eq!(read(&mut map, DMAC + DMAC_ENBLD_CHNS), 0);
+        write(&mut map, DMAC + DMAC_INT_TC_CLEAR, 1);
+        assert!(!map.interrupt_lines().irq);
+
+        // memory to the UART's transmit request line, a byte at a time
+        map.load(0x3000, b"dma!").unwrap();
+        // UARTDMACR.TXDMAE
+        write(&mut map, UART + 0x48, 2);
+        write(&mut map, channel(1, CHANNEL_SRC_ADDR), 0x3000);
+        write(&mut map, channel(1, CHANNEL_DEST_ADDR), UART);
+        write(&mut map, channel(1, CHANNEL_CONTROL), 4 | CONTROL_SI);
+        write(&mut map, channel(1, CHANNEL_CONFIGURATION), CONFIG_ENABLE | (5 << 6) | (1 << 11));
+        for _ in 0..20 {
+            map.tick(1);
+        }
+        assert_eq!(&*output.borrow(), b"dma!");
+
+        // a bus error stops the channel and raises its error interrupt
+        write(&mut map, channel(2, CHANNEL_SRC_ADDR), 0x9000_0000);
+        write(&mut map, channel(2, CHANNEL_DEST_ADDR), 0x2000);
+        write(&mut map, channel(2, CHANNEL_CONTROL), 1 | (2 << 18) | (2 << 21));
+        write(&mut map, channel(2, CHANNEL_CONFIGURATION), CONFIG_ENABLE | CONFIG_IE);
+        map.tick(1);
+        assert_eq!(read(&mut map, DMAC + DMAC_INT_ERROR_STATUS), 1 << 2);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::armv4t::*;
+use crate::sparse_memory::SparseMemory;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_ARM: u16 = 40;
+const PT_LOAD: u32 = 1;
+const EF_ARM_EABI_MASK: u32 = 0xFF000000;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ElfError {
+    /// Not a 32-bit little-endian ARM executable.
+    Format(&'static str),
+    /// A header or segment reaches past the end of the file.
+    Truncated,
+}
+
+impl std::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ElfError::Format(reason) => write!(f, "not an ARM executable: {}", reason),
+            ElfError::Truncated => write!(f, "truncated ELF file"),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Segment {
+    pub offset: u32,
+    pub vaddr: Word,
+    pub file_size: u32,
+    pub memory_size: u32,
+}
+
+/// A static executable: its entry point and the segments to load.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ElfImage {
+    pub entry: Word,
+    /// File offset, entry size and count of the program headers.
+    pub phoff: u32,
+    pub phentsize: u16,
+    pub phnum: u16,
+    /// Built for the EABI rather than the old ABI.
+    pub is_eabi: bool,
+    pub segments: Vec<Segment>,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+impl ElfImage {
+    pub fn parse(data: &[u8]) -> Result<ElfImage, ElfError> {
+        if data.get(0..4) != Some(&ELF_MAGIC[..]) {
+            return Err(ElfError::Format("bad magic"));
+        }
+        if data.get(4) != Some(&ELFCLASS32) || data.get(5) != Some(&ELFDATA2LSB) {
+            return Err(ElfError::Format("not 32-bit little-endian"));
+        }
+        if u16_at(data, 16)? != ET_EXEC {
+            return Err(ElfError::Format("not a static executable"));
+        }
+        if u16_at(data, 18)? != EM_ARM {
+            return Err(ElfError::Format("not for ARM"));
+        }
+        let mut image = ElfImage {
+            entry: u32_at(data, 24)?,
+            phoff: u32_at(data, 28)?,
+            phentsize: u16_at(data, 42)?,
+            phnum: u16_at(data, 44)?,
+            is_eabi: u32_at(data, 36)? & EF_ARM_EABI_MASK != 0,
+            segments: Vec::new(),
+        };
+        for i in 0..image.phnum as usize {
+            let header = image.phoff as usize + i * image.phentsize as usize;
+            if u32_at(data, header)? != PT_LOAD {
+                continue;
+            }
+            let segment = Segment {
+                offset: u32_at(data, header + 4)?,
+                vaddr: u32_at(data, header + 8)?,
+                file_size: u32_at(data, header + 16)?,
+                memory_size: u32_at(data, header + 20)?,
+            };
+            if data.len() < segment.offset as usize + segment.file_size as usize {
+                return Err(ElfError::Truncated);
+            }
+            image.segments.push(segment);
+        }
+        Ok(image)
+    }
+
+    /// Copies the segments into `memory`; the rest of each segment reads as zero.
+    pub fn load(&self, data: &[u8], memory: &mut SparseMemory) {
+        for segment in self.segments.iter() {
+            let start = segment.offset as usize;
+            memory.load(segment.vaddr, &data[start..start + segment.file_size as usize]);
+        }
+    }
+
+    /// Address the program headers are loaded at, if a segment covers them.
+    pub fn phdr_address(&self) -> Option<Word> {
+        self.segments
+            .iter()
+            .find(|segment| segment.offset <= self.phoff && self.phoff - segment.offset < segment.file_size)
+            .map(|segment| segment.vaddr.wrapping_add(self.phoff - segment.offset))
+    }
+
+    /// First address past every segment, where the heap starts.
+    pub fn end(&self) -> Word {
+        self.segments.iter().map(|segment| segment.vaddr.wrapping_add(segment.memory_size)).max().unwrap_or(0)
+    }
+}
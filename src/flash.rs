@@ -0,0 +1,410 @@
+use crate::armv4t::*;
+use crate::device::*;
+use crate::memory_map::{read_le, write_le};
+use std::io::{Seek, SeekFrom, Write};
+
+/// Command addresses are decoded on word (16-bit) addresses, as for a x16 part.
+const AMD_UNLOCK1: Word = 0x555;
+const AMD_UNLOCK2: Word = 0x2AA;
+const CFI_QUERY_ADDR: Word = 0x55;
+
+const CMD_CFI_QUERY: u16 = 0x98;
+const CMD_READ_ID: u16 = 0x90;
+
+const AMD_CMD_RESET: u16 = 0xF0;
+const AMD_CMD_UNLOCK1: u16 = 0xAA;
+const AMD_CMD_UNLOCK2: u16 = 0x55;
+const AMD_CMD_PROGRAM: u16 = 0xA0;
+const AMD_CMD_ERASE_SETUP: u16 = 0x80;
+const AMD_CMD_SECTOR_ERASE: u16 = 0x30;
+const AMD_CMD_CHIP_ERASE: u16 = 0x10;
+
+const INTEL_CMD_READ_ARRAY: u16 = 0xFF;
+const INTEL_CMD_READ_STATUS: u16 = 0x70;
+const INTEL_CMD_CLEAR_STATUS: u16 = 0x50;
+const INTEL_CMD_PROGRAM: u16 = 0x40;
+const INTEL_CMD_PROGRAM_ALT: u16 = 0x10;
+const INTEL_CMD_ERASE_SETUP: u16 = 0x20;
+const INTEL_CMD_ERASE_CONFIRM: u16 = 0xD0;
+const INTEL_CMD_LOCK_SETUP: u16 = 0x60;
+
+const AMD_STATUS_DQ7: u8 = 0x80;
+const AMD_STATUS_TOGGLE: u8 = 0x40;
+const AMD_STATUS_ERASE_STARTED: u8 = 0x08;
+
+const INTEL_STATUS_READY: u8 = 0x80;
+const INTEL_STATUS_ERASE_ERROR: u8 = 0x20;
+const INTEL_STATUS_PROGRAM_ERROR: u8 = 0x10;
+
+/// Which vendor command set the part answers to.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum FlashCommandSet {
+    /// AMD/Fujitsu standard command set (CFI primary command set 0x0002).
+    Amd,
+    /// Intel/Sharp extended command set (CFI primary command set 0x0001).
+    Intel,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum FlashState {
+    ReadArray,
+    AmdUnlocked1,
+    AmdUnlocked2,
+    AmdEraseSetup,
+    AmdEraseUnlocked1,
+    AmdEraseUnlocked2,
+    /// The next write is programmed into the array.
+    Program,
+    ReadId,
+    CfiQuery,
+    IntelStatus,
+    IntelEraseSetup,
+    IntelLockSetup,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum FlashOperation {
+    /// Programming; `data` is the last byte written, for AMD data polling.
+    Program { data: u8 },
+    Erase,
+}
+
+/// A x16 NOR flash part. It reads like ROM until the command sequences of its
+/// command set put it into another mode; program and erase take `program_cycles`
+/// and `erase_cycles` (per sector), during which reads return status.
+pub struct NorFlash {
+    pub data: Vec<u8>,
+    pub sector_size: usize,
+    pub command_set: FlashCommandSet,
+    pub manufacturer_id: u16,
+    pub device_id: u16,
+    pub program_cycles: u64,
+    pub erase_cycles: u64,
+    pub state: FlashState,
+    /// Operation in progress and the cycles left until it completes.
+    pub busy: Option<(FlashOperation, u64)>,
+    pub intel_status: u8,
+    /// DQ6 of the AMD status, flipped by every status read.
+    pub toggle: bool,
+    /// Image file that programmed and erased ranges are written back to.
+    pub file: Option<std::fs::File>,
+}
+
+impl NorFlash {
+    /// An erased part of `size` bytes with uniform sectors. `sector_size`
+    /// must be a power of two that divides `size`.
+    pub fn new(size: usize, sector_size: usize, command_set: FlashCommandSet) -> NorFlash {
+        assert!(sector_size.is_power_of_two(), "sector size {:#x} is not a power of two", sector_size);
+        assert!(size & (sector_size - 1) == 0, "size {:#x} is not a whole number of sectors", size);
+        let (manufacturer_id, device_id) = match command_set {
+            FlashCommandSet::Amd => (0x0001, 0x227E),
+            FlashCommandSet::Intel => (0x0089, 0x0018),
+        };
+        NorFlash {
+            data: vec![0xFF; size],
+            sector_size,
+            command_set,
+            manufacturer_id,
+            device_id,
+            program_cycles: 500,
+            erase_cycles: 50_000,
+            state: FlashState::ReadArray,
+            busy: None,
+            intel_status: INTEL_STATUS_READY,
+            toggle: false,
+            file: None,
+        }
+    }
+
+    /// A part initialised from the image at `path`, padded with erased bytes.
+    /// With `write_back` every program and erase is also applied to the file.
+    pub fn open(path: &str, size: usize, sector_size: usize, command_set: FlashCommandSet, write_back: bool) -> std::io::Result<NorFlash> {
+        let mut flash = NorFlash::new(size, sector_size, command_set);
+        let image = std::fs::read(path)?;
+        let length = image.len().min(size);
+        flash.data[..length].copy_from_slice(&image[..length]);
+        if write_back {
+            flash.file = Some(std::fs::OpenOptions::new().write(true).open(path)?);
+        }
+        Ok(flash)
+    }
+
+    fn sectors(&self) -> usize {
+        self.data.len() / self.sector_size
+    }
+
+    /// Query structure at word address `addr` while in CFI query mode.
+    fn cfi(&self, addr: Word) -> u16 {
+        let command_set: u16 = match self.command_set {
+            FlashCommandSet::Amd => 0x0002,
+            FlashCommandSet::Intel => 0x0001,
+        };
+        let blocks = self.sectors().max(1) - 1;
+        let block_size = self.sector_size / 256;
+        match addr {
+            0x10 => b'Q' as u16,
+            0x11 => b'R' as u16,
+            0x12 => b'Y' as u16,
+            0x13 => command_set,
+            0x1B => 0x27,
+            0x1C => 0x36,
+            // typical program 16us, sector erase 1s; maximum 16x those
+            0x1F => 0x04,
+            0x21 => 0x0A,
+            0x23 => 0x04,
+            0x25 => 0x04,
+            0x27 => self.data.len().trailing_zeros() as u16,
+            // x16 asynchronous interface
+            0x28 => 0x01,
+            0x2C => 1,
+            0x2D => blocks as u16 & 0xFF,
+            0x2E => (blocks >> 8) as u16 & 0xFF,
+            0x2F => block_size as u16 & 0xFF,
+            0x30 => (block_size >> 8) as u16 & 0xFF,
+            _ => 0,
+        }
+    }
+
+    /// Value a status read returns while an operation is in progress.
+    fn amd_status(&mut self, operation: FlashOperation) -> u8 {
+        self.toggle = !self.toggle;
+        let toggle = if self.toggle { AMD_STATUS_TOGGLE } else { 0 };
+        match operation {
+            FlashOperation::Program { data } => (!data & AMD_STATUS_DQ7) | toggle,
+            FlashOperation::Erase => toggle | AMD_STATUS_ERASE_STARTED,
+        }
+    }
+
+    fn program(&mut self, offset: usize, value: Word, width: BusWidth) {
+        let bytes = width.bytes() as usize;
+        // programming can only clear bits
+        let old = read_le(&self.data, offset, bytes);
+        write_le(&mut self.data, offset, bytes, old & value);
+        self.busy = Some((FlashOperation::Program { data: value as u8 }, self.program_cycles));
+        self.write_back(offset, bytes);
+    }
+
+    fn erase(&mut self, start: usize, length: usize) {
+        self.data[start..start + length].fill(0xFF);
+        let sectors = (length / self.sector_size) as u64;
+        self.busy = Some((FlashOperation::Erase, self.erase_cycles * sectors));
+        self.write_back(start, length);
+    }
+
+    fn write_back(&mut self, start: usize, length: usize) {
+        if let Some(file) = &mut self.file {
+            let result = file
+                .seek(SeekFrom::Start(start as u64))
+                .and_then(|_| file.write_all(&self.data[start..start + length]));
+            if let Err(e) = result {
+                eprintln!("flash write-back failed: {}", e);
+            }
+        }
+    }
+
+    fn amd_command(&mut self, offset: usize, value: Word, width: BusWidth) {
+        let addr = (offset as Word >> 1) & 0x7FF;
+        let command = value as u16 & 0xFF;
+        self.state = match (self.state, addr, command) {
+            (FlashState::Program, _, _) => {
+                self.program(offset, value, width);
+                FlashState::ReadArray
+            }
+            (_, _, AMD_CMD_RESET) => FlashState::ReadArray,
+            (FlashState::ReadArray, CFI_QUERY_ADDR, CMD_CFI_QUERY) => FlashState::CfiQuery,
+            (FlashState::ReadArray, AMD_UNLOCK1, AMD_CMD_UNLOCK1) => FlashState::AmdUnlocked1,
+            (FlashState::AmdUnlocked1, AMD_UNLOCK2, AMD_CMD_UNLOCK2) => FlashState::AmdUnlocked2,
+            (FlashState::AmdUnlocked2, AMD_UNLOCK1, AMD_CMD_PROGRAM) => FlashState::Program,
+            (FlashState::AmdUnlocked2, AMD_UNLOCK1, AMD_CMD_ERASE_SETUP) => FlashState::AmdEraseSetup,
+            (FlashState::AmdUnlocked2, AMD_UNLOCK1, CMD_READ_ID) => FlashState::ReadId,
+            (FlashState::AmdEraseSetup, AMD_UNLOCK1, AMD_CMD_UNLOCK1) => FlashState::AmdEraseUnlocked1,
+            (FlashState::AmdEraseUnlocked1, AMD_UNLOCK2, AMD_CMD_UNLOCK2) => FlashState::AmdEraseUnlocked2,
+            (FlashState::AmdEraseUnlocked2, _, AMD_CMD_SECTOR_ERASE) => {
+                let start = offset / self.sector_size * self.sector_size;
+                self.erase(start, self.sector_size);
+                FlashState::ReadArray
+            }
+            (FlashState::AmdEraseUnlocked2, AMD_UNLOCK1, AMD_CMD_CHIP_ERASE) => {
+                self.erase(0, self.data.len());
+                FlashState::ReadArray
+            }
+            // ID and CFI modes ignore everything but reset
+            (FlashState::ReadId, _, _) | (FlashState::CfiQuery, _, _) => self.state,
+            // a broken sequence drops back to reading the array
+            _ => FlashState::ReadArray,
+        };
+    }
+
+    fn intel_command(&mut self, offset: usize, value: Word, width: BusWidth) {
+        let command = value as u16 & 0xFF;
+        self.state = match (self.state, command) {
+            (FlashState::Program, _) => {
+                self.program(offset, value, width);
+                FlashState::IntelStatus
+            }
+            (FlashState::IntelEraseSetup, INTEL_CMD_ERASE_CONFIRM) => {
+                let start = offset / self.sector_size * self.sector_size;
+                self.erase(start, self.sector_size);
+                FlashState::IntelStatus
+            }
+            (FlashState::IntelEraseSetup, _) => {
+                self.intel_status |= INTEL_STATUS_ERASE_ERROR | INTEL_STATUS_PROGRAM_ERROR;
+                FlashState::IntelStatus
+            }
+            // block locking is not modelled; every block stays unlocked
+            (FlashState::IntelLockSetup, _) => FlashState::IntelStatus,
+            (_, INTEL_CMD_READ_ARRAY) => FlashState::ReadArray,
+            (_, CMD_READ_ID) => FlashState::ReadId,
+            (_, CMD_CFI_QUERY) => FlashState::CfiQuery,
+            (_, INTEL_CMD_READ_STATUS) => FlashState::IntelStatus,
+            (state, INTEL_CMD_CLEAR_STATUS) => {
+                self.intel_status = INTEL_STATUS_READY;
+                state
+            }
+            (_, INTEL_CMD_PROGRAM) | (_, INTEL_CMD_PROGRAM_ALT) => FlashState::Program,
+            (_, INTEL_CMD_ERASE_SETUP) => FlashState::IntelEraseSetup,
+            (_, INTEL_CMD_LOCK_SETUP) => FlashState::IntelLockSetup,
+            (state, _) => state,
+        };
+    }
+}
+
+impl Device for NorFlash {
+    fn read(&mut self, offset: Word, width: BusWidth) -> Result<Word, BusError> {
+        let bytes = width.bytes() as usize;
+        if offset as usize + bytes > self.data.len() {
+            return Err(BusError::Unmapped(offset));
+        }
+        let replicate = |value: u16| -> Word { (value as Word | (value as Word) << 16) & width.mask() };
+
+        if let Some((operation, _)) = self.busy {
+            let status = match self.command_set {
+                FlashCommandSet::Amd => self.amd_status(operation),
+                FlashCommandSet::Intel => self.intel_status & !INTEL_STATUS_READY,
+            };
+            return Ok(replicate(status as u16 | (status as u16) << 8));
+        }
+        let value = match self.state {
+            FlashState::ReadId => {
+                // the ID sits at the start of every sector
+                match (offset as usize % self.sector_size) >> 1 {
+                    0 => replicate(self.manufacturer_id),
+                    1 => replicate(self.device_id),
+                    _ => 0,
+                }
+            }
+            FlashState::CfiQuery => replicate(self.cfi(offset >> 1)),
+            FlashState::IntelStatus | FlashState::IntelEraseSetup | FlashState::IntelLockSetup => {
+                replicate(self.intel_status as u16)
+            }
+            _ => read_le(&self.data, offset as usize, bytes),
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: Word, value: Word, width: BusWidth) -> Result<(), BusError> {
+        if offset as usize + width.bytes() as usize > self.data.len() {
+            return Err(BusError::Unmapped(offset));
+        }
+        // commands are ignored while the part is busy
+        if self.busy.is_some() {
+            return Ok(());
+        }
+        match self.command_set {
+            FlashCommandSet::Amd => self.amd_command(offset as usize, value, width),
+            FlashCommandSet::Intel => self.intel_command(offset as usize, value, width),
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if let Some((operation, remaining)) = self.busy {
+            self.busy = if remaining > cycles { Some((operation, remaining - cycles)) } else { None };
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.busy.map(|(_, remaining)| remaining.max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALF: BusWidth = BusWidth::HalfWord;
+
+    /// Writes `command` to the 16-bit word address `addr`.
+    fn command(flash: &mut NorFlash, addr: Word, command: u16) {
+        flash.write(addr * 2, command as Word, HALF).unwrap();
+    }
+
+    fn amd_unlock(flash: &mut NorFlash) {
+        command(flash, AMD_UNLOCK1, AMD_CMD_UNLOCK1);
+        command(flash, AMD_UNLOCK2, AMD_CMD_UNLOCK2);
+    }
+
+    #[test]
+    fn amd_program_erase_and_query() {
+        let path = std::env::temp_dir().join("armv4t_flash_test.bin");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, [0x12u8, 0x34, 0x56, 0x78]).unwrap();
+        let mut flash = NorFlash::open(path, 0x20000, 0x10000, FlashCommandSet::Amd, true).unwrap();
+        assert_eq!(flash.read(0, BusWidth::Word).unwrap(), 0x78563412);
+
+        amd_unlock(&mut flash);
+        command(&mut flash, AMD_UNLOCK1, AMD_CMD_PROGRAM);
+        flash.write(0x10, 0x1234, HALF).unwrap();
+        let first = flash.read(0x10, HALF).unwrap();
+        let second = flash.read(0x10, HALF).unwrap();
+        assert_ne!(first & AMD_STATUS_TOGGLE as Word, second & AMD_STATUS_TOGGLE as Word);
+        // DQ7 reads as the complement of the data's bit 7 while programming
+        assert_eq!(first & AMD_STATUS_DQ7 as Word, AMD_STATUS_DQ7 as Word);
+        flash.tick(1000);
+        assert_eq!(flash.read(0x10, HALF).unwrap(), 0x1234);
+
+        amd_unlock(&mut flash);
+        command(&mut flash, AMD_UNLOCK1, AMD_CMD_ERASE_SETUP);
+        amd_unlock(&mut flash);
+        command(&mut flash, 0x80, AMD_CMD_SECTOR_ERASE);
+        assert!(flash.next_event().is_some());
+        flash.tick(100_000);
+        assert_eq!(flash.read(0, BusWidth::Word).unwrap(), 0xFFFFFFFF);
+        assert_eq!(&std::fs::read(path).unwrap()[..4], &[0xFF; 4]);
+
+        command(&mut flash, CFI_QUERY_ADDR, CMD_CFI_QUERY);
+        assert_eq!(flash.read(0x10 * 2, HALF).unwrap(), b'Q' as Word);
+        // device size as a power of two
+        assert_eq!(flash.read(0x27 * 2, HALF).unwrap(), 17);
+        // sectors in the erase block region, less one
+        assert_eq!(flash.read(0x2D * 2, HALF).unwrap(), 1);
+        command(&mut flash, 0, AMD_CMD_RESET);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn intel_program_and_read_id() {
+        let mut flash = NorFlash::new(0x20000, 0x10000, FlashCommandSet::Intel);
+        flash.write(0x10, INTEL_CMD_PROGRAM as Word, HALF).unwrap();
+        flash.write(0x10, 0xBEEF, HALF).unwrap();
+        assert_eq!(flash.read(0, HALF).unwrap() & INTEL_STATUS_READY as Word, 0);
+        flash.tick(1000);
+        assert_eq!(flash.read(0, HALF).unwrap() & INTEL_STATUS_READY as Word, INTEL_STATUS_READY as Word);
+        flash.write(0, INTEL_CMD_READ_ARRAY as Word, HALF).unwrap();
+        assert_eq!(flash.read(0x10, HALF).unwrap(), 0xBEEF);
+        flash.write(0x10, CMD_READ_ID as Word, HALF).unwrap();
+        assert_eq!(flash.read(0, HALF).unwrap(), 0x89);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a whole number of sectors")]
+    fn rejects_a_partial_sector() {
+        NorFlash::new(0x18000, 0x10000, FlashCommandSet::Amd);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a power of two")]
+    fn rejects_an_empty_sector() {
+        NorFlash::new(0x10000, 0, FlashCommandSet::Amd);
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::Write;
+
+/// Largest payload of a stored (uncompressed) deflate block.
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Ppm => "ppm",
+        }
+    }
+}
+
+/// An 8-bit RGB image, rows top to bottom.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    /// Three bytes per pixel: red, green, blue.
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize) -> Frame {
+        Frame { width, height, pixels: vec![0; width * height * 3] }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3]) {
+        let index = (y * self.width + x) * 3;
+        self.pixels[index..index + 3].copy_from_slice(&rgb);
+    }
+
+    pub fn save(&self, path: &str, format: ImageFormat) -> std::io::Result<()> {
+        let data = match format {
+            ImageFormat::Png => self.to_png(),
+            ImageFormat::Ppm => self.to_ppm(),
+        };
+        std::fs::File::create(path)?.write_all(&data)
+    }
+
+    /// Binary PPM (P6).
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.extend_from_slice(&self.pixels);
+        data
+    }
+
+    /// PNG with the image data in stored deflate blocks, so no compressor is needed.
+    pub fn to_png(&self) -> Vec<u8> {
+        // every row starts with filter type 0 (none)
+        let mut raw = Vec::with_capacity((self.width * 3 + 1) * self.height);
+        for row in self.pixels.chunks((self.width * 3).max(1)) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+
+        let mut zlib = vec![0x78, 0x01];
+        let mut blocks = raw.chunks(MAX_STORED_BLOCK).peekable();
+        if blocks.peek().is_none() {
+            zlib.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+        }
+        while let Some(block) = blocks.next() {
+            let is_final = blocks.peek().is_none();
+            let length = block.len() as u16;
+            zlib.push(is_final as u8);
+            zlib.extend_from_slice(&length.to_le_bytes());
+            zlib.extend_from_slice(&(!length).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8-bit depth, truecolour, deflate, adaptive filtering, no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut data = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        write_png_chunk(&mut data, b"IHDR", &header);
+        write_png_chunk(&mut data, b"IDAT", &zlib);
+        write_png_chunk(&mut data, b"IEND", &[]);
+        data
+    }
+}
+
+fn write_png_chunk(data: &mut Vec<u8>, kind: &[u8; 4], payload: &[u8]) {
+    data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    let start = data.len();
+    data.extend_from_slice(kind);
+    data.extend_from_slice(payload);
+    let crc = crc32(&data[start..]);
+    data.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// CRC-32 (IEEE 802.3), as used by PNG chunks.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b): (u32, u32) = (1, 0);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_reference_values() {
+        assert_eq!(crc32(b"IEND"), 0xAE426082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+}
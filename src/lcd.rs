@@ -0,0 +1,354 @@
+use crate::armv4t::*;
+use crate::device::*;
+use crate::image::*;
+use std::cell::Cell;
+use std::rc::Rc;
+
+const CLCD_TIMING0: Word = 0x000;
+const CLCD_TIMING1: Word = 0x004;
+const CLCD_TIMING2: Word = 0x008;
+const CLCD_TIMING3: Word = 0x00C;
+const CLCD_UPBASE: Word = 0x010;
+const CLCD_LPBASE: Word = 0x014;
+const CLCD_CONTROL: Word = 0x018;
+const CLCD_IMSC: Word = 0x01C;
+const CLCD_RIS: Word = 0x020;
+const CLCD_MIS: Word = 0x024;
+const CLCD_ICR: Word = 0x028;
+const CLCD_UPCURR: Word = 0x02C;
+const CLCD_LPCURR: Word = 0x030;
+const CLCD_PALETTE: Word = 0x200;
+const CLCD_PERIPH_ID0: Word = 0xFE0;
+
+const CONTROL_ENABLE: Word = 1 << 0;
+const CONTROL_BGR: Word = 1 << 8;
+const CONTROL_POWER: Word = 1 << 11;
+
+const INT_BASE_UPDATE: Word = 0x04;
+const INT_VCOMP: Word = 0x08;
+const INT_ALL: Word = 0x1E;
+
+const PALETTE_ENTRIES: usize = 256;
+
+const PERIPH_ID: [Word; 8] = [0x11, 0x11, 0x24, 0x00, 0x0D, 0xF0, 0x05, 0xB1];
+
+/// Pixel formats selected by the LcdBpp field. Colour components are stored
+/// red in the low bits unless the BGR bit of LCDControl swaps red and blue.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum PixelFormat {
+    /// Palette lookup with 1, 2, 4 or 8 bits per pixel.
+    Palette(u32),
+    /// 16 bits per pixel, 5:5:5 with the top bit unused.
+    Rgb555,
+    /// 24-bit colour in 32 bits per pixel.
+    Rgb888,
+    Rgb565,
+    /// 4:4:4 in 16 bits per pixel.
+    Rgb444,
+}
+
+impl PixelFormat {
+    pub fn from_bpp_field(field: Word) -> PixelFormat {
+        match field & 0x7 {
+            0 => PixelFormat::Palette(1),
+            1 => PixelFormat::Palette(2),
+            2 => PixelFormat::Palette(4),
+            3 => PixelFormat::Palette(8),
+            4 => PixelFormat::Rgb555,
+            5 => PixelFormat::Rgb888,
+            6 => PixelFormat::Rgb565,
+            _ => PixelFormat::Rgb444,
+        }
+    }
+
+    pub fn bpp_field(&self) -> Word {
+        match self {
+            PixelFormat::Palette(bits) => bits.trailing_zeros().min(3),
+            PixelFormat::Rgb555 => 4,
+            PixelFormat::Rgb888 => 5,
+            PixelFormat::Rgb565 => 6,
+            PixelFormat::Rgb444 => 7,
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        match self {
+            PixelFormat::Palette(bits) => *bits,
+            PixelFormat::Rgb888 => 32,
+            _ => 16,
+        }
+    }
+
+    /// Component widths and positions as (bits, shift) for the low, middle and high components.
+    fn components(&self) -> [(u32, u32); 3] {
+        match self {
+            PixelFormat::Rgb888 => [(8, 0), (8, 8), (8, 16)],
+            PixelFormat::Rgb565 => [(5, 0), (6, 5), (5, 11)],
+            PixelFormat::Rgb444 => [(4, 0), (4, 4), (4, 8)],
+            // palette entries share the 5:5:5 layout
+            _ => [(5, 0), (5, 5), (5, 10)],
+        }
+    }
+}
+
+/// Scales a `bits`-wide component to 8 bits.
+fn expand(value: Word, bits: u32) -> u8 {
+    let value = value & ((1 << bits) - 1);
+    ((value << (8 - bits)) | (value >> (2 * bits).saturating_sub(8))) as u8
+}
+
+/// PrimeCell PL111 colour LCD controller, as a framebuffer that can be dumped
+/// to image files.
+///
+/// The panel is `(PPL + 1) * 16` pixels wide and `LPP + 1` lines high, scanned
+/// from LCDUPBASE. A frame lasts `cycles_per_frame` CPU cycles; each vertical
+/// sync raises the vertical compare and base update interrupts and, every
+/// `dump_interval` frames, dumps the frame. Setting the flag returned by
+/// `dump_trigger` dumps the next time the device runs. Frames are fetched
+/// through the bus as a bus master, without stalling the CPU.
+pub struct Lcd {
+    pub timing: [Word; 4],
+    pub upbase: Word,
+    pub lpbase: Word,
+    pub control: Word,
+    pub imsc: Word,
+    pub ris: Word,
+    pub upcurr: Word,
+    pub palette: [u16; PALETTE_ENTRIES],
+    pub cycles_per_frame: u64,
+    pub frame_cycles: u64,
+    /// Vertical syncs since the controller was created.
+    pub frames: u64,
+    /// Dumps go to `<dump_prefix><frame number>.<extension>`.
+    pub dump_prefix: String,
+    pub dump_format: ImageFormat,
+    pub dump_interval: Option<u64>,
+    pub is_dump_pending: bool,
+    pub dump_trigger: Rc<Cell<bool>>,
+}
+
+impl Lcd {
+    pub fn new(cycles_per_frame: u64, dump_prefix: &str, dump_format: ImageFormat) -> Lcd {
+        Lcd {
+            timing: [0; 4],
+            upbase: 0,
+            lpbase: 0,
+            control: 0,
+            imsc: 0,
+            ris: 0,
+            upcurr: 0,
+            palette: [0; PALETTE_ENTRIES],
+            cycles_per_frame: cycles_per_frame.max(1),
+            frame_cycles: 0,
+            frames: 0,
+            dump_prefix: dump_prefix.to_string(),
+            dump_format,
+            dump_interval: None,
+            is_dump_pending: false,
+            dump_trigger: Rc::new(Cell::new(false)),
+        }
+    }
+
+    /// Programs the panel from the host, as the guest's driver would.
+    /// `width` must be a multiple of 16; both dimensions are at most 1024.
+    pub fn configure(&mut self, base: Word, width: usize, height: usize, format: PixelFormat) {
+        self.timing[0] = (self.timing[0] & !0xFC) | ((((width / 16).max(1) - 1) as Word & 0x3F) << 2);
+        self.timing[1] = (self.timing[1] & !0x3FF) | ((height.max(1) - 1) as Word & 0x3FF);
+        self.upbase = base & !0x7;
+        self.upcurr = self.upbase;
+        self.control = (self.control & !0xE) | (format.bpp_field() << 1) | CONTROL_ENABLE | CONTROL_POWER;
+    }
+
+    /// Shared flag the host sets to request a dump of the current frame.
+    pub fn dump_trigger(&self) -> Rc<Cell<bool>> {
+        self.dump_trigger.clone()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.control & CONTROL_ENABLE != 0
+    }
+
+    pub fn resolution(&self) -> (usize, usize) {
+        let width = (((self.timing[0] >> 2) & 0x3F) as usize + 1) * 16;
+        let height = (self.timing[1] & 0x3FF) as usize + 1;
+        (width, height)
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::from_bpp_field(self.control >> 1)
+    }
+
+    fn color(&self, format: PixelFormat, value: Word) -> [u8; 3] {
+        let value = match format {
+            PixelFormat::Palette(_) => self.palette[value as usize] as Word,
+            _ => value,
+        };
+        let [low, middle, high] = format.components().map(|(bits, shift)| expand(value >> shift, bits));
+        if self.control & CONTROL_BGR != 0 { [high, middle, low] } else { [low, middle, high] }
+    }
+
+    /// Reads the current frame out of memory.
+    pub fn capture(&self, bus: &mut dyn Bus) -> Result<Frame, BusError> {
+        let (width, height) = self.resolution();
+        let format = self.pixel_format();
+        let bits = format.bits();
+        let mut frame = Frame::new(width, height);
+        let mut word: Option<(Word, Word)> = None;
+        for y in 0..height {
+            for x in 0..width {
+                let bit = ((y * width + x) as u64 * bits as u64) as Word;
+                let addr = self.upcurr.wrapping_add(bit / 32 * 4);
+                let data = match word {
+                    Some((cached, data)) if cached == addr => data,
+                    _ => {
+                        let cycle = if word.is_some() { BusCycle::Sequential } else { BusCycle::NonSequential };
+                        let mut data: Word = 0;
+                        bus.transfer(&BusRequest::word(addr, BusRW::Read, cycle), &mut data)?;
+                        word = Some((addr, data));
+                        data
+                    }
+                };
+                let mask = if bits == 32 { 0xFFFFFFFF } else { (1 << bits) - 1 };
+                frame.set_pixel(x, y, self.color(format, (data >> (bit % 32)) & mask));
+            }
+        }
+        Ok(frame)
+    }
+
+    fn dump(&self, bus: &mut dyn Bus) {
+        let path = format!("{}{:05}.{}", self.dump_prefix, self.frames, self.dump_format.extension());
+        let result = self
+            .capture(bus)
+            .map_err(|e| std::io::Error::other(e.to_string()))
+            .and_then(|frame| frame.save(&path, self.dump_format));
+        if let Err(e) = result {
+            eprintln!("frame dump to {} failed: {}", path, e);
+        }
+    }
+
+    fn vsync(&mut self) {
+        self.frames += 1;
+        self.upcurr = self.upbase;
+        self.ris |= INT_VCOMP | INT_BASE_UPDATE;
+        if matches!(self.dump_interval, Some(interval) if interval > 0 && self.frames % interval == 0) {
+            self.is_dump_pending = true;
+        }
+    }
+}
+
+impl Device for Lcd {
+    fn read(&mut self, offset: Word, width: BusWidth) -> Result<Word, BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        let value = match offset {
+            CLCD_TIMING0..=CLCD_TIMING3 => self.timing[(offset / 4) as usize],
+            CLCD_UPBASE => self.upbase,
+            CLCD_LPBASE => self.lpbase,
+            CLCD_CONTROL => self.control,
+            CLCD_IMSC => self.imsc,
+            CLCD_RIS => self.ris,
+            CLCD_MIS => self.ris & self.imsc,
+            CLCD_UPCURR => self.upcurr,
+            CLCD_LPCURR => self.lpbase,
+            0x200..=0x3FC => {
+                let index = ((offset - CLCD_PALETTE) / 2) as usize;
+                self.palette[index] as Word | (self.palette[index + 1] as Word) << 16
+            }
+            0xFE0..=0xFFC => PERIPH_ID[((offset - CLCD_PERIPH_ID0) / 4) as usize],
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: Word, value: Word, width: BusWidth) -> Result<(), BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        match offset {
+            CLCD_TIMING0..=CLCD_TIMING3 => self.timing[(offset / 4) as usize] = value,
+            CLCD_UPBASE => self.upbase = value & !0x7,
+            CLCD_LPBASE => self.lpbase = value & !0x7,
+            CLCD_CONTROL => {
+                if value & CONTROL_ENABLE != 0 && !self.is_enabled() {
+                    self.frame_cycles = 0;
+                    self.upcurr = self.upbase;
+                }
+                self.control = value & 0xFFFF;
+            }
+            CLCD_IMSC => self.imsc = value & INT_ALL,
+            CLCD_ICR => self.ris &= !value,
+            0x200..=0x3FC => {
+                let index = ((offset - CLCD_PALETTE) / 2) as usize;
+                self.palette[index] = value as u16;
+                self.palette[index + 1] = (value >> 16) as u16;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.frame_cycles += cycles;
+        while self.frame_cycles >= self.cycles_per_frame {
+            self.frame_cycles -= self.cycles_per_frame;
+            self.vsync();
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.is_enabled().then(|| self.cycles_per_frame - self.frame_cycles)
+    }
+
+    fn interrupt(&self) -> bool {
+        self.ris & self.imsc != 0
+    }
+
+    fn master(&mut self, bus: &mut dyn Bus) -> u64 {
+        if self.dump_trigger.take() || std::mem::take(&mut self.is_dump_pending) {
+            self.dump(bus);
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_map::*;
+
+    #[test]
+    fn dumps_and_captures_frames() {
+        let prefix = std::env::temp_dir().join("armv4t_lcd_test_");
+        let prefix = prefix.to_str().unwrap();
+        let mut map = MemoryMap::new();
+        map.add_ram("ram", 0, 0x100000);
+        let mut lcd = Lcd::new(1000, prefix, ImageFormat::Png);
+        lcd.configure(0x10000, 32, 2, PixelFormat::Rgb565);
+        lcd.dump_interval = Some(1);
+        let trigger = lcd.dump_trigger();
+        map.add_device("lcd", 0x3000_0000, 0x1000, Box::new(lcd));
+        // pixel (0, 0) full red, pixel (1, 0) full green
+        map.load(0x10000, &[0x1F, 0x00, 0xE0, 0x07]).unwrap();
+
+        map.tick(999);
+        map.tick(1);
+        let path = format!("{}00001.png", prefix);
+        let png = std::fs::read(&path).unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+        let _ = std::fs::remove_file(&path);
+        trigger.set(true);
+        map.tick(1);
+        assert!(!trigger.get());
+        assert!(std::path::Path::new(&path).exists());
+        let _ = std::fs::remove_file(&path);
+
+        let mut lcd = Lcd::new(1000, "", ImageFormat::Ppm);
+        lcd.configure(0x10000, 32, 2, PixelFormat::Rgb565);
+        let frame = lcd.capture(&mut map).unwrap();
+        assert_eq!(&frame.pixels[0..6], &[255, 0, 0, 0, 255, 0]);
+    }
+}
@@ -0,0 +1,544 @@
+use crate::armv4t::*;
+use crate::elf::ElfImage;
+use crate::semihosting::{read_bytes, read_string, read_words, write_bytes};
+use crate::sparse_memory::{SparseMemory, PAGE_SIZE};
+use crate::uart::UartTx;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub const SYS_EXIT: Word = 1;
+pub const SYS_READ: Word = 3;
+pub const SYS_WRITE: Word = 4;
+pub const SYS_OPEN: Word = 5;
+pub const SYS_CLOSE: Word = 6;
+pub const SYS_BRK: Word = 45;
+pub const SYS_IOCTL: Word = 54;
+pub const SYS_GETTIMEOFDAY: Word = 78;
+/// Old mmap, with its six arguments in a block in memory.
+pub const SYS_MMAP: Word = 90;
+pub const SYS_MUNMAP: Word = 91;
+pub const SYS_UNAME: Word = 122;
+pub const SYS_WRITEV: Word = 146;
+/// mmap with the file offset in pages.
+pub const SYS_MMAP2: Word = 192;
+pub const SYS_EXIT_GROUP: Word = 248;
+pub const SYS_SET_TID_ADDRESS: Word = 256;
+/// ARM private: set the thread pointer.
+pub const SYS_SET_TLS: Word = 0xF0005;
+
+/// OABI system calls are `swi 0x900000 + number`.
+const OABI_BASE: Word = 0x900000;
+/// Most buffers a single `writev` takes, as Linux's UIO_MAXIOV.
+const MAX_IOVEC: Word = 1024;
+
+const ENOENT: Word = 2;
+const EBADF: Word = 9;
+const ENOMEM: Word = 12;
+const EFAULT: Word = 14;
+const EINVAL: Word = 22;
+const ENOTTY: Word = 25;
+const ENOSYS: Word = 38;
+
+const O_ACCMODE: Word = 0x3;
+const O_CREAT: Word = 0x40;
+const O_TRUNC: Word = 0x200;
+const O_APPEND: Word = 0x400;
+
+const MAP_FIXED: Word = 0x10;
+const MAP_ANONYMOUS: Word = 0x20;
+
+/// Largest transfer between guest and host memory in one go.
+const CHUNK: usize = 0x1000;
+
+/// The initial stack grows down from here.
+pub const STACK_TOP: Word = 0xC000_0000;
+/// mmap hands out addresses upwards from here.
+pub const MMAP_BASE: Word = 0x4000_0000;
+
+/// Kernel user helpers for cores without a TLS register or atomics.
+const KUSER_MEMORY_BARRIER: Word = 0xFFFF_0FA0;
+const KUSER_CMPXCHG: Word = 0xFFFF_0FC0;
+const KUSER_GET_TLS: Word = 0xFFFF_0FE0;
+/// Where the kernel keeps the thread pointer for `__kuser_get_tls`.
+const KUSER_TLS: Word = 0xFFFF_0FF0;
+const KUSER_HELPER_VERSION: Word = 0xFFFF_0FFC;
+
+const AT_NULL: Word = 0;
+const AT_PHDR: Word = 3;
+const AT_PHENT: Word = 4;
+const AT_PHNUM: Word = 5;
+const AT_PAGESZ: Word = 6;
+const AT_BASE: Word = 7;
+const AT_ENTRY: Word = 9;
+const AT_UID: Word = 11;
+const AT_EUID: Word = 12;
+const AT_GID: Word = 13;
+const AT_EGID: Word = 14;
+const AT_HWCAP: Word = 16;
+const AT_CLKTCK: Word = 17;
+const AT_RANDOM: Word = 25;
+
+/// HWCAP_SWP | HWCAP_HALF: no Thumb, as the core does not decode it.
+const HWCAP: Word = 0x3;
+
+pub enum LinuxFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(std::fs::File),
+}
+
+/// Runs a static ARM Linux program in User mode, the way qemu-user does:
+/// system calls made with `swi 0` (EABI, number in r7) or `swi 0x900000 + n`
+/// (OABI, also accepted from EABI programs) are carried out on the host
+/// instead of taking the exception.
+pub struct LinuxUser {
+    /// Receives writes to standard output.
+    pub stdout: UartTx,
+    /// Open file descriptors; 0 to 2 are the standard streams.
+    pub files: Vec<Option<LinuxFile>>,
+    /// Initial and current program break.
+    pub brk_start: Word,
+    pub brk: Word,
+    pub mmap_next: Word,
+    /// The program was built for the EABI; OABI programs never use `swi 0`.
+    pub is_eabi: bool,
+}
+
+impl LinuxUser {
+    pub fn new(stdout: UartTx, brk: Word) -> LinuxUser {
+        LinuxUser {
+            stdout,
+            files: vec![Some(LinuxFile::Stdin), Some(LinuxFile::Stdout), Some(LinuxFile::Stderr)],
+            brk_start: brk,
+            brk,
+            mmap_next: MMAP_BASE,
+            is_eabi: true,
+        }
+    }
+
+    /// System call number of `raw_inst`, about to execute with `r7`, if it is a system call.
+    pub fn syscall_number(&self, raw_inst: Word, r7: Word) -> Option<Word> {
+        if raw_inst & 0x0F000000 != 0x0F000000 {
+            return None;
+        }
+        match raw_inst & 0x00FFFFFF {
+            0 if self.is_eabi => Some(r7),
+            comment if comment & 0xF00000 == OABI_BASE => Some(comment - OABI_BASE),
+            _ => None,
+        }
+    }
+
+    /// Performs system call `number` with the arguments in r0 to r5 and
+    /// returns the value for r0 (a negated error number on failure) and any
+    /// request for the host.
+    pub fn syscall(&mut self, number: Word, args: [Word; 6], bus: &mut dyn Bus) -> (Word, Option<SystemRequest>) {
+        match number {
+            SYS_EXIT | SYS_EXIT_GROUP => (0, Some(SystemRequest::Exit(args[0] as i32))),
+            _ => match self.service(number, args, bus) {
+                Ok(result) => (result, None),
+                Err(errno) => (errno.wrapping_neg(), None),
+            },
+        }
+    }
+
+    fn service(&mut self, number: Word, args: [Word; 6], bus: &mut dyn Bus) -> Result<Word, Word> {
+        match number {
+            SYS_READ => {
+                let mut data = [0; CHUNK];
+                let mut total = 0;
+                while total < args[2] {
+                    let chunk = (args[2] - total).min(CHUNK as Word) as usize;
+                    let read = match self.file(args[0])? {
+                        LinuxFile::Stdin => std::io::stdin().read(&mut data[..chunk]),
+                        LinuxFile::File(file) => file.read(&mut data[..chunk]),
+                        _ => return Err(EBADF),
+                    }
+                    .map_err(io_errno)?;
+                    write_bytes(bus, args[1].wrapping_add(total), &data[..read]).map_err(|_| EFAULT)?;
+                    total += read as Word;
+                    if read < chunk {
+                        break;
+                    }
+                }
+                Ok(total)
+            }
+            SYS_WRITE => self.write_from(bus, args[0], args[1], args[2]),
+            SYS_WRITEV => {
+                if args[2] > MAX_IOVEC {
+                    return Err(EINVAL);
+                }
+                let mut written = 0;
+                for i in 0..args[2] {
+                    let [base, length] = read_words::<2>(bus, args[1].wrapping_add(i.wrapping_mul(8))).map_err(|_| EFAULT)?;
+                    written += self.write_from(bus, args[0], base, length)?;
+                }
+                Ok(written)
+            }
+            SYS_OPEN => {
+                let path = String::from_utf8_lossy(&read_string(bus, args[0]).map_err(|_| EFAULT)?).into_owned();
+                let flags = args[1];
+                let mut options = std::fs::OpenOptions::new();
+                match flags & O_ACCMODE {
+                    0 => options.read(true),
+                    1 => options.write(true),
+                    _ => options.read(true).write(true),
+                };
+                options.create(flags & O_CREAT != 0).truncate(flags & O_TRUNC != 0).append(flags & O_APPEND != 0);
+                let file = options.open(path).map_err(io_errno)?;
+                Ok(self.insert(LinuxFile::File(file)))
+            }
+            SYS_CLOSE => {
+                self.file(args[0])?;
+                self.files[args[0] as usize] = None;
+                Ok(0)
+            }
+            SYS_BRK => {
+                // memory is sparse, so moving the break needs no allocation
+                if args[0] >= self.brk_start && args[0] < MMAP_BASE {
+                    self.brk = args[0];
+                }
+                Ok(self.brk)
+            }
+            SYS_IOCTL => {
+                self.file(args[0])?;
+                Err(ENOTTY)
+            }
+            SYS_GETTIMEOFDAY => {
+                if args[0] != 0 {
+                    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+                    let mut data = (now.as_secs() as Word).to_le_bytes().to_vec();
+                    data.extend_from_slice(&now.subsec_micros().to_le_bytes());
+                    write_bytes(bus, args[0], &data).map_err(|_| EFAULT)?;
+                }
+                Ok(0)
+            }
+            SYS_MMAP => {
+                let [addr, length, protection, flags, fd, offset] = read_words::<6>(bus, args[0]).map_err(|_| EFAULT)?;
+                self.mmap([addr, length, protection, flags, fd, offset], bus)
+            }
+            SYS_MMAP2 => {
+                let mut args = args;
+                args[5] = args[5].wrapping_mul(PAGE_SIZE as Word);
+                self.mmap(args, bus)
+            }
+            SYS_MUNMAP => Ok(0),
+            SYS_UNAME => {
+                // six NUL-padded 65-byte fields
+                let fields = ["Linux", "armv4t", "5.15.0", "#1", "armv4tl", ""];
+                let mut data = vec![0u8; 65 * fields.len()];
+                for (i, field) in fields.iter().enumerate() {
+                    data[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+                }
+                write_bytes(bus, args[0], &data).map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            SYS_SET_TID_ADDRESS => Ok(1),
+            SYS_SET_TLS => {
+                write_bytes(bus, KUSER_TLS, &args[0].to_le_bytes()).map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+
+    fn file(&mut self, fd: Word) -> Result<&mut LinuxFile, Word> {
+        self.files.get_mut(fd as usize).and_then(|file| file.as_mut()).ok_or(EBADF)
+    }
+
+    fn insert(&mut self, file: LinuxFile) -> Word {
+        match self.files.iter().position(|file| file.is_none()) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                fd as Word
+            }
+            None => {
+                self.files.push(Some(file));
+                self.files.len() as Word - 1
+            }
+        }
+    }
+
+    fn write(&mut self, fd: Word, data: &[u8]) -> Result<Word, Word> {
+        match self.file(fd)? {
+            LinuxFile::Stdout => data.iter().for_each(|byte| self.stdout.write_byte(*byte)),
+            LinuxFile::Stderr => std::io::stderr().write_all(data).map_err(io_errno)?,
+            LinuxFile::File(file) => file.write_all(data).map_err(io_errno)?,
+            LinuxFile::Stdin => return Err(EBADF),
+        }
+        Ok(data.len() as Word)
+    }
+
+    /// Writes `length` bytes of guest memory from `addr` to `fd`, a chunk at a time.
+    fn write_from(&mut self, bus: &mut dyn Bus, fd: Word, addr: Word, length: Word) -> Result<Word, Word> {
+        let mut written = 0;
+        while written < length {
+            let chunk = (length - written).min(CHUNK as Word);
+            let data = read_bytes(bus, addr.wrapping_add(written), chunk as usize).map_err(|_| EFAULT)?;
+            written += self.write(fd, &data)?;
+        }
+        Ok(written)
+    }
+
+    /// Maps `length` bytes, zeroed or read from a file, with `offset` in bytes.
+    /// Addresses from `mmap_next` have never been written and already read as
+    /// zero, so only `MAP_FIXED` mappings are cleared.
+    fn mmap(&mut self, args: [Word; 6], bus: &mut dyn Bus) -> Result<Word, Word> {
+        let [addr, length, _protection, flags, fd, offset] = args;
+        if length == 0 {
+            return Err(EINVAL);
+        }
+        let length = length.checked_next_multiple_of(PAGE_SIZE as Word).ok_or(ENOMEM)?;
+        let addr = if flags & MAP_FIXED != 0 {
+            addr
+        }
+        else {
+            let addr = self.mmap_next;
+            self.mmap_next = addr.checked_add(length).filter(|end| *end <= STACK_TOP - 0x100_0000).ok_or(ENOMEM)?;
+            addr
+        };
+        let mut data = [0; CHUNK];
+        let mut filled = 0;
+        if flags & MAP_ANONYMOUS == 0 {
+            let LinuxFile::File(file) = self.file(fd)? else {
+                return Err(EBADF);
+            };
+            let position = file.stream_position().map_err(io_errno)?;
+            file.seek(SeekFrom::Start(offset as u64)).map_err(io_errno)?;
+            while filled < length {
+                let chunk = (length - filled).min(CHUNK as Word) as usize;
+                let read = file.read(&mut data[..chunk]).map_err(io_errno)?;
+                if read == 0 {
+                    break;
+                }
+                write_bytes(bus, addr.wrapping_add(filled), &data[..read]).map_err(|_| EFAULT)?;
+                filled += read as Word;
+            }
+            file.seek(SeekFrom::Start(position)).map_err(io_errno)?;
+        }
+        if flags & MAP_FIXED != 0 {
+            data.fill(0);
+            while filled < length {
+                let chunk = (length - filled).min(CHUNK as Word);
+                write_bytes(bus, addr.wrapping_add(filled), &data[..chunk as usize]).map_err(|_| EFAULT)?;
+                filled += chunk;
+            }
+        }
+        Ok(addr)
+    }
+}
+
+fn io_errno(error: std::io::Error) -> Word {
+    match error.raw_os_error() {
+        Some(errno) => errno as Word,
+        None if error.kind() == std::io::ErrorKind::NotFound => ENOENT,
+        None => EINVAL,
+    }
+}
+
+/// Maps the kernel user helpers that ARMv4T C libraries call for the
+/// thread pointer and atomic compare-and-swap.
+fn map_kuser_helpers(memory: &mut SparseMemory) {
+    // bx lr
+    memory.load(KUSER_MEMORY_BARRIER, &0xE12FFF1Eu32.to_le_bytes());
+    // ldr r3, [r2]; subs r3, r3, r0; streq r1, [r2]; rsbs r0, r3, #0; bx lr
+    let cmpxchg: [Word; 5] = [0xE5923000, 0xE0533000, 0x05821000, 0xE2730000, 0xE12FFF1E];
+    for (i, inst) in cmpxchg.iter().enumerate() {
+        memory.load(KUSER_CMPXCHG + i as Word * 4, &inst.to_le_bytes());
+    }
+    // ldr r0, [pc, #8]; bx lr
+    memory.load(KUSER_GET_TLS, &0xE59F0008u32.to_le_bytes());
+    memory.load(KUSER_GET_TLS + 4, &0xE12FFF1Eu32.to_le_bytes());
+    memory.load(KUSER_HELPER_VERSION, &3u32.to_le_bytes());
+}
+
+/// Builds the initial stack below `STACK_TOP`: argc, argv, envp and the
+/// auxiliary vector, with the strings above them. Returns the stack pointer.
+fn build_stack(memory: &mut SparseMemory, image: &ElfImage, args: &[String], env: &[String]) -> Word {
+    let mut top = STACK_TOP;
+    let mut push_bytes = |memory: &mut SparseMemory, bytes: &[u8]| {
+        top -= bytes.len() as Word;
+        memory.load(top, bytes);
+        top
+    };
+    let random = push_bytes(memory, &std::process::id().to_le_bytes().repeat(4));
+    let mut string = |memory: &mut SparseMemory, value: &String| {
+        let mut bytes = value.clone().into_bytes();
+        bytes.push(0);
+        push_bytes(memory, &bytes)
+    };
+    let argv: Vec<Word> = args.iter().map(|arg| string(memory, arg)).collect();
+    let envp: Vec<Word> = env.iter().map(|var| string(memory, var)).collect();
+
+    let auxv = [
+        (AT_PHDR, image.phdr_address().unwrap_or(0)),
+        (AT_PHENT, image.phentsize as Word),
+        (AT_PHNUM, image.phnum as Word),
+        (AT_PAGESZ, PAGE_SIZE as Word),
+        (AT_BASE, 0),
+        (AT_ENTRY, image.entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_HWCAP, HWCAP),
+        (AT_CLKTCK, 100),
+        (AT_RANDOM, random),
+        (AT_NULL, 0),
+    ];
+    let mut words = vec![argv.len() as Word];
+    words.extend_from_slice(&argv);
+    words.push(0);
+    words.extend_from_slice(&envp);
+    words.push(0);
+    for (key, value) in auxv {
+        words.extend_from_slice(&[key, value]);
+    }
+
+    // the ABI wants an 8-byte aligned stack at entry
+    let sp = (top - words.len() as Word * 4) & !0x7;
+    let data: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    memory.load(sp, &data);
+    sp
+}
+
+/// Loads the static executable `data` and returns a core about to run its
+/// entry point in User mode with `args` and `env`, writing standard output to `stdout`.
+pub fn load(data: &[u8], args: &[String], env: &[String], stdout: UartTx) -> Result<ARMv4T<SparseMemory>, crate::elf::ElfError> {
+    let image = ElfImage::parse(data)?;
+    let mut memory = SparseMemory::new();
+    image.load(data, &mut memory);
+    map_kuser_helpers(&mut memory);
+    let sp = build_stack(&mut memory, &image, args, env);
+
+    let mut cpu = ARMv4T::new(memory);
+    cpu.reset();
+    cpu.set_mode(ProcessorMode::User(0));
+    cpu.cpsr.i = 0;
+    cpu.cpsr.f = 0;
+    cpu.set_gpr(13, sp);
+    cpu.set_gpr(15, image.entry);
+    let brk = image.end().checked_next_multiple_of(PAGE_SIZE as Word).ok_or(crate::elf::ElfError::Format("segments reach the end of the address space"))?;
+    let mut linux = LinuxUser::new(stdout, brk);
+    linux.is_eabi = image.is_eabi;
+    cpu.linux = Some(linux);
+    Ok(cpu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const LOAD_ADDRESS: Word = 0x10000;
+    const HEADERS: usize = 52 + 32;
+
+    /// A static EABI executable with one segment holding the headers and `code`.
+    fn executable(code: &[Word]) -> Vec<u8> {
+        let mut elf = vec![0u8; HEADERS];
+        elf[0..4].copy_from_slice(b"\x7fELF");
+        // 32-bit, little-endian, version 1
+        elf[4..7].copy_from_slice(&[1, 1, 1]);
+        elf[16..18].copy_from_slice(&2u16.to_le_bytes());
+        elf[18..20].copy_from_slice(&40u16.to_le_bytes());
+        elf[24..28].copy_from_slice(&(LOAD_ADDRESS + HEADERS as Word).to_le_bytes());
+        elf[28..32].copy_from_slice(&52u32.to_le_bytes());
+        elf[36..40].copy_from_slice(&0x05000000u32.to_le_bytes());
+        elf[42..44].copy_from_slice(&32u16.to_le_bytes());
+        elf[44..46].copy_from_slice(&1u16.to_le_bytes());
+        let size = (HEADERS + code.len() * 4) as Word;
+        let program_header = [1, 0, LOAD_ADDRESS, LOAD_ADDRESS, size, size + 0x100, 5, 0x1000];
+        for (i, word) in program_header.iter().enumerate() {
+            elf[52 + i * 4..56 + i * 4].copy_from_slice(&word.to_le_bytes());
+        }
+        for word in code {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        elf
+    }
+
+    #[test]
+    fn runs_a_static_executable() {
+        let elf = executable(&[
+            0xE59D4000, // ldr r4, [sp]             argc
+            0xE59D1008, // ldr r1, [sp, #8]         argv[1]
+            0xE3A02002, // mov r2, #2
+            0xE3A07004, // mov r7, #SYS_WRITE
+            0xE3A00001, // mov r0, #1
+            0xEF000000, // swi 0
+            0xE3A00000, // mov r0, #0
+            0xE3A0702D, // mov r7, #SYS_BRK
+            0xEF000000, // swi 0
+            0xE1A05000, // mov r5, r0
+            0xE3A00A01, // mov r0, #0x1000
+            0xE3A0780F, // mov r7, #0xF0000
+            0xE2877005, // add r7, r7, #5           SYS_SET_TLS
+            0xEF000000, // swi 0
+            0xE3E03A0F, // mvn r3, #0xF000
+            0xE243301F, // sub r3, r3, #0x1F        KUSER_GET_TLS
+            0xE1A0E00F, // mov lr, pc
+            0xE12FFF13, // bx r3
+            0xE1A06000, // mov r6, r0
+            0xE1A00004, // mov r0, r4
+            0xEF9000F8, // swi 0x9000F8             old ABI SYS_EXIT_GROUP
+        ]);
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = load(&elf, &["prog".into(), "hi".into()], &["A=B".into()], UartTx::Buffer(output.clone())).unwrap();
+        cpu.run(10000).unwrap();
+        assert_eq!(cpu.system_request, Some(SystemRequest::Exit(2)));
+        assert_eq!(&output.borrow()[..], b"hi");
+        assert_eq!(cpu.get_gpr(5), 0x11000);
+        assert_eq!(cpu.get_gpr(6), 0x1000);
+        assert!(cpu.mode == ProcessorMode::User(0));
+        assert_eq!(ElfImage::parse(&elf[..40]), Err(crate::elf::ElfError::Truncated));
+    }
+
+    #[test]
+    fn headers_near_the_end_of_the_address_space() {
+        let mut elf = executable(&[0xEAFFFFFE]);
+        // move the segment, and so the heap, to the last page
+        elf[60..64].copy_from_slice(&0xFFFFF000u32.to_le_bytes());
+        let result = load(&elf, &[], &[], UartTx::Buffer(Rc::new(RefCell::new(Vec::new()))));
+        assert!(matches!(result, Err(crate::elf::ElfError::Format(_))));
+
+        let image = ElfImage::parse(&elf).unwrap();
+        let segment = crate::elf::Segment { offset: 0x10, vaddr: 0x1000, file_size: 0xFFFFFFF8, memory_size: 0 };
+        let image = ElfImage { phoff: 0xFFFFFFF0, segments: vec![segment], ..image };
+        assert_eq!(image.phdr_address(), Some(0xFE0));
+    }
+
+    #[test]
+    fn oabi_programs_pass_the_number_in_the_comment() {
+        let mut linux = LinuxUser::new(UartTx::Buffer(Rc::new(RefCell::new(Vec::new()))), 0x20000);
+        assert_eq!(linux.syscall_number(0xEF000000, SYS_WRITE), Some(SYS_WRITE));
+        assert_eq!(linux.syscall_number(0xEF900004, 0), Some(SYS_WRITE));
+        linux.is_eabi = false;
+        assert_eq!(linux.syscall_number(0xEF000000, SYS_WRITE), None);
+        assert_eq!(linux.syscall_number(0xEF900004, 0), Some(SYS_WRITE));
+    }
+
+    #[test]
+    fn large_transfers_and_mappings_stay_sparse() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut linux = LinuxUser::new(UartTx::Buffer(output.clone()), 0x20000);
+        let mut memory = SparseMemory::new();
+
+        // anonymous mappings are not written to
+        let args = [0, 0x1000_0000, 3, MAP_ANONYMOUS, Word::MAX, 0];
+        assert_eq!(linux.syscall(SYS_MMAP2, args, &mut memory), (MMAP_BASE, None));
+        assert!(memory.populated_pages().is_empty());
+
+        // writes larger than a chunk arrive whole
+        memory.load(0x8000, &[b'x'; 0x2800]);
+        assert_eq!(linux.syscall(SYS_WRITE, [1, 0x8000, 0x2800, 0, 0, 0], &mut memory), (0x2800, None));
+        assert_eq!(output.borrow().len(), 0x2800);
+        // writev takes at most UIO_MAXIOV buffers
+        assert_eq!(linux.syscall(SYS_WRITEV, [1, 0x8000, Word::MAX, 0, 0, 0], &mut memory), (EINVAL.wrapping_neg(), None));
+
+        // fixed mappings clear what was there
+        let args = [0x8000, 0x1000, 3, MAP_ANONYMOUS | MAP_FIXED, Word::MAX, 0];
+        assert_eq!(linux.syscall(SYS_MMAP2, args, &mut memory), (0x8000, None));
+        assert_eq!(memory.read_byte(0x8FFF), 0);
+        assert_eq!(memory.read_byte(0x9000), b'x');
+    }
+}
@@ -2,58 +2,123 @@
 
 
 mod armv4t;
+#[allow(dead_code)]
+mod block;
+#[allow(dead_code)]
+mod cache;
+#[allow(dead_code)]
+mod device;
+#[allow(dead_code)]
+mod dma;
+#[allow(dead_code)]
+mod elf;
+#[allow(dead_code)]
+mod flash;
+#[allow(dead_code)]
+mod image;
+#[allow(dead_code)]
+mod lcd;
+#[allow(dead_code)]
+mod linux_user;
+#[allow(dead_code)]
+mod memory_map;
+#[allow(dead_code)]
+mod semihosting;
+#[allow(dead_code)]
+mod sim_control;
+#[allow(dead_code)]
+mod sparse_memory;
+#[allow(dead_code)]
+mod timer;
+#[allow(dead_code)]
+mod trace;
+#[allow(dead_code)]
+mod uart;
+#[allow(dead_code)]
+mod vic;
+#[allow(dead_code)]
+mod wait_state;
+#[allow(dead_code)]
+mod watchdog;
 use armv4t::*;
+use memory_map::*;
+use semihosting::*;
+use sim_control::*;
+use uart::*;
 
-struct MyMemory{
-    memory: [u8; 0x10000],
-}
 
-
-impl Bus for MyMemory{
-    fn access(&mut self, addr: u32, data: &mut u32, r: BusRW) -> Result<u32, ()>{
-        match r{
-            BusRW::Read => {
-                *data = self.memory[addr as usize] as u32 + 
-                ((self.memory[(addr + 1) as usize] as u32) << 8) + 
-                ((self.memory[(addr + 2) as usize] as u32) << 16) + 
-                ((self.memory[(addr + 3) as usize] as u32) << 24);
-            }
-            BusRW::Write => {
-                self.memory[addr as usize] = (*data & 0xFF) as u8;
-                self.memory[(addr + 1) as usize] = ((*data >> 8) & 0xFF) as u8;
-                self.memory[(addr + 2) as usize] = ((*data >> 16) & 0xFF) as u8;
-                self.memory[(addr + 3) as usize] = ((*data >> 24) & 0xFF) as u8;
-            }
+/// `--linux <elf> [args...]`: runs a static ARM Linux executable in User mode.
+fn run_linux(args: &[String]) -> ! {
+    let data = std::fs::read(&args[0]).unwrap();
+    let env: Vec<String> = std::env::vars().map(|(key, value)| format!("{}={}", key, value)).collect();
+    let mut cpu = match linux_user::load(&data, args, &env, UartTx::Stdout) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            eprintln!("{}: {}", args[0], e);
+            std::process::exit(1);
         }
-        return Ok(0);
-    }
-}
-
-impl MyMemory{
-    fn new() -> MyMemory{
-        MyMemory{
-            memory: [0; 0x10000],
+    };
+    loop {
+        if let Err(e) = cpu.run(1_000_000) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        if let Some(abort) = cpu.abort.take() {
+            eprintln!("{}", abort);
+            std::process::exit(1);
+        }
+        if let Some(SystemRequest::Exit(code)) = cpu.system_request.take() {
+            std::process::exit(code);
         }
-    }
-    fn load(&mut self, addr: u32, data: u8){
-        self.memory[addr as usize] = data;
     }
 }
 
-
 fn main() {
-    let mut mem = MyMemory::new();
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 2 && args[1] == "--linux" {
+        run_linux(&args[2..]);
+    }
+    // `--trace` dumps the CPU state to stderr around every step
+    let trace = args[1..].iter().any(|arg| arg == "--trace");
+
+    let mut mem = MemoryMap::new();
+    mem.add_ram("ram", 0x0000_0000, 0x10000);
+    // semihosting reads from the same stdin as the UART
+    let stdin = UartRx::stdin();
+    mem.add_device("uart0", 0x101F_1000, 0x1000, Box::new(Uart::new(UartTx::Stdout, stdin.clone())));
+    mem.add_device("sim_control", 0x1000_0000, 0x1000, Box::new(SimControl::new(UartTx::Stdout)));
     let filename = "program.bin";
     let program = std::fs::read(filename).unwrap();
-    for i in 0..program.len(){
-        mem.load(i as u32, program[i]);
-    }
-    let mut cpu = ARMv4T::<MyMemory>::new(mem);
+    mem.load(0x0000_0000, &program).unwrap();
+    let mut cpu = ARMv4T::<MemoryMap>::new(mem);
+    let mut semihosting = Semihosting::new(UartTx::Stdout);
+    semihosting.console_input = stdin;
+    semihosting.cmdline = filename.to_string();
+    semihosting.heap_info = HeapInfo { heap_base: 0x8000, heap_limit: 0xC000, stack_base: 0x10000, stack_limit: 0xC000 };
+    cpu.semihosting = Some(semihosting);
     cpu.reset();
-    println!("{}", cpu);
+    if trace {
+        eprintln!("{}", cpu);
+    }
 
     loop{
-        cpu.step();
-        println!("{}", cpu);
+        if let Err(e) = cpu.step() {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        if let Some(abort) = cpu.abort.take() {
+            eprintln!("{}", abort);
+        }
+        if trace {
+            eprintln!("{}", cpu);
+        }
+        match cpu.system_request.take() {
+            Some(SystemRequest::Exit(code)) => std::process::exit(code),
+            Some(SystemRequest::AssertionFailure(id)) => {
+                eprintln!("guest assertion failed: 0x{:08x}", id);
+                std::process::exit(1);
+            }
+            Some(SystemRequest::Reset) | None => (),
+        }
     }
 }
@@ -0,0 +1,512 @@
+use crate::armv4t::*;
+use crate::device::*;
+
+/// How many mirrors an access may pass through before it is reported as unmapped.
+const MAX_ALIAS_DEPTH: usize = 8;
+
+pub enum RegionKind {
+    Ram(Vec<u8>),
+    /// Read-only; writes are ignored.
+    Rom(Vec<u8>),
+    /// Forwards `start + offset` to `target + offset % period`.
+    Mirror { target: Word, period: Word },
+    /// Forwards accesses to a bus with addresses relative to the region start.
+    /// Mark it `side_effects` if the bus is not plain memory.
+    Mmio(Box<dyn Bus>),
+    Device(MappedDevice),
+}
+
+/// What a region allows. Checked on the region an access finally lands in,
+/// after mirrors are followed.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Attributes {
+    pub readable: bool,
+    pub writable: bool,
+    /// Instructions may be fetched from it.
+    pub executable: bool,
+    /// Reading it can change state, so debuggers must not peek at it.
+    pub side_effects: bool,
+}
+
+impl Attributes {
+    pub const RAM: Attributes = Attributes { readable: true, writable: true, executable: true, side_effects: false };
+    pub const ROM: Attributes = Attributes { readable: true, writable: false, executable: true, side_effects: false };
+    /// Readable and writable data that must not be executed.
+    pub const DATA: Attributes = Attributes { readable: true, writable: true, executable: false, side_effects: false };
+    pub const DEVICE: Attributes = Attributes { readable: true, writable: true, executable: false, side_effects: true };
+}
+
+/// What happens to a write to a region that is not writable.
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub enum WriteProtection {
+    /// The write completes without changing anything.
+    #[default]
+    Ignore,
+    /// The write fails with `BusError::Permission`, which the CPU takes as a data abort.
+    Fault,
+}
+
+pub struct Region {
+    pub name: String,
+    pub start: Word,
+    pub size: Word,
+    /// On overlap the region with the highest priority wins; among equal
+    /// priorities the one added last wins.
+    pub priority: i32,
+    pub attributes: Attributes,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub fn contains(&self, addr: Word) -> bool {
+        addr.wrapping_sub(self.start) < self.size
+    }
+}
+
+/// A bus that routes every access to the region mapped at its address.
+#[derive(Default)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+    /// Cycles bus-master devices held the bus for, not yet claimed by the CPU.
+    pub stolen_cycles: u64,
+    pub write_protection: WriteProtection,
+}
+
+/// Stands in for a bus-master device while it runs, so that it can use the
+/// map as its bus. Accesses to its own registers during that time fail.
+struct Detached;
+
+impl Device for Detached {
+    fn read(&mut self, offset: Word, _width: BusWidth) -> Result<Word, BusError> {
+        Err(BusError::Device(offset))
+    }
+
+    fn write(&mut self, offset: Word, _value: Word, _width: BusWidth) -> Result<(), BusError> {
+        Err(BusError::Device(offset))
+    }
+}
+
+impl MemoryMap {
+    pub fn new() -> MemoryMap {
+        MemoryMap { regions: Vec::new(), stolen_cycles: 0, write_protection: WriteProtection::Ignore }
+    }
+
+    /// Maps `kind` at `start..start + size` and returns its region index.
+    /// ROM is read-only and devices are not executable; everything else allows all accesses.
+    pub fn add_region(&mut self, name: &str, start: Word, size: Word, priority: i32, kind: RegionKind) -> usize {
+        let attributes = match kind {
+            RegionKind::Rom(_) => Attributes::ROM,
+            RegionKind::Device(_) => Attributes::DEVICE,
+            _ => Attributes::RAM,
+        };
+        self.regions.push(Region {
+            name: name.to_string(),
+            start,
+            size,
+            priority,
+            attributes,
+            kind,
+        });
+        self.regions.len() - 1
+    }
+
+    pub fn add_ram(&mut self, name: &str, start: Word, size: Word) -> usize {
+        self.add_region(name, start, size, 0, RegionKind::Ram(vec![0; size as usize]))
+    }
+
+    pub fn add_rom(&mut self, name: &str, start: Word, image: Vec<u8>) -> usize {
+        let size = image.len() as Word;
+        self.add_region(name, start, size, 0, RegionKind::Rom(image))
+    }
+
+    pub fn add_mirror(&mut self, name: &str, start: Word, size: Word, target: Word, period: Word) -> usize {
+        self.add_region(name, start, size, 0, RegionKind::Mirror { target, period })
+    }
+
+    pub fn add_mmio(&mut self, name: &str, start: Word, size: Word, bus: Box<dyn Bus>) -> usize {
+        self.add_region(name, start, size, 0, RegionKind::Mmio(bus))
+    }
+
+    pub fn add_device(&mut self, name: &str, start: Word, size: Word, device: Box<dyn Device>) -> usize {
+        let device = MappedDevice { device, route: InterruptRoute::None, dma: None };
+        self.add_region(name, start, size, 0, RegionKind::Device(device))
+    }
+
+    /// Connects the interrupt output of the device mapped as `region`.
+    /// Controllers have 32 sources, so `source` must be below 32.
+    pub fn route_interrupt(&mut self, region: usize, route: InterruptRoute) {
+        if let InterruptRoute::Controller { source, .. } = route {
+            assert!(source < 32, "interrupt source {} out of range", source);
+        }
+        if let RegionKind::Device(mapped) = &mut self.regions[region].kind {
+            mapped.route = route;
+        }
+    }
+
+    /// Connects the DMA request lines of the device mapped as `region`.
+    pub fn route_dma(&mut self, region: usize, route: DmaRoute) {
+        if let RegionKind::Device(mapped) = &mut self.regions[region].kind {
+            mapped.dma = Some(route);
+        }
+    }
+
+    pub fn set_priority(&mut self, region: usize, priority: i32) {
+        self.regions[region].priority = priority;
+    }
+
+    /// Devices that hold code, such as NOR flash, need `executable` set here.
+    pub fn set_attributes(&mut self, region: usize, attributes: Attributes) {
+        self.regions[region].attributes = attributes;
+    }
+
+    /// Index of the region that answers at `addr`, ignoring mirrors.
+    pub fn find(&self, addr: Word) -> Option<usize> {
+        let mut found: Option<usize> = None;
+        for (index, region) in self.regions.iter().enumerate() {
+            if !region.contains(addr) {
+                continue;
+            }
+            match found {
+                Some(current) if self.regions[current].priority > region.priority => (),
+                _ => found = Some(index),
+            }
+        }
+        found
+    }
+
+    /// Passes the request line levels of every device with a DMA route to its controller.
+    fn update_dma_requests(&mut self) {
+        let mut controllers: Vec<(usize, Word)> = Vec::new();
+        for region in self.regions.iter() {
+            if let RegionKind::Device(mapped) = &region.kind {
+                if let Some(DmaRoute { controller, line }) = mapped.dma {
+                    let requests = mapped.device.dma_requests() << line;
+                    match controllers.iter_mut().find(|(index, _)| *index == controller) {
+                        Some((_, levels)) => *levels |= requests,
+                        None => controllers.push((controller, requests)),
+                    }
+                }
+            }
+        }
+        for (controller, requests) in controllers {
+            if let RegionKind::Device(mapped) = &mut self.regions[controller].kind {
+                mapped.device.set_dma_requests(requests);
+            }
+        }
+    }
+
+    /// Follows mirrors from `addr` and returns the final region index and offset into it.
+    pub fn resolve(&self, addr: Word) -> Option<(usize, Word)> {
+        let mut addr = addr;
+        for _ in 0..MAX_ALIAS_DEPTH {
+            let index = self.find(addr)?;
+            let region = &self.regions[index];
+            let offset = addr - region.start;
+            match region.kind {
+                RegionKind::Mirror { target, period } => {
+                    addr = target.wrapping_add(if period == 0 { offset } else { offset % period });
+                }
+                _ => return Some((index, offset)),
+            }
+        }
+        None
+    }
+
+    /// Copies `data` into RAM or ROM starting at `addr`, bypassing write protection.
+    pub fn load(&mut self, addr: Word, data: &[u8]) -> Result<(), BusError> {
+        for (i, byte) in data.iter().enumerate() {
+            let addr = addr.wrapping_add(i as Word);
+            let (index, offset) = self.resolve(addr).ok_or(BusError::Unmapped(addr))?;
+            match &mut self.regions[index].kind {
+                RegionKind::Ram(memory) | RegionKind::Rom(memory) if (offset as usize) < memory.len() => {
+                    memory[offset as usize] = *byte;
+                }
+                _ => return Err(BusError::Unmapped(addr)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Bus for MemoryMap {
+    fn access(&mut self, addr: Word, data: &mut Word, rw: BusRW, cycle: BusCycle) -> BusState {
+        self.transfer(&BusRequest::word(addr, rw, cycle), data)
+    }
+
+    fn transfer(&mut self, request: &BusRequest, data: &mut Word) -> BusState {
+        let (index, offset) = self.resolve(request.addr).ok_or(BusError::Unmapped(request.addr))?;
+        let bytes = request.width.bytes() as usize;
+        let attributes = self.regions[index].attributes;
+        let is_allowed = match (request.kind, request.rw) {
+            (BusKind::Fetch, _) => attributes.executable,
+            (_, BusRW::Read) => attributes.readable,
+            (_, BusRW::Write) => attributes.writable,
+        };
+        if !is_allowed {
+            if request.rw == BusRW::Write && self.write_protection == WriteProtection::Ignore {
+                return Ok(0);
+            }
+            return Err(BusError::Permission(request.addr));
+        }
+        match &mut self.regions[index].kind {
+            RegionKind::Ram(memory) | RegionKind::Rom(memory) if offset as usize + bytes > memory.len() => {
+                Err(BusError::Unmapped(request.addr))
+            }
+            RegionKind::Ram(memory) | RegionKind::Rom(memory) => {
+                match request.rw {
+                    BusRW::Read => *data = read_le(memory, offset as usize, bytes),
+                    BusRW::Write => write_le(memory, offset as usize, bytes, *data),
+                }
+                Ok(0)
+            }
+            RegionKind::Mmio(bus) => {
+                let mut request = *request;
+                request.addr = offset;
+                bus.transfer(&request, data)
+            }
+            RegionKind::Device(mapped) => {
+                match request.rw {
+                    BusRW::Read => *data = mapped.device.read(offset, request.width)?,
+                    BusRW::Write => mapped.device.write(offset, *data & request.width.mask(), request.width)?,
+                }
+                Ok(0)
+            }
+            RegionKind::Mirror { .. } => Err(BusError::Unmapped(request.addr)),
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        for region in self.regions.iter_mut() {
+            match &mut region.kind {
+                RegionKind::Mmio(bus) => bus.tick(cycles),
+                RegionKind::Device(mapped) => mapped.device.tick(cycles),
+                _ => (),
+            }
+        }
+
+        // bus masters run once everything else has caught up, with the whole map as their bus
+        self.update_dma_requests();
+        for index in 0..self.regions.len() {
+            let mut device = match &mut self.regions[index].kind {
+                RegionKind::Device(mapped) => std::mem::replace(&mut mapped.device, Box::new(Detached)),
+                _ => continue,
+            };
+            self.stolen_cycles += device.master(self);
+            if let RegionKind::Device(mapped) = &mut self.regions[index].kind {
+                mapped.device = device;
+            }
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.regions
+            .iter()
+            .filter_map(|region| match &region.kind {
+                RegionKind::Mmio(bus) => bus.next_event(),
+                RegionKind::Device(mapped) => mapped.device.next_event(),
+                _ => None,
+            })
+            .min()
+    }
+
+    fn interrupt_lines(&mut self) -> InterruptLines {
+        // gather the source levels of every interrupt controller first
+        let mut controllers: Vec<(usize, Word)> = Vec::new();
+        for region in self.regions.iter() {
+            if let RegionKind::Device(mapped) = &region.kind {
+                if let InterruptRoute::Controller { controller, source } = mapped.route {
+                    let level = (mapped.device.interrupt() as Word) << source;
+                    match controllers.iter_mut().find(|(index, _)| *index == controller) {
+                        Some((_, sources)) => *sources |= level,
+                        None => controllers.push((controller, level)),
+                    }
+                }
+            }
+        }
+        for (controller, sources) in controllers {
+            if let RegionKind::Device(mapped) = &mut self.regions[controller].kind {
+                mapped.device.set_interrupt_sources(sources);
+            }
+        }
+
+        let mut lines = InterruptLines::default();
+        for region in self.regions.iter_mut() {
+            match &mut region.kind {
+                RegionKind::Mmio(bus) => {
+                    let region_lines = bus.interrupt_lines();
+                    lines.irq |= region_lines.irq;
+                    lines.fiq |= region_lines.fiq;
+                }
+                RegionKind::Device(mapped) => {
+                    lines.fiq |= mapped.device.fast_interrupt();
+                    if mapped.device.interrupt() {
+                        match mapped.route {
+                            InterruptRoute::Irq => lines.irq = true,
+                            InterruptRoute::Fiq => lines.fiq = true,
+                            _ => (),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        lines
+    }
+
+    fn stolen_cycles(&mut self) -> u64 {
+        let mut stolen = std::mem::take(&mut self.stolen_cycles);
+        for region in self.regions.iter_mut() {
+            if let RegionKind::Mmio(bus) = &mut region.kind {
+                stolen += bus.stolen_cycles();
+            }
+        }
+        stolen
+    }
+
+    fn system_request(&mut self) -> Option<SystemRequest> {
+        self.regions.iter_mut().find_map(|region| match &mut region.kind {
+            RegionKind::Mmio(bus) => bus.system_request(),
+            RegionKind::Device(mapped) => mapped.device.system_request(),
+            _ => None,
+        })
+    }
+
+    fn begin_instruction(&mut self, pc: Word) {
+        for region in self.regions.iter_mut() {
+            if let RegionKind::Mmio(bus) = &mut region.kind {
+                bus.begin_instruction(pc);
+            }
+        }
+    }
+
+    fn peek(&mut self, addr: Word, width: BusWidth) -> Option<Word> {
+        let (index, offset) = self.resolve(addr)?;
+        if self.regions[index].attributes.side_effects {
+            return None;
+        }
+        let bytes = width.bytes() as usize;
+        match &mut self.regions[index].kind {
+            RegionKind::Ram(memory) | RegionKind::Rom(memory) if offset as usize + bytes <= memory.len() => {
+                Some(read_le(memory, offset as usize, bytes))
+            }
+            RegionKind::Mmio(bus) => bus.peek(offset, width),
+            _ => None,
+        }
+    }
+}
+
+pub fn read_le(memory: &[u8], offset: usize, bytes: usize) -> Word {
+    let mut value: Word = 0;
+    for i in 0..bytes {
+        value |= (memory[offset + i] as Word) << (i * 8);
+    }
+    value
+}
+
+pub fn write_le(memory: &mut [u8], offset: usize, bytes: usize, value: Word) {
+    for i in 0..bytes {
+        memory[offset + i] = (value >> (i * 8)) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(map: &mut MemoryMap, addr: Word) -> Result<Word, BusError> {
+        let mut data = 0;
+        map.access(addr, &mut data, BusRW::Read, BusCycle::NonSequential).map(|_| data)
+    }
+
+    fn write(map: &mut MemoryMap, addr: Word, value: Word) {
+        let mut data = value;
+        map.access(addr, &mut data, BusRW::Write, BusCycle::NonSequential).unwrap();
+    }
+
+    #[test]
+    fn routes_ram_rom_and_mirrors() {
+        let mut map = MemoryMap::new();
+        map.add_ram("ram", 0, 0x1000);
+        let rom = map.add_rom("rom", 0x800, vec![1, 2, 3, 4]);
+        map.set_priority(rom, 1);
+        map.add_mirror("mirror", 0x10000, 0x10000, 0, 0x1000);
+
+        write(&mut map, 0x10, 0xAABBCCDD);
+        assert_eq!(read(&mut map, 0x11010), Ok(0xAABBCCDD));
+        assert_eq!(read(&mut map, 0x800), Ok(0x04030201));
+        let request = BusRequest {
+            addr: 0x13,
+            rw: BusRW::Read,
+            width: BusWidth::Byte,
+            kind: BusKind::Data,
+            privilege: Privilege::User,
+            cycle: BusCycle::NonSequential,
+        };
+        let mut data = 0;
+        map.transfer(&request, &mut data).unwrap();
+        assert_eq!(data, 0xAA);
+        assert_eq!(read(&mut map, 0x20000), Err(BusError::Unmapped(0x20000)));
+    }
+
+    /// Writes to ROM, then jumps into a non-executable data region.
+    fn attributes_cpu(protection: WriteProtection) -> ARMv4T<MemoryMap> {
+        let mut map = MemoryMap::new();
+        map.add_ram("ram", 0, 0x1000);
+        let data = map.add_ram("data", 0x8000, 0x1000);
+        map.set_attributes(data, Attributes::DATA);
+        map.add_rom("rom", 0x10000, vec![0xAA; 0x100]);
+        map.write_protection = protection;
+        let program: [Word; 15] = [
+            0xEA000006, // b 0x20
+            0,
+            0,
+            0xEAFFFFFE, // prefetch abort: b .
+            0xEAFFFFFE, // data abort: b .
+            0,
+            0,
+            0,
+            0xE3A01801, // mov r1, #0x10000
+            0xE3A00005, // mov r0, #5
+            0xE5810000, // str r0, [r1]
+            0xE5912000, // ldr r2, [r1]
+            0xE3A03902, // mov r3, #0x8000
+            0xE5830000, // str r0, [r3]
+            0xE1A0F003, // mov pc, r3
+        ];
+        for (i, inst) in program.iter().enumerate() {
+            map.load(i as Word * 4, &inst.to_le_bytes()).unwrap();
+        }
+        let mut cpu = ARMv4T::new(map);
+        cpu.reset();
+        cpu
+    }
+
+    fn run_to_abort(cpu: &mut ARMv4T<MemoryMap>) -> AbortReport {
+        for _ in 0..40 {
+            cpu.step().unwrap();
+            if let Some(abort) = cpu.abort.take() {
+                return abort;
+            }
+        }
+        panic!("no abort");
+    }
+
+    #[test]
+    fn ignored_rom_writes_and_prefetch_abort() {
+        let mut cpu = attributes_cpu(WriteProtection::Ignore);
+        let abort = run_to_abort(&mut cpu);
+        assert_eq!(cpu.get_gpr(2), 0xAAAAAAAA);
+        assert_eq!((abort.exception, abort.pc, abort.error), (Exception::PrefetchAbort, 0x8000, BusError::Permission(0x8000)));
+        assert_eq!(cpu.get_gpr(14), 0x8004);
+        assert_eq!(cpu.bus.peek(0x8000, BusWidth::Word), Some(5));
+    }
+
+    #[test]
+    fn faulting_rom_write_takes_data_abort() {
+        let mut cpu = attributes_cpu(WriteProtection::Fault);
+        let abort = run_to_abort(&mut cpu);
+        assert_eq!((abort.exception, abort.pc, abort.error), (Exception::DataAbort, 0x28, BusError::Permission(0x10000)));
+        assert_eq!(cpu.get_gpr(14), 0x30);
+        assert_eq!(cpu.next_instruction_address(), 0x10);
+    }
+}
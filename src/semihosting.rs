@@ -0,0 +1,489 @@
+use crate::armv4t::*;
+use crate::uart::{UartRx, UartTx};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub const SYS_OPEN: Word = 0x01;
+pub const SYS_CLOSE: Word = 0x02;
+pub const SYS_WRITEC: Word = 0x03;
+pub const SYS_WRITE0: Word = 0x04;
+pub const SYS_WRITE: Word = 0x05;
+pub const SYS_READ: Word = 0x06;
+pub const SYS_ISTTY: Word = 0x09;
+pub const SYS_SEEK: Word = 0x0A;
+pub const SYS_FLEN: Word = 0x0C;
+pub const SYS_CLOCK: Word = 0x10;
+pub const SYS_TIME: Word = 0x11;
+pub const SYS_ERRNO: Word = 0x13;
+pub const SYS_GET_CMDLINE: Word = 0x15;
+pub const SYS_HEAPINFO: Word = 0x16;
+pub const SYS_EXIT: Word = 0x18;
+pub const SYS_EXIT_EXTENDED: Word = 0x20;
+
+/// SYS_EXIT reason for a normal end of the program.
+pub const ADP_STOPPED_APPLICATION_EXIT: Word = 0x20026;
+
+/// SWI 0x123456 in ARM state.
+const SEMIHOSTING_SWI: Word = 0x0F123456;
+/// BKPT 0xAB; an ARMv5 instruction that ARMv4T cores treat as undefined.
+const SEMIHOSTING_BKPT: Word = 0xE1200A7B;
+
+/// Longest string read from guest memory; guards against missing terminators.
+const MAX_STRING: usize = 0x10000;
+/// Bytes moved per host transfer by SYS_READ and SYS_WRITE, so that the
+/// guest's length never sizes a host buffer.
+const CHUNK: usize = 0x1000;
+
+const EBADF: Word = 9;
+const EIO: Word = 5;
+const EFAULT: Word = 14;
+const EINVAL: Word = 22;
+
+pub enum SemihostingHandle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(std::fs::File),
+}
+
+/// Values returned by SYS_HEAPINFO.
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct HeapInfo {
+    pub heap_base: Word,
+    pub heap_limit: Word,
+    pub stack_base: Word,
+    pub stack_limit: Word,
+}
+
+/// Services ARM semihosting calls against the host, so that programs linked
+/// with newlib's rdimon can print, use files and exit without peripherals.
+///
+/// Calls are made with `SWI 0x123456` in ARM state, or `BKPT 0xAB` when
+/// `bkpt` is set. The Thumb `SWI 0xAB` form is not recognised, as the core
+/// does not decode Thumb instructions.
+pub struct Semihosting {
+    /// Receives SYS_WRITEC, SYS_WRITE0 and writes to `:tt` opened for writing.
+    pub console: UartTx,
+    /// Source of reads from `:tt`. Share the console UART's receiver with
+    /// `UartRx::clone` so that only one reader consumes the host's stdin.
+    pub console_input: UartRx,
+    /// Open handles; handle `n` is entry `n - 1`.
+    pub handles: Vec<Option<SemihostingHandle>>,
+    pub cmdline: String,
+    pub heap_info: HeapInfo,
+    pub bkpt: bool,
+    /// Error number of the last failed call, for SYS_ERRNO.
+    pub errno: Word,
+    pub start: std::time::Instant,
+}
+
+impl Semihosting {
+    pub fn new(console: UartTx) -> Semihosting {
+        Semihosting {
+            console,
+            console_input: UartRx::None,
+            handles: Vec::new(),
+            cmdline: String::new(),
+            heap_info: HeapInfo::default(),
+            bkpt: false,
+            errno: 0,
+            start: std::time::Instant::now(),
+        }
+    }
+
+    /// Whether `raw_inst`, about to execute, is a semihosting call.
+    pub fn is_call(&self, raw_inst: Word) -> bool {
+        raw_inst & 0x0FFFFFFF == SEMIHOSTING_SWI || (self.bkpt && raw_inst == SEMIHOSTING_BKPT)
+    }
+
+    /// Performs `operation` with the parameter `param`, as passed in r0 and
+    /// r1, and returns the value for r0 and any request for the host.
+    pub fn call(&mut self, operation: Word, param: Word, bus: &mut dyn Bus) -> (Word, Option<SystemRequest>) {
+        match operation {
+            SYS_EXIT => {
+                let code = if param == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 };
+                (0, Some(SystemRequest::Exit(code)))
+            }
+            SYS_EXIT_EXTENDED => match read_words::<2>(bus, param) {
+                Ok([ADP_STOPPED_APPLICATION_EXIT, code]) => (0, Some(SystemRequest::Exit(code as i32))),
+                Ok(_) => (0, Some(SystemRequest::Exit(1))),
+                Err(_) => (self.fail(EFAULT), None),
+            },
+            _ => match self.service(operation, param, bus) {
+                Ok(result) => (result, None),
+                Err(errno) => (self.fail(errno), None),
+            },
+        }
+    }
+
+    fn fail(&mut self, errno: Word) -> Word {
+        self.errno = errno;
+        Word::MAX
+    }
+
+    /// The operations that return a value; `Err` carries the error number.
+    fn service(&mut self, operation: Word, param: Word, bus: &mut dyn Bus) -> Result<Word, Word> {
+        match operation {
+            SYS_OPEN => {
+                let [name, mode, length] = read_words::<3>(bus, param).map_err(|_| EFAULT)?;
+                if length as usize > MAX_STRING {
+                    return Err(EINVAL);
+                }
+                let name = read_bytes(bus, name, length as usize).map_err(|_| EFAULT)?;
+                let name = String::from_utf8_lossy(&name).into_owned();
+                let handle = open(&name, mode).map_err(|e| e.raw_os_error().unwrap_or(EIO as i32) as Word)?;
+                Ok(self.insert(handle))
+            }
+            SYS_CLOSE => {
+                let [handle] = read_words::<1>(bus, param).map_err(|_| EFAULT)?;
+                let slot = self.handle_slot(handle)?;
+                self.handles[slot] = None;
+                Ok(0)
+            }
+            SYS_WRITEC => {
+                let byte = read_bytes(bus, param, 1).map_err(|_| EFAULT)?;
+                self.console.write_byte(byte[0]);
+                Ok(0)
+            }
+            SYS_WRITE0 => {
+                for byte in read_string(bus, param).map_err(|_| EFAULT)? {
+                    self.console.write_byte(byte);
+                }
+                Ok(0)
+            }
+            SYS_WRITE => {
+                let [handle, buffer, length] = read_words::<3>(bus, param).map_err(|_| EFAULT)?;
+                let slot = self.handle_slot(handle)?;
+                let mut written: Word = 0;
+                while written < length {
+                    let chunk = (length - written).min(CHUNK as Word);
+                    let data = read_bytes(bus, buffer.wrapping_add(written), chunk as usize).map_err(|_| EFAULT)?;
+                    match self.handles[slot].as_mut() {
+                        Some(SemihostingHandle::Stdout) => data.iter().for_each(|byte| self.console.write_byte(*byte)),
+                        Some(SemihostingHandle::Stderr) => std::io::stderr().write_all(&data).map_err(|_| EIO)?,
+                        Some(SemihostingHandle::File(file)) => file.write_all(&data).map_err(|_| EIO)?,
+                        _ => return Err(EBADF),
+                    }
+                    written += chunk;
+                }
+                // the result is the number of bytes not written
+                Ok(0)
+            }
+            SYS_READ => {
+                let [handle, buffer, length] = read_words::<3>(bus, param).map_err(|_| EFAULT)?;
+                let slot = self.handle_slot(handle)?;
+                let mut data = [0; CHUNK];
+                let mut read: Word = 0;
+                while read < length {
+                    let chunk = ((length - read) as usize).min(CHUNK);
+                    let count = match self.handles[slot].as_mut() {
+                        Some(SemihostingHandle::Stdin) => read_line(&mut self.console_input, &mut data[..chunk], read == 0),
+                        Some(SemihostingHandle::File(file)) => file.read(&mut data[..chunk]).map_err(|_| EIO)?,
+                        _ => return Err(EBADF),
+                    };
+                    write_bytes(bus, buffer.wrapping_add(read), &data[..count]).map_err(|_| EFAULT)?;
+                    read += count as Word;
+                    if count < chunk {
+                        break;
+                    }
+                }
+                // the result is the number of bytes not read
+                Ok(length - read)
+            }
+            SYS_ISTTY => {
+                let [handle] = read_words::<1>(bus, param).map_err(|_| EFAULT)?;
+                let slot = self.handle_slot(handle)?;
+                Ok(!matches!(self.handles[slot], Some(SemihostingHandle::File(_))) as Word)
+            }
+            SYS_SEEK => {
+                let [handle, position] = read_words::<2>(bus, param).map_err(|_| EFAULT)?;
+                let slot = self.handle_slot(handle)?;
+                match self.handles[slot].as_mut() {
+                    Some(SemihostingHandle::File(file)) => {
+                        file.seek(SeekFrom::Start(position as u64)).map_err(|_| EIO)?;
+                        Ok(0)
+                    }
+                    _ => Err(EBADF),
+                }
+            }
+            SYS_FLEN => {
+                let [handle] = read_words::<1>(bus, param).map_err(|_| EFAULT)?;
+                let slot = self.handle_slot(handle)?;
+                match self.handles[slot].as_ref() {
+                    Some(SemihostingHandle::File(file)) => Ok(file.metadata().map_err(|_| EIO)?.len() as Word),
+                    _ => Err(EBADF),
+                }
+            }
+            // centiseconds since the start of the run
+            SYS_CLOCK => Ok((self.start.elapsed().as_millis() / 10) as Word),
+            SYS_TIME => {
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_err(|_| EIO)?;
+                Ok(now.as_secs() as Word)
+            }
+            SYS_ERRNO => Ok(self.errno),
+            SYS_GET_CMDLINE => {
+                let [buffer, length] = read_words::<2>(bus, param).map_err(|_| EFAULT)?;
+                let mut cmdline = self.cmdline.clone().into_bytes();
+                if cmdline.len() + 1 > length as usize {
+                    return Err(EIO);
+                }
+                cmdline.push(0);
+                write_bytes(bus, buffer, &cmdline).map_err(|_| EFAULT)?;
+                write_bytes(bus, param.wrapping_add(4), &(self.cmdline.len() as Word).to_le_bytes()).map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            SYS_HEAPINFO => {
+                let [block] = read_words::<1>(bus, param).map_err(|_| EFAULT)?;
+                let info = self.heap_info;
+                let mut data = Vec::new();
+                for word in [info.heap_base, info.heap_limit, info.stack_base, info.stack_limit] {
+                    data.extend_from_slice(&word.to_le_bytes());
+                }
+                write_bytes(bus, block, &data).map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            _ => Err(EIO),
+        }
+    }
+
+    fn insert(&mut self, handle: SemihostingHandle) -> Word {
+        let slot = match self.handles.iter().position(|handle| handle.is_none()) {
+            Some(slot) => slot,
+            None => {
+                self.handles.push(None);
+                self.handles.len() - 1
+            }
+        };
+        self.handles[slot] = Some(handle);
+        slot as Word + 1
+    }
+
+    fn handle_slot(&self, handle: Word) -> Result<usize, Word> {
+        let slot = (handle as usize).wrapping_sub(1);
+        match self.handles.get(slot) {
+            Some(Some(_)) => Ok(slot),
+            _ => Err(EBADF),
+        }
+    }
+}
+
+/// Opens `name` with an fopen mode numbered as in the semihosting
+/// specification: r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+, a+b.
+/// `:tt` is the console: stdin for reading, stdout for writing, stderr for appending.
+fn open(name: &str, mode: Word) -> std::io::Result<SemihostingHandle> {
+    if name == ":tt" {
+        return Ok(match mode {
+            0..=3 => SemihostingHandle::Stdin,
+            4..=7 => SemihostingHandle::Stdout,
+            _ => SemihostingHandle::Stderr,
+        });
+    }
+    let mut options = std::fs::OpenOptions::new();
+    let is_update = mode & 0x2 != 0;
+    match mode >> 2 {
+        0 => options.read(true).write(is_update),
+        1 => options.write(true).create(true).truncate(true).read(is_update),
+        2 => options.append(true).create(true).read(is_update),
+        _ => return Err(std::io::ErrorKind::InvalidInput.into()),
+    };
+    Ok(SemihostingHandle::File(options.open(name)?))
+}
+
+/// Fills `data` from the console up to the end of a line, first waiting for
+/// input if `wait` is set. Returns the number of bytes read.
+fn read_line(input: &mut UartRx, data: &mut [u8], wait: bool) -> usize {
+    let mut count = 0;
+    while count < data.len() {
+        let byte = if wait && count == 0 { input.wait_byte() } else { input.read_byte() };
+        match byte {
+            Some(byte) => {
+                data[count] = byte;
+                count += 1;
+                if byte == b'\n' {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    count
+}
+
+fn byte_request(addr: Word, rw: BusRW) -> BusRequest {
+    BusRequest { width: BusWidth::Byte, ..BusRequest::word(addr, rw, BusCycle::NonSequential) }
+}
+
+pub fn read_bytes(bus: &mut dyn Bus, addr: Word, length: usize) -> Result<Vec<u8>, BusError> {
+    let mut data = Vec::with_capacity(length);
+    for i in 0..length {
+        let mut byte: Word = 0;
+        bus.transfer(&byte_request(addr.wrapping_add(i as Word), BusRW::Read), &mut byte)?;
+        data.push(byte as u8);
+    }
+    Ok(data)
+}
+
+pub fn write_bytes(bus: &mut dyn Bus, addr: Word, data: &[u8]) -> Result<(), BusError> {
+    for (i, byte) in data.iter().enumerate() {
+        let mut byte = *byte as Word;
+        bus.transfer(&byte_request(addr.wrapping_add(i as Word), BusRW::Write), &mut byte)?;
+    }
+    Ok(())
+}
+
+/// Bytes of the NUL-terminated string at `addr`, without the terminator.
+pub fn read_string(bus: &mut dyn Bus, addr: Word) -> Result<Vec<u8>, BusError> {
+    let mut data = Vec::new();
+    while data.len() < MAX_STRING {
+        let mut byte: Word = 0;
+        bus.transfer(&byte_request(addr.wrapping_add(data.len() as Word), BusRW::Read), &mut byte)?;
+        if byte == 0 {
+            break;
+        }
+        data.push(byte as u8);
+    }
+    Ok(data)
+}
+
+/// Reads a parameter block of `N` words.
+pub fn read_words<const N: usize>(bus: &mut dyn Bus, addr: Word) -> Result<[Word; N], BusError> {
+    let mut words = [0; N];
+    for (i, word) in words.iter_mut().enumerate() {
+        bus.transfer(&BusRequest::word(addr.wrapping_add(i as Word * 4), BusRW::Read, BusCycle::NonSequential), word)?;
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_map::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A program making SYS_WRITE0, SYS_HEAPINFO and SYS_EXIT calls.
+    fn semihosting_cpu() -> ARMv4T<MemoryMap> {
+        let mut map = MemoryMap::new();
+        map.add_ram("ram", 0, 0x1000);
+        let program: [Word; 10] = [
+            0xE3A00004, // mov r0, #SYS_WRITE0
+            0xE3A01C01, // mov r1, #0x100
+            0xEF123456, // swi 0x123456
+            0xE3A00016, // mov r0, #SYS_HEAPINFO
+            0xE3A01C02, // mov r1, #0x200
+            0xEF123456, // swi 0x123456
+            0xE3A00018, // mov r0, #SYS_EXIT
+            0xE59F101C, // ldr r1, =ADP_STOPPED_APPLICATION_EXIT
+            0xEF123456, // swi 0x123456
+            0xEAFFFFFE, // b .
+        ];
+        for (i, inst) in program.iter().enumerate() {
+            map.load(i as Word * 4, &inst.to_le_bytes()).unwrap();
+        }
+        map.load(0x40, &ADP_STOPPED_APPLICATION_EXIT.to_le_bytes()).unwrap();
+        map.load(0x100, b"hello\n\0").unwrap();
+        map.load(0x200, &0x300u32.to_le_bytes()).unwrap();
+        let mut cpu = ARMv4T::new(map);
+        cpu.reset();
+        cpu
+    }
+
+    /// Stores a parameter block at 0x200 and returns its address.
+    fn block(map: &mut MemoryMap, words: &[Word]) -> Word {
+        for (i, word) in words.iter().enumerate() {
+            map.load(0x200 + i as Word * 4, &word.to_le_bytes()).unwrap();
+        }
+        0x200
+    }
+
+    #[test]
+    fn guest_prints_reads_heap_info_and_exits() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = semihosting_cpu();
+        let mut semihosting = Semihosting::new(UartTx::Buffer(output.clone()));
+        semihosting.heap_info = HeapInfo { heap_base: 1, heap_limit: 2, stack_base: 3, stack_limit: 4 };
+        cpu.semihosting = Some(semihosting);
+        cpu.run(1000).unwrap();
+        assert_eq!(cpu.system_request, Some(SystemRequest::Exit(0)));
+        assert_eq!(&output.borrow()[..], b"hello\n");
+        assert_eq!(cpu.bus.peek(0x30C, BusWidth::Word), Some(4));
+    }
+
+    #[test]
+    fn swi_without_semihosting_takes_the_exception() {
+        let mut cpu = semihosting_cpu();
+        while cpu.step_address != 8 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.get_gpr(14), 0xC);
+        assert!(cpu.mode == ProcessorMode::Supervisor(0));
+        assert_eq!(cpu.next_instruction_address(), 0x8);
+    }
+
+    #[test]
+    fn console_reads_lines_and_writes_in_chunks() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut map = MemoryMap::new();
+        map.add_ram("ram", 0, 0x4000);
+        map.load(0x100, b":tt").unwrap();
+        let mut semihosting = Semihosting::new(UartTx::Buffer(output.clone()));
+        semihosting.console_input = UartRx::script(b"one\ntwo");
+        let param = block(&mut map, &[0x100, 0, 3]);
+        let (stdin, _) = semihosting.call(SYS_OPEN, param, &mut map);
+        let param = block(&mut map, &[0x100, 4, 3]);
+        let (stdout, _) = semihosting.call(SYS_OPEN, param, &mut map);
+
+        let param = block(&mut map, &[stdin, 0x400, 16]);
+        assert_eq!(semihosting.call(SYS_READ, param, &mut map).0, 12);
+        assert_eq!(map.peek(0x400, BusWidth::Word), Some(Word::from_le_bytes(*b"one\n")));
+        assert_eq!(semihosting.call(SYS_READ, param, &mut map).0, 13);
+        // end of input
+        assert_eq!(semihosting.call(SYS_READ, param, &mut map).0, 16);
+
+        map.load(0x1000, &[b'x'; 0x2800]).unwrap();
+        let param = block(&mut map, &[stdout, 0x1000, 0x2800]);
+        assert_eq!(semihosting.call(SYS_WRITE, param, &mut map).0, 0);
+        assert_eq!(output.borrow().len(), 0x2800);
+        // a length running off the end of memory faults rather than sizing a buffer
+        let param = block(&mut map, &[stdout, 0x1000, Word::MAX]);
+        assert_eq!(semihosting.call(SYS_WRITE, param, &mut map).0, Word::MAX);
+        assert_eq!(semihosting.errno, EFAULT);
+    }
+
+    #[test]
+    fn file_operations() {
+        let path = std::env::temp_dir().join("armv4t_semihosting_test.txt");
+        let name = path.to_str().unwrap();
+        let mut map = MemoryMap::new();
+        map.add_ram("ram", 0, 0x1000);
+        map.load(0x100, name.as_bytes()).unwrap();
+        let mut semihosting = Semihosting::new(UartTx::Buffer(Rc::new(RefCell::new(Vec::new()))));
+
+        // mode 6 is "w+"
+        let param = block(&mut map, &[0x100, 6, name.len() as Word]);
+        let (handle, _) = semihosting.call(SYS_OPEN, param, &mut map);
+        assert_eq!(handle, 1);
+        let param = block(&mut map, &[0x100, 6, Word::MAX]);
+        assert_eq!(semihosting.call(SYS_OPEN, param, &mut map).0, Word::MAX);
+        assert_eq!(semihosting.errno, EINVAL);
+        map.load(0x400, b"abcdef").unwrap();
+        let param = block(&mut map, &[handle, 0x400, 6]);
+        assert_eq!(semihosting.call(SYS_WRITE, param, &mut map).0, 0);
+        let param = block(&mut map, &[handle, 2]);
+        assert_eq!(semihosting.call(SYS_SEEK, param, &mut map).0, 0);
+        // reading past the end leaves 6 of 10 bytes unread
+        let param = block(&mut map, &[handle, 0x500, 10]);
+        assert_eq!(semihosting.call(SYS_READ, param, &mut map).0, 6);
+        assert_eq!(map.peek(0x500, BusWidth::Word), Some(Word::from_le_bytes(*b"cdef")));
+        let param = block(&mut map, &[handle]);
+        assert_eq!(semihosting.call(SYS_FLEN, param, &mut map).0, 6);
+        assert_eq!(semihosting.call(SYS_CLOSE, param, &mut map).0, 0);
+        assert_eq!(semihosting.call(SYS_CLOSE, param, &mut map).0, Word::MAX);
+        assert_eq!(semihosting.call(SYS_ERRNO, 0, &mut map).0, EBADF);
+
+        semihosting.cmdline = "prog a b".into();
+        let param = block(&mut map, &[0x600, 64]);
+        assert_eq!(semihosting.call(SYS_GET_CMDLINE, param, &mut map).0, 0);
+        assert_eq!(map.peek(0x204, BusWidth::Word), Some(8));
+        let _ = std::fs::remove_file(path);
+    }
+}
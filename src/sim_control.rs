@@ -0,0 +1,103 @@
+use crate::armv4t::*;
+use crate::device::*;
+use crate::uart::UartTx;
+
+const SIM_EXIT: Word = 0x00;
+const SIM_PUTC: Word = 0x04;
+const SIM_CYCLES_LO: Word = 0x08;
+const SIM_CYCLES_HI: Word = 0x0C;
+const SIM_ASSERT: Word = 0x10;
+
+/// Lets the guest talk to the host running it:
+///
+/// | offset | access | function                                                |
+/// |--------|--------|---------------------------------------------------------|
+/// | 0x00   | W      | end the run with the written exit status                |
+/// | 0x04   | W      | emit the low byte as a character                        |
+/// | 0x08   | R      | low word of the cycle count; latches the high word      |
+/// | 0x0C   | R      | high word latched by the last read of 0x08              |
+/// | 0x10   | W      | report a failed assertion identified by the written value |
+pub struct SimControl {
+    pub output: UartTx,
+    /// Cycles the device has been ticked for since it was created.
+    pub cycles: u64,
+    pub cycles_hi_latch: Word,
+    pub request: Option<SystemRequest>,
+}
+
+impl SimControl {
+    pub fn new(output: UartTx) -> SimControl {
+        SimControl {
+            output,
+            cycles: 0,
+            cycles_hi_latch: 0,
+            request: None,
+        }
+    }
+}
+
+impl Device for SimControl {
+    fn read(&mut self, offset: Word, _width: BusWidth) -> Result<Word, BusError> {
+        let value = match offset {
+            SIM_CYCLES_LO => {
+                self.cycles_hi_latch = (self.cycles >> 32) as Word;
+                self.cycles as Word
+            }
+            SIM_CYCLES_HI => self.cycles_hi_latch,
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: Word, value: Word, _width: BusWidth) -> Result<(), BusError> {
+        match offset {
+            SIM_EXIT => self.request = Some(SystemRequest::Exit(value as i32)),
+            SIM_PUTC => self.output.write_byte(value as u8),
+            SIM_ASSERT => self.request = Some(SystemRequest::AssertionFailure(value)),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+
+    fn system_request(&mut self) -> Option<SystemRequest> {
+        self.request.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_map::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn guest_prints_reads_cycles_and_exits() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut map = MemoryMap::new();
+        map.add_ram("ram", 0, 0x10000);
+        map.add_device("sim_control", 0x1000_0000, 0x1000, Box::new(SimControl::new(UartTx::Buffer(output.clone()))));
+        let program: [Word; 6] = [
+            0xE3A01201, // mov r1, #0x10000000
+            0xE3A00041, // mov r0, #'A'
+            0xE5810004, // str r0, [r1, #SIM_PUTC]
+            0xE5912008, // ldr r2, [r1, #SIM_CYCLES_LO]
+            0xE3A0002A, // mov r0, #42
+            0xE5810000, // str r0, [r1, #SIM_EXIT]
+        ];
+        for (i, inst) in program.iter().enumerate() {
+            map.load(i as Word * 4, &inst.to_le_bytes()).unwrap();
+        }
+        let mut cpu = ARMv4T::new(map);
+        cpu.reset();
+        cpu.run(1000).unwrap();
+        assert_eq!(cpu.system_request, Some(SystemRequest::Exit(42)));
+        assert_eq!(&*output.borrow(), b"A");
+        assert!(cpu.get_gpr(2) > 0);
+        assert!(cpu.cycles.total() < 100);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::armv4t::*;
+use std::collections::HashMap;
+
+pub const PAGE_SIZE: usize = 0x1000;
+const PAGE_SHIFT: u32 = 12;
+const PAGE_MASK: Word = PAGE_SIZE as Word - 1;
+
+/// RAM covering the whole 32-bit address space, allocated a page at a time on
+/// first write. Unwritten memory reads as zero and addresses wrap at 4 GiB,
+/// so an access at 0xFFFFFFFF continues at 0x00000000.
+#[derive(Default)]
+pub struct SparseMemory {
+    pub pages: HashMap<Word, Box<[u8; PAGE_SIZE]>>,
+}
+
+impl SparseMemory {
+    pub fn new() -> SparseMemory {
+        SparseMemory { pages: HashMap::new() }
+    }
+
+    fn page_mut(&mut self, addr: Word) -> &mut [u8; PAGE_SIZE] {
+        self.pages.entry(addr >> PAGE_SHIFT).or_insert_with(|| Box::new([0; PAGE_SIZE]))
+    }
+
+    /// Copies `data` to `addr` a page at a time.
+    pub fn load(&mut self, addr: Word, data: &[u8]) {
+        let mut addr = addr;
+        let mut data = data;
+        while !data.is_empty() {
+            let offset = (addr & PAGE_MASK) as usize;
+            let length = data.len().min(PAGE_SIZE - offset);
+            self.page_mut(addr)[offset..offset + length].copy_from_slice(&data[..length]);
+            addr = addr.wrapping_add(length as Word);
+            data = &data[length..];
+        }
+    }
+
+    /// Fills `data` from `addr` a page at a time, without allocating.
+    pub fn read(&self, addr: Word, data: &mut [u8]) {
+        let mut addr = addr;
+        let mut data = data;
+        while !data.is_empty() {
+            let offset = (addr & PAGE_MASK) as usize;
+            let length = data.len().min(PAGE_SIZE - offset);
+            let (chunk, rest) = data.split_at_mut(length);
+            match self.pages.get(&(addr >> PAGE_SHIFT)) {
+                Some(page) => chunk.copy_from_slice(&page[offset..offset + length]),
+                None => chunk.fill(0),
+            }
+            addr = addr.wrapping_add(length as Word);
+            data = rest;
+        }
+    }
+
+    pub fn read_byte(&self, addr: Word) -> u8 {
+        self.pages.get(&(addr >> PAGE_SHIFT)).map_or(0, |page| page[(addr & PAGE_MASK) as usize])
+    }
+
+    pub fn write_byte(&mut self, addr: Word, value: u8) {
+        self.page_mut(addr)[(addr & PAGE_MASK) as usize] = value;
+    }
+
+    /// Base addresses of the allocated pages, in ascending order.
+    pub fn populated_pages(&self) -> Vec<Word> {
+        let mut pages: Vec<Word> = self.pages.keys().map(|page| page << PAGE_SHIFT).collect();
+        pages.sort_unstable();
+        pages
+    }
+
+    /// Bytes of host memory held by allocated pages.
+    pub fn allocated_bytes(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+
+    /// Frees every page, leaving all memory reading as zero.
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+
+    fn read_le(&self, addr: Word, bytes: usize) -> Word {
+        let mut data = [0u8; 4];
+        self.read(addr, &mut data[..bytes]);
+        Word::from_le_bytes(data)
+    }
+}
+
+impl Bus for SparseMemory {
+    fn access(&mut self, addr: Word, data: &mut Word, rw: BusRW, cycle: BusCycle) -> BusState {
+        self.transfer(&BusRequest::word(addr, rw, cycle), data)
+    }
+
+    fn transfer(&mut self, request: &BusRequest, data: &mut Word) -> BusState {
+        let bytes = request.width.bytes() as usize;
+        match request.rw {
+            BusRW::Read => *data = self.read_le(request.addr, bytes),
+            BusRW::Write => self.load(request.addr, &data.to_le_bytes()[..bytes]),
+        }
+        Ok(0)
+    }
+
+    fn peek(&mut self, addr: Word, width: BusWidth) -> Option<Word> {
+        Some(self.read_le(addr, width.bytes() as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocates_pages_on_write_and_wraps() {
+        let mut memory = SparseMemory::new();
+        let mut data = 0x11223344;
+        memory.transfer(&BusRequest::word(0xFFFFFFFE, BusRW::Write, BusCycle::NonSequential), &mut data).unwrap();
+        assert_eq!(memory.populated_pages(), vec![0, 0xFFFFF000]);
+        assert_eq!(memory.read_byte(0), 0x22);
+        let mut bytes = [0u8; 4];
+        memory.read(0xFFFFFFFE, &mut bytes);
+        assert_eq!(bytes, [0x44, 0x33, 0x22, 0x11]);
+
+        // reads never allocate
+        memory.access(0x5000, &mut data, BusRW::Read, BusCycle::NonSequential).unwrap();
+        assert_eq!(data, 0);
+        assert_eq!(memory.allocated_bytes(), 2 * PAGE_SIZE);
+
+        memory.load(0x1FFE, &[1; 0x2004]);
+        assert_eq!(memory.populated_pages(), vec![0, 0x1000, 0x2000, 0x3000, 0x4000, 0xFFFFF000]);
+        memory.access(0x4000, &mut data, BusRW::Read, BusCycle::NonSequential).unwrap();
+        assert_eq!(data, 0x0101);
+        memory.clear();
+        assert!(memory.populated_pages().is_empty());
+    }
+}
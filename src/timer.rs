@@ -0,0 +1,249 @@
+use crate::armv4t::*;
+use crate::device::*;
+
+const TIMER_LOAD: Word = 0x00;
+const TIMER_VALUE: Word = 0x04;
+const TIMER_CONTROL: Word = 0x08;
+const TIMER_INT_CLR: Word = 0x0C;
+const TIMER_RIS: Word = 0x10;
+const TIMER_MIS: Word = 0x14;
+const TIMER_BG_LOAD: Word = 0x18;
+/// Timer 2 registers repeat timer 1's at this offset.
+const TIMER2_OFFSET: Word = 0x20;
+const TIMER_PERIPH_ID0: Word = 0xFE0;
+
+const CONTROL_ONE_SHOT: Word = 0x01;
+const CONTROL_32BIT: Word = 0x02;
+const CONTROL_INT_ENABLE: Word = 0x20;
+const CONTROL_PERIODIC: Word = 0x40;
+const CONTROL_ENABLE: Word = 0x80;
+
+const PERIPH_ID: [Word; 8] = [0x04, 0x18, 0x14, 0x00, 0x0D, 0xF0, 0x05, 0xB1];
+
+/// One down-counter of the dual timer.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Timer {
+    pub load: Word,
+    pub value: Word,
+    pub control: Word,
+    pub raw_interrupt: bool,
+    /// CPU cycles not yet turned into a counter decrement.
+    pub pending_cycles: u64,
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer {
+            load: 0,
+            value: 0xFFFFFFFF,
+            control: CONTROL_INT_ENABLE,
+            raw_interrupt: false,
+            pending_cycles: 0,
+        }
+    }
+}
+
+impl Timer {
+    pub fn is_enabled(&self) -> bool {
+        self.control & CONTROL_ENABLE != 0
+    }
+
+    pub fn masked_interrupt(&self) -> bool {
+        self.raw_interrupt && self.control & CONTROL_INT_ENABLE != 0
+    }
+
+    fn max_value(&self) -> Word {
+        if self.control & CONTROL_32BIT != 0 { 0xFFFFFFFF } else { 0xFFFF }
+    }
+
+    fn prescale(&self) -> u64 {
+        match (self.control >> 2) & 0x3 {
+            0b00 => 1,
+            0b01 => 16,
+            _ => 256,
+        }
+    }
+
+    /// Value the counter restarts from after reaching zero.
+    fn reload_value(&self) -> Word {
+        if self.control & CONTROL_PERIODIC != 0 { self.load & self.max_value() } else { self.max_value() }
+    }
+
+    /// A one-shot counter halts at zero until it is reloaded.
+    fn is_halted(&self) -> bool {
+        self.control & CONTROL_ONE_SHOT != 0 && self.value == 0
+    }
+
+    fn tick(&mut self, cycles: u64, cycles_per_tick: u64) {
+        if !self.is_enabled() || self.is_halted() {
+            return;
+        }
+        let divisor = cycles_per_tick * self.prescale();
+        self.pending_cycles += cycles;
+        let mut ticks = self.pending_cycles / divisor;
+        self.pending_cycles %= divisor;
+
+        while ticks > 0 {
+            let value = (self.value & self.max_value()) as u64;
+            if value == 0 {
+                // the interrupt was raised on reaching zero; this clock only reloads
+                ticks -= 1;
+                self.value = self.reload_value();
+                continue;
+            }
+            if ticks < value {
+                self.value = (value - ticks) as Word;
+                return;
+            }
+            ticks -= value;
+            self.value = 0;
+            self.raw_interrupt = true;
+            if self.control & CONTROL_ONE_SHOT != 0 || ticks == 0 {
+                return;
+            }
+            // the clock after reaching zero reloads; whole periods only re-raise the interrupt
+            ticks -= 1;
+            self.value = self.reload_value();
+            ticks %= self.value as u64 + 1;
+        }
+    }
+
+    /// Cycles until the counter next reaches zero.
+    fn next_event(&self, cycles_per_tick: u64) -> Option<u64> {
+        if !self.is_enabled() || self.is_halted() {
+            return None;
+        }
+        let divisor = cycles_per_tick * self.prescale();
+        let ticks = match self.value & self.max_value() {
+            0 => self.reload_value() as u64 + 1,
+            value => value as u64,
+        };
+        Some((ticks * divisor).saturating_sub(self.pending_cycles).max(1))
+    }
+
+    fn read(&self, offset: Word) -> Word {
+        match offset {
+            TIMER_LOAD | TIMER_BG_LOAD => self.load,
+            TIMER_VALUE => self.value & self.max_value(),
+            TIMER_CONTROL => self.control,
+            TIMER_RIS => self.raw_interrupt as Word,
+            TIMER_MIS => self.masked_interrupt() as Word,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: Word, value: Word) {
+        match offset {
+            TIMER_LOAD => {
+                self.load = value;
+                self.value = value;
+                self.pending_cycles = 0;
+            }
+            TIMER_CONTROL => self.control = value & 0xFF,
+            TIMER_INT_CLR => self.raw_interrupt = false,
+            TIMER_BG_LOAD => self.load = value,
+            _ => (),
+        }
+    }
+}
+
+/// PrimeCell SP804 dual timer. Both counters are clocked every
+/// `cycles_per_tick` CPU cycles (before their prescalers) and share one
+/// combined interrupt output.
+#[derive(Debug, Default)]
+pub struct DualTimer {
+    pub timers: [Timer; 2],
+    pub cycles_per_tick: u64,
+}
+
+impl DualTimer {
+    pub fn new(cycles_per_tick: u64) -> DualTimer {
+        DualTimer {
+            timers: [Timer::default(); 2],
+            cycles_per_tick: cycles_per_tick.max(1),
+        }
+    }
+}
+
+impl Device for DualTimer {
+    fn read(&mut self, offset: Word, width: BusWidth) -> Result<Word, BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        let value = match offset {
+            0x00..=0x1C => self.timers[0].read(offset),
+            0x20..=0x3C => self.timers[1].read(offset - TIMER2_OFFSET),
+            0xFE0..=0xFFC => PERIPH_ID[((offset - TIMER_PERIPH_ID0) / 4) as usize],
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: Word, value: Word, width: BusWidth) -> Result<(), BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        match offset {
+            0x00..=0x1C => self.timers[0].write(offset, value),
+            0x20..=0x3C => self.timers[1].write(offset - TIMER2_OFFSET, value),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        for timer in self.timers.iter_mut() {
+            timer.tick(cycles, self.cycles_per_tick);
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.timers.iter().filter_map(|timer| timer.next_event(self.cycles_per_tick)).min()
+    }
+
+    fn interrupt(&self) -> bool {
+        self.timers.iter().any(|timer| timer.masked_interrupt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORD: BusWidth = BusWidth::Word;
+
+    #[test]
+    fn periodic_timer_reloads_and_interrupts() {
+        let mut timer = DualTimer::new(1);
+        timer.write(TIMER_LOAD, 10, WORD).unwrap();
+        timer.write(TIMER_CONTROL, CONTROL_ENABLE | CONTROL_PERIODIC | CONTROL_INT_ENABLE | CONTROL_32BIT, WORD).unwrap();
+        assert_eq!(timer.next_event(), Some(10));
+        timer.tick(9);
+        assert!(!timer.interrupt());
+        assert_eq!(timer.read(TIMER_VALUE, WORD).unwrap(), 1);
+        timer.tick(1);
+        assert!(timer.interrupt());
+        timer.write(TIMER_INT_CLR, 0, WORD).unwrap();
+        assert!(!timer.interrupt());
+        timer.tick(1);
+        assert!(!timer.interrupt());
+        assert_eq!(timer.read(TIMER_VALUE, WORD).unwrap(), 10);
+        timer.tick(10 + 11 * 5);
+        assert!(timer.interrupt());
+        assert_eq!(timer.read(TIMER_VALUE, WORD).unwrap(), 0);
+    }
+
+    #[test]
+    fn one_shot_timer_stops_at_zero() {
+        let mut timer = DualTimer::new(1);
+        timer.write(TIMER2_OFFSET + TIMER_LOAD, 2, WORD).unwrap();
+        // 16-bit counter, prescaled by 16
+        timer.write(TIMER2_OFFSET + TIMER_CONTROL, CONTROL_ENABLE | CONTROL_INT_ENABLE | (1 << 2) | CONTROL_ONE_SHOT, WORD).unwrap();
+        timer.tick(31);
+        assert!(!timer.timers[1].masked_interrupt());
+        timer.tick(1);
+        assert!(timer.timers[1].masked_interrupt());
+        timer.tick(1000);
+        assert_eq!(timer.read(TIMER2_OFFSET + TIMER_VALUE, WORD).unwrap(), 0);
+    }
+}
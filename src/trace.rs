@@ -0,0 +1,193 @@
+use crate::armv4t::*;
+use std::io::Write;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TraceFormat {
+    /// One line per transaction:
+    /// `<pc> <kind><rw><bytes> <addr> <value> <privilege>`, e.g.
+    /// `00000104 DW4 101f1000 00000041 P`, followed by `!<error>` if it failed.
+    /// Kind is F(etch), D(ata) or S(wap); privilege is U(ser) or P(rivileged).
+    Text,
+    /// 16-byte little-endian records: pc, address and value words, then a
+    /// flags byte (bit 0 write, bit 1 privileged, bits 3:2 kind, bits 5:4
+    /// log2 of the width, bit 6 error) and three bytes of padding.
+    Binary,
+}
+
+/// Logs the transactions made through `bus` that fall in one of `ranges`
+/// (inclusive; all addresses if empty) and are of one of `kinds` (all kinds if empty).
+pub struct TraceBus<T: Bus> {
+    pub bus: T,
+    pub output: Box<dyn Write>,
+    pub format: TraceFormat,
+    pub ranges: Vec<(Word, Word)>,
+    pub kinds: Vec<BusKind>,
+    /// Address of the instruction the current transactions belong to.
+    pub pc: Word,
+    /// First error writing the log; nothing more is written after it.
+    pub error: Option<std::io::Error>,
+}
+
+impl<T: Bus> TraceBus<T> {
+    pub fn new(bus: T, output: Box<dyn Write>, format: TraceFormat) -> TraceBus<T> {
+        TraceBus {
+            bus,
+            output,
+            format,
+            ranges: Vec::new(),
+            kinds: Vec::new(),
+            pc: 0,
+            error: None,
+        }
+    }
+
+    /// Traces to a buffered file at `path`.
+    pub fn to_file(bus: T, path: &str, format: TraceFormat) -> std::io::Result<TraceBus<T>> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        Ok(TraceBus::new(bus, Box::new(file), format))
+    }
+
+    pub fn range(mut self, start: Word, end: Word) -> TraceBus<T> {
+        self.ranges.push((start, end));
+        self
+    }
+
+    pub fn kind(mut self, kind: BusKind) -> TraceBus<T> {
+        self.kinds.push(kind);
+        self
+    }
+
+    fn is_traced(&self, request: &BusRequest) -> bool {
+        let is_in_range = self.ranges.is_empty()
+            || self.ranges.iter().any(|(start, end)| *start <= request.addr && request.addr <= *end);
+        let is_kind = self.kinds.is_empty() || self.kinds.contains(&request.kind);
+        is_in_range && is_kind
+    }
+
+    fn log(&mut self, request: &BusRequest, value: Word, result: &BusState) {
+        if self.error.is_some() || !self.is_traced(request) {
+            return;
+        }
+        let written = match self.format {
+            TraceFormat::Text => {
+                let kind = match request.kind {
+                    BusKind::Fetch => 'F',
+                    BusKind::Data => 'D',
+                    BusKind::Swap => 'S',
+                };
+                let rw = if request.rw == BusRW::Read { 'R' } else { 'W' };
+                let privilege = if request.privilege == Privilege::User { 'U' } else { 'P' };
+                let error = match result {
+                    Ok(_) => String::new(),
+                    Err(e) => format!(" !{}", e),
+                };
+                writeln!(
+                    self.output,
+                    "{:08x} {}{}{} {:08x} {:08x} {}{}",
+                    self.pc, kind, rw, request.width.bytes(), request.addr, value, privilege, error
+                )
+            }
+            TraceFormat::Binary => {
+                let kind: u8 = match request.kind {
+                    BusKind::Fetch => 0,
+                    BusKind::Data => 1,
+                    BusKind::Swap => 2,
+                };
+                let flags = (request.rw == BusRW::Write) as u8
+                    | ((request.privilege == Privilege::Privileged) as u8) << 1
+                    | kind << 2
+                    | (request.width.bytes().trailing_zeros() as u8) << 4
+                    | (result.is_err() as u8) << 6;
+                let mut record = [0u8; 16];
+                record[0..4].copy_from_slice(&self.pc.to_le_bytes());
+                record[4..8].copy_from_slice(&request.addr.to_le_bytes());
+                record[8..12].copy_from_slice(&value.to_le_bytes());
+                record[12] = flags;
+                self.output.write_all(&record)
+            }
+        };
+        if let Err(e) = written {
+            self.error = Some(e);
+        }
+    }
+}
+
+impl<T: Bus> Bus for TraceBus<T> {
+    fn access(&mut self, addr: Word, data: &mut Word, rw: BusRW, cycle: BusCycle) -> BusState {
+        self.transfer(&BusRequest::word(addr, rw, cycle), data)
+    }
+
+    fn transfer(&mut self, request: &BusRequest, data: &mut Word) -> BusState {
+        let result = self.bus.transfer(request, data);
+        self.log(request, *data, &result);
+        result
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.bus.tick(cycles);
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.bus.next_event()
+    }
+
+    fn interrupt_lines(&mut self) -> InterruptLines {
+        self.bus.interrupt_lines()
+    }
+
+    fn stolen_cycles(&mut self) -> u64 {
+        self.bus.stolen_cycles()
+    }
+
+    fn system_request(&mut self) -> Option<SystemRequest> {
+        self.bus.system_request()
+    }
+
+    fn peek(&mut self, addr: Word, width: BusWidth) -> Option<Word> {
+        self.bus.peek(addr, width)
+    }
+
+    fn cache_operation(&mut self, operation: CacheOperation) -> BusState {
+        self.bus.cache_operation(operation)
+    }
+
+    fn begin_instruction(&mut self, pc: Word) {
+        self.pc = pc;
+        self.bus.begin_instruction(pc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_map::*;
+
+    #[test]
+    fn logs_filtered_data_accesses_as_text() {
+        let mut map = MemoryMap::new();
+        map.add_ram("ram", 0, 0x10000);
+        let program: [Word; 5] = [
+            0xE3A01C01, // mov r1, #0x100
+            0xE3A00041, // mov r0, #0x41
+            0xE5C10000, // strb r0, [r1]
+            0xE5912000, // ldr r2, [r1]
+            0xEAFFFFFE, // b .
+        ];
+        for (i, inst) in program.iter().enumerate() {
+            map.load(i as Word * 4, &inst.to_le_bytes()).unwrap();
+        }
+        let path = std::env::temp_dir().join("armv4t_trace_test.txt");
+        let path = path.to_str().unwrap();
+        let bus = TraceBus::to_file(map, path, TraceFormat::Text).unwrap().kind(BusKind::Data).range(0x100, 0x1FF);
+        let mut cpu = ARMv4T::new(bus);
+        cpu.reset();
+        while cpu.next_instruction_address() != 0x10 || cpu.decoded_inst.is_none() {
+            cpu.step().unwrap();
+        }
+        // flushes the log
+        drop(cpu);
+        let log = std::fs::read_to_string(path).unwrap();
+        assert_eq!(log, "00000008 DW1 00000100 00000041 P\n0000000c DR4 00000100 00000041 P\n");
+        let _ = std::fs::remove_file(path);
+    }
+}
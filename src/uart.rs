@@ -0,0 +1,440 @@
+use crate::armv4t::*;
+use crate::device::*;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver};
+
+const UART_DR: Word = 0x000;
+const UART_RSR: Word = 0x004;
+const UART_FR: Word = 0x018;
+const UART_ILPR: Word = 0x020;
+const UART_IBRD: Word = 0x024;
+const UART_FBRD: Word = 0x028;
+const UART_LCR_H: Word = 0x02C;
+const UART_CR: Word = 0x030;
+const UART_IFLS: Word = 0x034;
+const UART_IMSC: Word = 0x038;
+const UART_RIS: Word = 0x03C;
+const UART_MIS: Word = 0x040;
+const UART_ICR: Word = 0x044;
+const UART_DMACR: Word = 0x048;
+const UART_PERIPH_ID0: Word = 0xFE0;
+
+const FR_BUSY: Word = 0x008;
+const FR_RXFE: Word = 0x010;
+const FR_TXFF: Word = 0x020;
+const FR_RXFF: Word = 0x040;
+const FR_TXFE: Word = 0x080;
+
+const LCR_H_FEN: Word = 0x10;
+
+const DMACR_RXDMAE: Word = 0x1;
+const DMACR_TXDMAE: Word = 0x2;
+
+const INT_RX: Word = 0x010;
+const INT_TX: Word = 0x020;
+const INT_RT: Word = 0x040;
+const INT_ALL: Word = 0x7FF;
+
+const FIFO_DEPTH: usize = 16;
+/// Bit periods without new data before the receive timeout interrupt.
+const RX_TIMEOUT_BITS: u64 = 32;
+/// Start, 8 data and stop bits.
+const BITS_PER_CHAR: u64 = 10;
+
+const PERIPH_ID: [Word; 8] = [0x11, 0x10, 0x14, 0x00, 0x0D, 0xF0, 0x05, 0xB1];
+
+/// Where transmitted characters go.
+pub enum UartTx {
+    Stdout,
+    File(std::fs::File),
+    /// Collected for inspection by the host, e.g. in tests.
+    Buffer(Rc<RefCell<Vec<u8>>>),
+}
+
+impl UartTx {
+    pub fn file(path: &str) -> std::io::Result<UartTx> {
+        Ok(UartTx::File(std::fs::File::create(path)?))
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        // the guest has no way to see a host I/O error, so it is dropped like a line fault
+        let _ = match self {
+            UartTx::Stdout => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&[byte]).and_then(|_| stdout.flush())
+            }
+            UartTx::File(file) => file.write_all(&[byte]),
+            UartTx::Buffer(buffer) => {
+                buffer.borrow_mut().push(byte);
+                Ok(())
+            }
+        };
+    }
+}
+
+/// Where received characters come from. Clones share one source, so that a
+/// UART and semihosting can both read the host's stdin.
+#[derive(Clone)]
+pub enum UartRx {
+    None,
+    /// Fed by a background thread, so the guest never blocks on the host.
+    Stdin(Rc<Receiver<u8>>),
+    /// Bytes delivered in order; the host may append more at any time.
+    Script(Rc<RefCell<VecDeque<u8>>>),
+}
+
+impl UartRx {
+    pub fn stdin() -> UartRx {
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || {
+            for byte in std::io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte) if sender.send(byte).is_ok() => (),
+                    _ => break,
+                }
+            }
+        });
+        UartRx::Stdin(Rc::new(receiver))
+    }
+
+    pub fn file(path: &str) -> std::io::Result<UartRx> {
+        let data = std::fs::read(path)?;
+        Ok(UartRx::script(&data))
+    }
+
+    pub fn script(data: &[u8]) -> UartRx {
+        UartRx::Script(Rc::new(RefCell::new(data.iter().copied().collect())))
+    }
+
+    pub fn read_byte(&mut self) -> Option<u8> {
+        match self {
+            UartRx::None => None,
+            UartRx::Stdin(receiver) => receiver.try_recv().ok(),
+            UartRx::Script(script) => script.borrow_mut().pop_front(),
+        }
+    }
+
+    /// Like `read_byte`, but waits for stdin until a byte arrives or it closes.
+    pub fn wait_byte(&mut self) -> Option<u8> {
+        match self {
+            UartRx::Stdin(receiver) => receiver.recv().ok(),
+            _ => self.read_byte(),
+        }
+    }
+}
+
+/// PrimeCell PL011 UART.
+///
+/// With `cycles_per_char` at 0 characters move instantly; otherwise each one
+/// takes that many CPU cycles on the line in either direction. The enable bits
+/// in UARTCR are stored but not enforced, so firmware that skips the UART
+/// setup still gets a console.
+pub struct Uart {
+    pub tx: UartTx,
+    pub rx: UartRx,
+    pub cycles_per_char: u64,
+    pub tx_fifo: VecDeque<u8>,
+    pub rx_fifo: VecDeque<u8>,
+    pub ilpr: Word,
+    pub ibrd: Word,
+    pub fbrd: Word,
+    pub lcr_h: Word,
+    pub cr: Word,
+    pub ifls: Word,
+    pub imsc: Word,
+    pub ris: Word,
+    pub dmacr: Word,
+    pub tx_cycles: u64,
+    pub rx_cycles: u64,
+    /// Cycles since the last character arrived, for the receive timeout.
+    pub rx_idle_cycles: u64,
+}
+
+impl Uart {
+    pub fn new(tx: UartTx, rx: UartRx) -> Uart {
+        Uart {
+            tx,
+            rx,
+            cycles_per_char: 0,
+            tx_fifo: VecDeque::new(),
+            rx_fifo: VecDeque::new(),
+            ilpr: 0,
+            ibrd: 0,
+            fbrd: 0,
+            lcr_h: 0,
+            cr: 0x300,
+            ifls: 0x12,
+            imsc: 0,
+            ris: INT_TX,
+            dmacr: 0,
+            tx_cycles: 0,
+            rx_cycles: 0,
+            rx_idle_cycles: 0,
+        }
+    }
+
+    /// Without FIFOs enabled both directions hold a single character.
+    fn fifo_depth(&self) -> usize {
+        if self.lcr_h & LCR_H_FEN != 0 { FIFO_DEPTH } else { 1 }
+    }
+
+    /// Trigger level in characters for an IFLS field: 1/8, 1/4, 1/2, 3/4 or 7/8 full.
+    fn trigger_level(&self, field: Word) -> usize {
+        let eighths = match field & 0x7 {
+            0 => 1,
+            1 => 2,
+            2 => 4,
+            3 => 6,
+            _ => 7,
+        };
+        FIFO_DEPTH * eighths / 8
+    }
+
+    fn is_tx_level(&self) -> bool {
+        match self.fifo_depth() {
+            1 => self.tx_fifo.is_empty(),
+            _ => self.tx_fifo.len() <= self.trigger_level(self.ifls),
+        }
+    }
+
+    fn is_rx_level(&self) -> bool {
+        match self.fifo_depth() {
+            1 => !self.rx_fifo.is_empty(),
+            _ => self.rx_fifo.len() >= self.trigger_level(self.ifls >> 3),
+        }
+    }
+
+    fn rx_timeout_cycles(&self) -> u64 {
+        (self.cycles_per_char * RX_TIMEOUT_BITS / BITS_PER_CHAR).max(RX_TIMEOUT_BITS)
+    }
+
+    fn flags(&self) -> Word {
+        let mut flags = 0;
+        if !self.tx_fifo.is_empty() {
+            flags |= FR_BUSY;
+        }
+        if self.rx_fifo.is_empty() {
+            flags |= FR_RXFE;
+        }
+        if self.tx_fifo.len() >= self.fifo_depth() {
+            flags |= FR_TXFF;
+        }
+        if self.rx_fifo.len() >= self.fifo_depth() {
+            flags |= FR_RXFF;
+        }
+        if self.tx_fifo.is_empty() {
+            flags |= FR_TXFE;
+        }
+        flags
+    }
+
+    fn transmit(&mut self) {
+        if let Some(byte) = self.tx_fifo.pop_front() {
+            self.tx.write_byte(byte);
+            if self.is_tx_level() {
+                self.ris |= INT_TX;
+            }
+        }
+    }
+
+    fn receive(&mut self) -> bool {
+        if self.rx_fifo.len() >= self.fifo_depth() {
+            return false;
+        }
+        match self.rx.read_byte() {
+            Some(byte) => {
+                self.rx_fifo.push_back(byte);
+                self.rx_idle_cycles = 0;
+                if self.is_rx_level() {
+                    self.ris |= INT_RX;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write_data(&mut self, value: Word) {
+        if self.tx_fifo.len() < self.fifo_depth() {
+            self.tx_fifo.push_back(value as u8);
+        }
+        if !self.is_tx_level() {
+            self.ris &= !INT_TX;
+        }
+        if self.cycles_per_char == 0 {
+            while !self.tx_fifo.is_empty() {
+                self.transmit();
+            }
+        }
+    }
+
+    fn read_data(&mut self) -> Word {
+        let value = self.rx_fifo.pop_front().map_or(0, |byte| byte as Word);
+        if !self.is_rx_level() {
+            self.ris &= !INT_RX;
+        }
+        if self.rx_fifo.is_empty() {
+            self.ris &= !INT_RT;
+        }
+        value
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: Word, width: BusWidth) -> Result<Word, BusError> {
+        let value = match offset {
+            UART_DR => self.read_data(),
+            UART_RSR => 0,
+            UART_FR => self.flags(),
+            UART_ILPR => self.ilpr,
+            UART_IBRD => self.ibrd,
+            UART_FBRD => self.fbrd,
+            UART_LCR_H => self.lcr_h,
+            UART_CR => self.cr,
+            UART_IFLS => self.ifls,
+            UART_IMSC => self.imsc,
+            UART_RIS => self.ris,
+            UART_MIS => self.ris & self.imsc,
+            UART_DMACR => self.dmacr,
+            0xFE0..=0xFFC if width == BusWidth::Word => PERIPH_ID[((offset - UART_PERIPH_ID0) / 4) as usize],
+            _ => 0,
+        };
+        Ok(value & width.mask())
+    }
+
+    fn write(&mut self, offset: Word, value: Word, _width: BusWidth) -> Result<(), BusError> {
+        match offset {
+            UART_DR => self.write_data(value),
+            UART_ILPR => self.ilpr = value & 0xFF,
+            UART_IBRD => self.ibrd = value & 0xFFFF,
+            UART_FBRD => self.fbrd = value & 0x3F,
+            UART_LCR_H => {
+                // toggling the FIFOs flushes them
+                if (self.lcr_h ^ value) & LCR_H_FEN != 0 {
+                    self.tx_fifo.clear();
+                    self.rx_fifo.clear();
+                }
+                self.lcr_h = value & 0xFF;
+            }
+            UART_CR => self.cr = value & 0xFFFF,
+            UART_IFLS => self.ifls = value & 0x3F,
+            UART_IMSC => self.imsc = value & INT_ALL,
+            UART_ICR => self.ris &= !value,
+            UART_DMACR => self.dmacr = value & 0x7,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if self.cycles_per_char == 0 {
+            while self.receive() {}
+        } else {
+            if !self.tx_fifo.is_empty() {
+                self.tx_cycles += cycles;
+                while self.tx_cycles >= self.cycles_per_char && !self.tx_fifo.is_empty() {
+                    self.tx_cycles -= self.cycles_per_char;
+                    self.transmit();
+                }
+            }
+            if self.tx_fifo.is_empty() {
+                self.tx_cycles = 0;
+            }
+            self.rx_cycles += cycles;
+            while self.rx_cycles >= self.cycles_per_char {
+                self.rx_cycles -= self.cycles_per_char;
+                if !self.receive() {
+                    self.rx_cycles = 0;
+                    break;
+                }
+            }
+        }
+
+        if self.rx_fifo.is_empty() {
+            self.rx_idle_cycles = 0;
+        } else {
+            self.rx_idle_cycles += cycles;
+            if self.rx_idle_cycles >= self.rx_timeout_cycles() {
+                self.ris |= INT_RT;
+            }
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let tx = match self.tx_fifo.is_empty() || self.cycles_per_char == 0 {
+            true => None,
+            false => Some(self.cycles_per_char.saturating_sub(self.tx_cycles).max(1)),
+        };
+        let timeout = match self.rx_fifo.is_empty() || self.ris & INT_RT != 0 {
+            true => None,
+            false => Some(self.rx_timeout_cycles().saturating_sub(self.rx_idle_cycles).max(1)),
+        };
+        tx.into_iter().chain(timeout).min()
+    }
+
+    fn interrupt(&self) -> bool {
+        self.ris & self.imsc != 0
+    }
+
+    /// Line 0 requests RX service while data is waiting, line 1 requests TX data
+    /// while the FIFO is at or below its trigger level.
+    fn dma_requests(&self) -> Word {
+        let rx = self.dmacr & DMACR_RXDMAE != 0 && !self.rx_fifo.is_empty();
+        let tx = self.dmacr & DMACR_TXDMAE != 0 && self.is_tx_level();
+        (rx as Word) | (tx as Word) << 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORD: BusWidth = BusWidth::Word;
+
+    fn is_rx_empty(uart: &mut Uart) -> bool {
+        uart.read(UART_FR, WORD).unwrap() & FR_RXFE != 0
+    }
+
+    #[test]
+    fn transmits_and_receives_through_fifos() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut uart = Uart::new(UartTx::Buffer(output.clone()), UartRx::script(b"hello"));
+        uart.write(UART_LCR_H, LCR_H_FEN | 0x60, WORD).unwrap();
+        uart.write(UART_IMSC, INT_RX | INT_TX | INT_RT, WORD).unwrap();
+        for byte in b"hi" {
+            uart.write(UART_DR, *byte as Word, BusWidth::Byte).unwrap();
+        }
+        assert_eq!(&*output.borrow(), b"hi");
+        assert!(uart.interrupt());
+        uart.write(UART_ICR, INT_ALL, WORD).unwrap();
+
+        uart.tick(1);
+        assert!(!is_rx_empty(&mut uart));
+        // five characters stay below the receive trigger level, so only the timeout fires
+        assert_eq!(uart.read(UART_RIS, WORD).unwrap() & (INT_RX | INT_RT), 0);
+        uart.tick(40);
+        assert_eq!(uart.read(UART_RIS, WORD).unwrap() & (INT_RX | INT_RT), INT_RT);
+        let mut received = Vec::new();
+        while !is_rx_empty(&mut uart) {
+            received.push(uart.read(UART_DR, WORD).unwrap() as u8);
+        }
+        assert_eq!(received, b"hello");
+        assert!(!uart.interrupt());
+    }
+
+    #[test]
+    fn paces_transmission() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut uart = Uart::new(UartTx::Buffer(output.clone()), UartRx::None);
+        uart.cycles_per_char = 100;
+        uart.write(UART_DR, b'x' as Word, WORD).unwrap();
+        assert_eq!(uart.next_event(), Some(100));
+        uart.tick(99);
+        assert!(output.borrow().is_empty());
+        uart.tick(1);
+        assert_eq!(&*output.borrow(), b"x");
+    }
+}
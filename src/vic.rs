@@ -0,0 +1,242 @@
+use crate::armv4t::*;
+use crate::device::*;
+
+const VIC_IRQ_STATUS: Word = 0x000;
+const VIC_FIQ_STATUS: Word = 0x004;
+const VIC_RAW_INTR: Word = 0x008;
+const VIC_INT_SELECT: Word = 0x00C;
+const VIC_INT_ENABLE: Word = 0x010;
+const VIC_INT_EN_CLEAR: Word = 0x014;
+const VIC_SOFT_INT: Word = 0x018;
+const VIC_SOFT_INT_CLEAR: Word = 0x01C;
+const VIC_PROTECTION: Word = 0x020;
+const VIC_VECT_ADDR: Word = 0x030;
+const VIC_DEF_VECT_ADDR: Word = 0x034;
+const VIC_VECT_ADDR0: Word = 0x100;
+const VIC_VECT_CNTL0: Word = 0x200;
+const VIC_PERIPH_ID0: Word = 0xFE0;
+
+const VECTORED_SLOTS: usize = 16;
+/// Priority of the non-vectored (default) interrupts, below every vectored slot.
+const DEFAULT_PRIORITY: usize = VECTORED_SLOTS;
+const VECT_CNTL_ENABLE: Word = 0x20;
+
+const PERIPH_ID: [Word; 8] = [0x90, 0x11, 0x04, 0x00, 0x0D, 0xF0, 0x05, 0xB1];
+
+/// PrimeCell PL190 vectored interrupt controller with 32 sources.
+///
+/// Map it with `InterruptRoute::Irq`; its FIQ output drives nFIQ directly.
+/// Peripherals are connected with `InterruptRoute::Controller`.
+///
+/// VICProtection can be written and read back but is not enforced: User mode
+/// accesses reach every register whatever its value.
+#[derive(Debug, Default)]
+pub struct Vic {
+    pub sources: Word,
+    pub int_select: Word,
+    pub int_enable: Word,
+    pub soft_int: Word,
+    pub protection: Word,
+    pub def_vect_addr: Word,
+    pub vect_addr: [Word; VECTORED_SLOTS],
+    pub vect_cntl: [Word; VECTORED_SLOTS],
+    /// Priorities of the interrupts in service, innermost last. Reading
+    /// VICVectAddr pushes, writing it pops.
+    pub in_service: Vec<usize>,
+}
+
+impl Vic {
+    pub fn new() -> Vic {
+        Vic::default()
+    }
+
+    pub fn raw_interrupts(&self) -> Word {
+        self.sources | self.soft_int
+    }
+
+    pub fn irq_status(&self) -> Word {
+        self.raw_interrupts() & self.int_enable & !self.int_select
+    }
+
+    pub fn fiq_status(&self) -> Word {
+        self.raw_interrupts() & self.int_enable & self.int_select
+    }
+
+    /// Priority of `source` as an IRQ: its vectored slot, or the default priority.
+    fn priority(&self, source: u32) -> usize {
+        self.vect_cntl
+            .iter()
+            .position(|cntl| cntl & VECT_CNTL_ENABLE != 0 && cntl & 0x1F == source)
+            .unwrap_or(DEFAULT_PRIORITY)
+    }
+
+    /// Highest priority (lowest number) among the active IRQs.
+    fn highest_pending(&self) -> Option<usize> {
+        let status = self.irq_status();
+        (0..32).filter(|source| status & (1 << source) != 0).map(|source| self.priority(source)).min()
+    }
+
+    /// Interrupts at or below the priority in service are masked until it is acknowledged.
+    fn current_priority(&self) -> usize {
+        self.in_service.last().map_or(DEFAULT_PRIORITY + 1, |priority| *priority)
+    }
+
+    fn read_vect_addr(&mut self) -> Word {
+        match self.highest_pending() {
+            Some(priority) if priority < self.current_priority() => {
+                self.in_service.push(priority);
+                if priority == DEFAULT_PRIORITY { self.def_vect_addr } else { self.vect_addr[priority] }
+            }
+            _ => match self.in_service.last() {
+                Some(priority) if *priority < DEFAULT_PRIORITY => self.vect_addr[*priority],
+                _ => self.def_vect_addr,
+            },
+        }
+    }
+}
+
+impl Device for Vic {
+    fn read(&mut self, offset: Word, width: BusWidth) -> Result<Word, BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        let value = match offset {
+            VIC_IRQ_STATUS => self.irq_status(),
+            VIC_FIQ_STATUS => self.fiq_status(),
+            VIC_RAW_INTR => self.raw_interrupts(),
+            VIC_INT_SELECT => self.int_select,
+            VIC_INT_ENABLE => self.int_enable,
+            VIC_SOFT_INT => self.soft_int,
+            VIC_PROTECTION => self.protection,
+            VIC_VECT_ADDR => self.read_vect_addr(),
+            VIC_DEF_VECT_ADDR => self.def_vect_addr,
+            0x100..=0x13C => self.vect_addr[((offset - VIC_VECT_ADDR0) / 4) as usize],
+            0x200..=0x23C => self.vect_cntl[((offset - VIC_VECT_CNTL0) / 4) as usize],
+            0xFE0..=0xFFC => PERIPH_ID[((offset - VIC_PERIPH_ID0) / 4) as usize],
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: Word, value: Word, width: BusWidth) -> Result<(), BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        match offset {
+            VIC_INT_SELECT => self.int_select = value,
+            VIC_INT_ENABLE => self.int_enable |= value,
+            VIC_INT_EN_CLEAR => self.int_enable &= !value,
+            VIC_SOFT_INT => self.soft_int |= value,
+            VIC_SOFT_INT_CLEAR => self.soft_int &= !value,
+            VIC_PROTECTION => self.protection = value & 0x1,
+            // any write acknowledges the interrupt in service
+            VIC_VECT_ADDR => {
+                self.in_service.pop();
+            }
+            VIC_DEF_VECT_ADDR => self.def_vect_addr = value,
+            0x100..=0x13C => self.vect_addr[((offset - VIC_VECT_ADDR0) / 4) as usize] = value,
+            0x200..=0x23C => self.vect_cntl[((offset - VIC_VECT_CNTL0) / 4) as usize] = value & 0x3F,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn interrupt(&self) -> bool {
+        matches!(self.highest_pending(), Some(priority) if priority < self.current_priority())
+    }
+
+    fn fast_interrupt(&self) -> bool {
+        self.fiq_status() != 0
+    }
+
+    fn set_interrupt_sources(&mut self, sources: Word) {
+        self.sources = sources;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_map::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// An interrupt output the test drives directly.
+    struct Line(Rc<Cell<bool>>);
+
+    impl Device for Line {
+        fn read(&mut self, _offset: Word, _width: BusWidth) -> Result<Word, BusError> {
+            Ok(0)
+        }
+
+        fn write(&mut self, _offset: Word, _value: Word, _width: BusWidth) -> Result<(), BusError> {
+            Ok(())
+        }
+
+        fn interrupt(&self) -> bool {
+            self.0.get()
+        }
+    }
+
+    const BASE: Word = 0x1000;
+
+    fn read(map: &mut MemoryMap, offset: Word) -> Word {
+        let mut data = 0;
+        map.access(BASE + offset, &mut data, BusRW::Read, BusCycle::NonSequential).unwrap();
+        data
+    }
+
+    fn write(map: &mut MemoryMap, offset: Word, value: Word) {
+        let mut data = value;
+        map.access(BASE + offset, &mut data, BusRW::Write, BusCycle::NonSequential).unwrap();
+    }
+
+    #[test]
+    fn nests_vectored_interrupts_by_priority() {
+        let mut map = MemoryMap::new();
+        let vic = map.add_device("vic", BASE, 0x1000, Box::new(Vic::new()));
+        map.route_interrupt(vic, InterruptRoute::Irq);
+        let low = Rc::new(Cell::new(false));
+        let high = Rc::new(Cell::new(false));
+        let low_device = map.add_device("low", 0x3000, 0x10, Box::new(Line(low.clone())));
+        let high_device = map.add_device("high", 0x3010, 0x10, Box::new(Line(high.clone())));
+        map.route_interrupt(low_device, InterruptRoute::Controller { controller: vic, source: 4 });
+        map.route_interrupt(high_device, InterruptRoute::Controller { controller: vic, source: 9 });
+
+        write(&mut map, VIC_INT_ENABLE, (1 << 4) | (1 << 9));
+        write(&mut map, VIC_VECT_ADDR0, 0xAAAA);
+        write(&mut map, VIC_VECT_CNTL0, VECT_CNTL_ENABLE | 9);
+        write(&mut map, VIC_VECT_ADDR0 + 4, 0xBBBB);
+        write(&mut map, VIC_VECT_CNTL0 + 4, VECT_CNTL_ENABLE | 4);
+        assert!(!map.interrupt_lines().irq);
+
+        low.set(true);
+        assert!(map.interrupt_lines().irq);
+        assert_eq!(read(&mut map, VIC_VECT_ADDR), 0xBBBB);
+        // masked while in service
+        assert!(!map.interrupt_lines().irq);
+        high.set(true);
+        // a higher priority source preempts
+        assert!(map.interrupt_lines().irq);
+        assert_eq!(read(&mut map, VIC_VECT_ADDR), 0xAAAA);
+        high.set(false);
+        write(&mut map, VIC_VECT_ADDR, 0);
+        assert!(!map.interrupt_lines().irq);
+        write(&mut map, VIC_VECT_ADDR, 0);
+        assert!(map.interrupt_lines().irq);
+
+        assert_eq!(read(&mut map, VIC_IRQ_STATUS), 1 << 4);
+        write(&mut map, VIC_INT_SELECT, 1 << 4);
+        let lines = map.interrupt_lines();
+        assert!(lines.fiq && !lines.irq);
+    }
+
+    #[test]
+    #[should_panic(expected = "interrupt source 32 out of range")]
+    fn rejects_sources_past_31() {
+        let mut map = MemoryMap::new();
+        let vic = map.add_device("vic", BASE, 0x1000, Box::new(Vic::new()));
+        let device = map.add_device("line", 0x3000, 0x10, Box::new(Line(Rc::new(Cell::new(false)))));
+        map.route_interrupt(device, InterruptRoute::Controller { controller: vic, source: 32 });
+    }
+}
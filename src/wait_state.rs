@@ -0,0 +1,194 @@
+use crate::armv4t::*;
+
+/// Wait states of the address range `start..=end` for non-sequential and sequential accesses.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct WaitStateRegion {
+    pub start: Word,
+    pub end: Word,
+    pub non_sequential: u32,
+    pub sequential: u32,
+}
+
+/// Per-region wait states. The first region containing an address wins;
+/// addresses outside every region take no wait states.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct WaitStateTable {
+    pub regions: Vec<WaitStateRegion>,
+}
+
+impl WaitStateTable {
+    pub fn new() -> WaitStateTable {
+        WaitStateTable { regions: Vec::new() }
+    }
+
+    pub fn region(mut self, start: Word, end: Word, non_sequential: u32, sequential: u32) -> WaitStateTable {
+        self.regions.push(WaitStateRegion { start, end, non_sequential, sequential });
+        self
+    }
+
+    /// GBA-like map: 0-wait IWRAM and I/O, 2-wait EWRAM and 2/1-wait cartridge ROM.
+    pub fn gba() -> WaitStateTable {
+        WaitStateTable::new()
+            .region(0x0000_0000, 0x0000_3FFF, 0, 0) // BIOS
+            .region(0x0200_0000, 0x0203_FFFF, 2, 2) // EWRAM
+            .region(0x0300_0000, 0x0300_7FFF, 0, 0) // IWRAM
+            .region(0x0400_0000, 0x0400_03FF, 0, 0) // I/O
+            .region(0x0800_0000, 0x0DFF_FFFF, 2, 1) // ROM
+            .region(0x0E00_0000, 0x0E00_FFFF, 4, 4) // SRAM
+    }
+
+    pub fn wait_states(&self, addr: Word, cycle: BusCycle) -> u32 {
+        match self.regions.iter().find(|region| region.start <= addr && addr <= region.end) {
+            Some(region) => match cycle {
+                BusCycle::NonSequential => region.non_sequential,
+                BusCycle::Sequential => region.sequential,
+            },
+            None => 0,
+        }
+    }
+}
+
+/// Adds the wait states of `table` to every access made through `bus`.
+pub struct WaitStateBus<T: Bus> {
+    pub bus: T,
+    pub table: WaitStateTable,
+}
+
+impl<T: Bus> WaitStateBus<T> {
+    pub fn new(bus: T, table: WaitStateTable) -> WaitStateBus<T> {
+        WaitStateBus { bus, table }
+    }
+}
+
+impl<T: Bus> Bus for WaitStateBus<T> {
+    fn access(&mut self, addr: Word, data: &mut Word, rw: BusRW, cycle: BusCycle) -> BusState {
+        let wait_states = self.bus.access(addr, data, rw, cycle)?;
+        Ok(wait_states + self.table.wait_states(addr, cycle))
+    }
+
+    fn transfer(&mut self, request: &BusRequest, data: &mut Word) -> BusState {
+        let wait_states = self.bus.transfer(request, data)?;
+        Ok(wait_states + self.table.wait_states(request.addr, request.cycle))
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.bus.tick(cycles);
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.bus.next_event()
+    }
+
+    fn interrupt_lines(&mut self) -> InterruptLines {
+        self.bus.interrupt_lines()
+    }
+
+    fn stolen_cycles(&mut self) -> u64 {
+        self.bus.stolen_cycles()
+    }
+
+    fn system_request(&mut self) -> Option<SystemRequest> {
+        self.bus.system_request()
+    }
+
+    fn peek(&mut self, addr: Word, width: BusWidth) -> Option<Word> {
+        self.bus.peek(addr, width)
+    }
+
+    fn cache_operation(&mut self, operation: CacheOperation) -> BusState {
+        self.bus.cache_operation(operation)
+    }
+
+    fn begin_instruction(&mut self, pc: Word) {
+        self.bus.begin_instruction(pc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 64 KiB of RAM repeated across the whole address space, logging every access.
+    struct MirroredMemory {
+        data: Vec<u8>,
+        accesses: Vec<(Word, BusCycle)>,
+    }
+
+    impl MirroredMemory {
+        fn load(&mut self, addr: Word, program: &[Word]) {
+            for (i, inst) in program.iter().enumerate() {
+                let start = (addr & 0xFFFF) as usize + i * 4;
+                self.data[start..start + 4].copy_from_slice(&inst.to_le_bytes());
+            }
+        }
+    }
+
+    impl Bus for MirroredMemory {
+        fn access(&mut self, addr: Word, data: &mut Word, rw: BusRW, cycle: BusCycle) -> BusState {
+            self.accesses.push((addr, cycle));
+            let start = (addr & 0xFFFC) as usize;
+            match rw {
+                BusRW::Read => *data = Word::from_le_bytes(self.data[start..start + 4].try_into().unwrap()),
+                BusRW::Write => self.data[start..start + 4].copy_from_slice(&data.to_le_bytes()),
+            }
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn rom_fetches_are_non_sequential_then_sequential() {
+        let mut memory = MirroredMemory { data: vec![0; 0x10000], accesses: Vec::new() };
+        memory.load(0, &[
+            0xE3A00302, // mov r0, #0x08000000
+            0xE280FF40, // add pc, r0, #0x100
+        ]);
+        memory.load(0x0800_0100, &[
+            0xE3A03403, // mov r3, #0x03000000
+            0xE3A01002, // mov r1, #2
+            0xE5932000, // ldr r2, [r3]
+            0xE3A04004, // mov r4, #4
+            0xE3A05005, // mov r5, #5
+            0xEAFFFFFE, // b .
+        ]);
+        let mut cpu = ARMv4T::new(WaitStateBus::new(memory, WaitStateTable::gba()));
+        cpu.reset();
+        for _ in 0..12 {
+            cpu.step().unwrap();
+        }
+
+        use BusCycle::*;
+        let accesses = &cpu.bus.bus.accesses;
+        let rom = accesses.iter().position(|access| *access == (0x0800_0100, NonSequential)).unwrap();
+        assert_eq!(accesses[rom..rom + 8], [
+            // refilling from ROM: one non-sequential fetch, then sequential ones
+            (0x0800_0100, NonSequential),
+            (0x0800_0104, Sequential),
+            (0x0800_0108, Sequential),
+            (0x0800_010C, Sequential),
+            (0x0800_0110, Sequential),
+            // the ldr's data access breaks the stream, so the next fetch is non-sequential again
+            (0x0300_0000, NonSequential),
+            (0x0800_0114, NonSequential),
+            (0x0800_0118, Sequential),
+        ]);
+        let table = &cpu.bus.table;
+        let wait_states = |accesses: &[(Word, BusCycle)]| -> u64 {
+            accesses.iter().map(|(addr, cycle)| table.wait_states(*addr, *cycle) as u64).sum()
+        };
+        // 2 + 1 + 1 + 1 + 1 for the refill, none for IWRAM, then 2 + 1
+        assert_eq!(wait_states(&accesses[rom..rom + 8]), 9);
+        // every step reports the wait states of its accesses
+        assert_eq!(cpu.cycles.wait, wait_states(accesses));
+    }
+
+    #[test]
+    fn gba_table_lookup() {
+        let table = WaitStateTable::gba();
+        assert_eq!(table.wait_states(0x0800_0000, BusCycle::NonSequential), 2);
+        assert_eq!(table.wait_states(0x0DFF_FFFC, BusCycle::Sequential), 1);
+        assert_eq!(table.wait_states(0x0200_0000, BusCycle::Sequential), 2);
+        assert_eq!(table.wait_states(0x0300_0000, BusCycle::NonSequential), 0);
+        // outside every region
+        assert_eq!(table.wait_states(0x1000_0000, BusCycle::NonSequential), 0);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::armv4t::*;
+use crate::device::*;
+
+const WDOG_LOAD: Word = 0x000;
+const WDOG_VALUE: Word = 0x004;
+const WDOG_CONTROL: Word = 0x008;
+const WDOG_INT_CLR: Word = 0x00C;
+const WDOG_RIS: Word = 0x010;
+const WDOG_MIS: Word = 0x014;
+const WDOG_LOCK: Word = 0xC00;
+const WDOG_PERIPH_ID0: Word = 0xFE0;
+
+const CONTROL_INT_ENABLE: Word = 0x1;
+const CONTROL_RESET_ENABLE: Word = 0x2;
+
+/// Writing this to WdogLock enables register writes; any other value disables them.
+const UNLOCK_KEY: Word = 0x1ACCE551;
+
+const PERIPH_ID: [Word; 8] = [0x05, 0x18, 0x14, 0x00, 0x0D, 0xF0, 0x05, 0xB1];
+
+/// PrimeCell SP805 watchdog.
+///
+/// The counter runs while its interrupt is enabled and decrements every
+/// `cycles_per_tick` CPU cycles. The first time it reaches zero it raises the
+/// interrupt and reloads; reaching zero again before the interrupt is cleared
+/// (the kick) requests a reset, if enabled, which resets the core and the
+/// watchdog itself.
+#[derive(Debug)]
+pub struct Watchdog {
+    pub load: Word,
+    pub value: Word,
+    pub control: Word,
+    pub raw_interrupt: bool,
+    pub locked: bool,
+    pub cycles_per_tick: u64,
+    pub pending_cycles: u64,
+    pub reset_requested: bool,
+}
+
+impl Watchdog {
+    pub fn new(cycles_per_tick: u64) -> Watchdog {
+        Watchdog {
+            load: 0xFFFFFFFF,
+            value: 0xFFFFFFFF,
+            control: 0,
+            raw_interrupt: false,
+            locked: false,
+            cycles_per_tick: cycles_per_tick.max(1),
+            pending_cycles: 0,
+            reset_requested: false,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.control & CONTROL_INT_ENABLE != 0
+    }
+
+    /// The counter hit zero: interrupt on the first timeout, reset on the second.
+    fn expire(&mut self) {
+        if self.raw_interrupt && self.control & CONTROL_RESET_ENABLE != 0 {
+            let cycles_per_tick = self.cycles_per_tick;
+            *self = Watchdog::new(cycles_per_tick);
+            self.reset_requested = true;
+            return;
+        }
+        self.raw_interrupt = true;
+        self.value = self.load;
+    }
+}
+
+impl Device for Watchdog {
+    fn read(&mut self, offset: Word, width: BusWidth) -> Result<Word, BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        let value = match offset {
+            WDOG_LOAD => self.load,
+            WDOG_VALUE => self.value,
+            WDOG_CONTROL => self.control,
+            WDOG_RIS => self.raw_interrupt as Word,
+            WDOG_MIS => self.interrupt() as Word,
+            WDOG_LOCK => self.locked as Word,
+            0xFE0..=0xFFC => PERIPH_ID[((offset - WDOG_PERIPH_ID0) / 4) as usize],
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: Word, value: Word, width: BusWidth) -> Result<(), BusError> {
+        if width != BusWidth::Word {
+            return Err(BusError::Width(offset));
+        }
+        if offset == WDOG_LOCK {
+            self.locked = value != UNLOCK_KEY;
+            return Ok(());
+        }
+        if self.locked {
+            return Ok(());
+        }
+        match offset {
+            WDOG_LOAD => {
+                self.load = value;
+                self.value = value;
+                self.pending_cycles = 0;
+            }
+            WDOG_CONTROL => {
+                // enabling the interrupt restarts the count from the load value
+                if value & CONTROL_INT_ENABLE != 0 && !self.is_running() {
+                    self.value = self.load;
+                    self.pending_cycles = 0;
+                }
+                self.control = value & 0x3;
+            }
+            // kicking: clears the interrupt and reloads the counter
+            WDOG_INT_CLR => {
+                self.raw_interrupt = false;
+                self.value = self.load;
+                self.pending_cycles = 0;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if !self.is_running() {
+            return;
+        }
+        self.pending_cycles += cycles;
+        let mut ticks = self.pending_cycles / self.cycles_per_tick;
+        self.pending_cycles %= self.cycles_per_tick;
+        while ticks > 0 && self.is_running() {
+            if ticks < self.value as u64 {
+                self.value -= ticks as Word;
+                return;
+            }
+            ticks -= (self.value as u64).max(1);
+            self.expire();
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        if !self.is_running() {
+            return None;
+        }
+        let ticks = (self.value as u64).max(1);
+        Some((ticks * self.cycles_per_tick).saturating_sub(self.pending_cycles).max(1))
+    }
+
+    fn interrupt(&self) -> bool {
+        self.raw_interrupt && self.control & CONTROL_INT_ENABLE != 0
+    }
+
+    fn system_request(&mut self) -> Option<SystemRequest> {
+        std::mem::take(&mut self.reset_requested).then_some(SystemRequest::Reset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_map::*;
+
+    #[test]
+    fn second_timeout_resets_the_core() {
+        let mut map = MemoryMap::new();
+        map.add_ram("ram", 0, 0x10000);
+        let watchdog = map.add_device("watchdog", 0x2000_0000, 0x1000, Box::new(Watchdog::new(1)));
+        map.route_interrupt(watchdog, InterruptRoute::Irq);
+        let program: [Word; 6] = [
+            0xE3A01202, // mov r1, #0x20000000
+            0xE3A00064, // mov r0, #100
+            0xE5810000, // str r0, [r1, #WDOG_LOAD]
+            0xE3A00003, // mov r0, #CONTROL_RESET_ENABLE | CONTROL_INT_ENABLE
+            0xE5810008, // str r0, [r1, #WDOG_CONTROL]
+            0xEAFFFFFE, // b .
+        ];
+        for (i, inst) in program.iter().enumerate() {
+            map.load(i as Word * 4, &inst.to_le_bytes()).unwrap();
+        }
+        let mut cpu = ARMv4T::new(map);
+        cpu.reset();
+        // until the watchdog is enabled
+        while cpu.next_instruction_address() != 0x14 || cpu.decoded_inst.is_none() {
+            cpu.step().unwrap();
+        }
+        assert!(!cpu.bus.interrupt_lines().irq);
+        cpu.run(100).unwrap();
+        assert!(cpu.bus.interrupt_lines().irq);
+
+        // IRQs stay masked, so nothing clears the interrupt before the second timeout
+        let mut steps = 0;
+        while cpu.get_gpr(0) != 0 {
+            cpu.step().unwrap();
+            steps += 1;
+            assert!(steps < 200, "watchdog never reset the core");
+        }
+        assert!(!cpu.bus.interrupt_lines().irq);
+    }
+
+    #[test]
+    fn lock_ignores_writes() {
+        let mut watchdog = Watchdog::new(1);
+        watchdog.write(WDOG_LOCK, 0, BusWidth::Word).unwrap();
+        watchdog.write(WDOG_LOAD, 5, BusWidth::Word).unwrap();
+        assert_eq!(watchdog.read(WDOG_LOAD, BusWidth::Word).unwrap(), 0xFFFFFFFF);
+    }
+}